@@ -0,0 +1,361 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::path::{Path, PathBuf};
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use rules::Fault;
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+mod rules;
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Fail the call if a rule injects an error into it.
+fn inject(operation: &str, path: &Path) -> Result<(), ErrorCode> {
+    match rules::fault(operation, path) {
+        Some(Fault::Error(error)) => Err(error),
+        _ => Ok(()),
+    }
+}
+
+fn failed(error: ErrorCode) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    let (tx, rx) = wit_future::new(|| Err(ErrorCode::Io));
+    wit_bindgen::spawn(async move {
+        let _ = tx.write(Err(error)).await;
+    });
+    rx
+}
+
+/// Copy at most `limit` items from one stream to another.
+async fn pipe<T: wit_stream::StreamPayload>(
+    rx: &mut wit_bindgen::StreamReader<T>,
+    tx: &mut wit_bindgen::StreamWriter<T>,
+    mut limit: u64,
+) {
+    while limit > 0 {
+        let capacity = limit.min(CHUNK_SIZE) as usize;
+        let (status, buf) = rx.read(Vec::with_capacity(capacity)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        limit -= buf.len() as u64;
+        let remaining = tx.write_all(buf).await;
+        if !remaining.is_empty() {
+            break;
+        }
+    }
+}
+
+/// Apply a fault to a stream read from a descriptor.
+fn read_fault<T: wit_stream::StreamPayload>(
+    fault: Option<Fault>,
+    read: impl FnOnce() -> (
+        wit_bindgen::StreamReader<T>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ),
+) -> (
+    wit_bindgen::StreamReader<T>,
+    wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) {
+    match fault {
+        None => read(),
+        Some(Fault::Error(error)) => {
+            let (_, data_rx) = wit_stream::new();
+            (data_rx, failed(error))
+        }
+        Some(Fault::Truncate { after, error }) => {
+            let (mut data, _) = read();
+            let (mut data_tx, data_rx) = wit_stream::new();
+            let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+            wit_bindgen::spawn(async move {
+                pipe(&mut data, &mut data_tx, after).await;
+                drop(data_tx);
+                drop(data);
+                let _ = result_tx.write(error.map_or(Ok(()), Err)).await;
+            });
+            (data_rx, result_rx)
+        }
+    }
+}
+
+/// Apply a fault to a stream written to a descriptor.
+fn write_fault(
+    fault: Option<Fault>,
+    mut data: wit_bindgen::StreamReader<u8>,
+    write: impl FnOnce(
+        wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    match fault {
+        None => write(data),
+        Some(Fault::Error(error)) => failed(error),
+        Some(Fault::Truncate { after, error }) => {
+            let (mut data_tx, data_rx) = wit_stream::new();
+            let result = write(data_rx);
+            let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+            wit_bindgen::spawn(async move {
+                pipe(&mut data, &mut data_tx, after).await;
+                drop(data_tx);
+                drop(data);
+                let result = result.await;
+                let _ = result_tx.write(error.map_or(result, Err)).await;
+            });
+            result_rx
+        }
+    }
+}
+
+struct FilesystemChaos {}
+
+impl Preopens for FilesystemChaos {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| {
+                let fd = Descriptor::new(ChaosDescriptor::new(fd, path.clone().into()));
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemChaos {
+    type Descriptor = ChaosDescriptor;
+}
+
+struct ChaosDescriptor {
+    fd: types::Descriptor,
+    path: PathBuf,
+}
+
+impl ChaosDescriptor {
+    fn new(fd: types::Descriptor, path: PathBuf) -> Self {
+        Self { fd, path }
+    }
+
+    fn path_at(&self, path: &str) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl GuestDescriptor for ChaosDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let fault = rules::stream_fault("read-via-stream", &self.path);
+        read_fault(fault, || self.fd.read_via_stream(offset))
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let fault = rules::stream_fault("write-via-stream", &self.path);
+        write_fault(fault, data, |data| self.fd.write_via_stream(data, offset))
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let fault = rules::stream_fault("append-via-stream", &self.path);
+        write_fault(fault, data, |data| self.fd.append_via_stream(data))
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        inject("advise", &self.path)?;
+        self.fd.advise(offset, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        inject("sync-data", &self.path)?;
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        inject("get-flags", &self.path)?;
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        inject("get-type", &self.path)?;
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        inject("set-size", &self.path)?;
+        self.fd.set_size(size).await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        inject("set-times", &self.path)?;
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let fault = rules::stream_fault("read-directory", &self.path);
+        read_fault(fault, || self.fd.read_directory())
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        inject("sync", &self.path)?;
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        inject("create-directory-at", &self.path_at(&path))?;
+        self.fd.create_directory_at(path).await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        inject("stat", &self.path)?;
+        self.fd.stat().await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        inject("stat-at", &self.path_at(&path))?;
+        self.fd.stat_at(path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        inject("set-times-at", &self.path_at(&path))?;
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        inject("link-at", &new_descriptor.path_at(&new_path))?;
+        self.fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let path_at = self.path_at(&path);
+        inject("open-at", &path_at)?;
+        self.fd
+            .open_at(path_flags, path, open_flags, flags)
+            .await
+            .map(|fd| Descriptor::new(ChaosDescriptor::new(fd, path_at)))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        inject("readlink-at", &self.path_at(&path))?;
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        inject("remove-directory-at", &self.path_at(&path))?;
+        self.fd.remove_directory_at(path).await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        inject("rename-at", &self.path_at(&old_path))?;
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        inject("symlink-at", &self.path_at(&new_path))?;
+        self.fd.symlink_at(old_path, new_path).await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        inject("unlink-file-at", &self.path_at(&path))?;
+        self.fd.unlink_file_at(path).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        inject("metadata-hash", &self.path)?;
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        inject("metadata-hash-at", &self.path_at(&path))?;
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemChaos);
@@ -0,0 +1,309 @@
+use std::cell::RefCell;
+use std::path::Path;
+
+use common::glob;
+use common::random::Xorshift;
+use regex_lite::Regex;
+
+use crate::wasi::clocks::system_clock;
+use crate::wasi::config::store;
+use crate::wasi::filesystem::types::ErrorCode;
+use crate::wasi::logging::logging::{log, Level};
+
+const CHAOS_KEY_PREFIX: &str = "chaos.";
+
+thread_local! {
+    static CHAOS: RefCell<Chaos> = RefCell::new(Chaos::from_config());
+}
+
+/// A fault to inject into a call.
+pub(crate) enum Fault {
+    /// Fail the call without making it.
+    Error(ErrorCode),
+    /// Cut a stream short after a number of items, optionally failing once it is cut.
+    Truncate {
+        after: u64,
+        error: Option<ErrorCode>,
+    },
+}
+
+/// A fault injection rule, configured by a wasi:config key prefixed with 'chaos.' and a value of
+/// space separated '<field>=<value>' pairs:
+///
+/// - 'op': comma separated operations the rule applies to, defaults to all
+/// - 'path': glob the path must match, defaults to all
+/// - 'probability': chance between 0 and 1 a matching call fails, defaults to 1
+/// - 'nth': only the nth matching call fails
+/// - 'error': the error code to fail with
+/// - 'truncate': number of items after which a stream is cut
+struct Rule {
+    operations: Option<Vec<String>>,
+    path: Option<Regex>,
+    probability: f64,
+    nth: Option<u64>,
+    error: Option<ErrorCode>,
+    truncate: Option<u64>,
+    calls: u64,
+}
+
+impl Rule {
+    fn parse(value: &str) -> Result<Self, String> {
+        let mut rule = Self {
+            operations: None,
+            path: None,
+            probability: 1.0,
+            nth: None,
+            error: None,
+            truncate: None,
+            calls: 0,
+        };
+        for pair in value.split_whitespace() {
+            let Some((field, value)) = pair.split_once('=') else {
+                return Err(format!("expected '<field>=<value>', found '{pair}'"));
+            };
+            match field {
+                "op" => rule.operations = Some(value.split(',').map(String::from).collect()),
+                "path" => {
+                    rule.path = Some(Regex::new(&glob::to_regex(value)).map_err(|e| e.to_string())?)
+                }
+                "probability" => {
+                    rule.probability = value
+                        .parse::<f64>()
+                        .map_err(|e| e.to_string())?
+                        .clamp(0.0, 1.0)
+                }
+                "nth" => rule.nth = Some(value.parse::<u64>().map_err(|e| e.to_string())?),
+                "error" => rule.error = Some(error_code(value)?),
+                "truncate" => {
+                    rule.truncate = Some(value.parse::<u64>().map_err(|e| e.to_string())?)
+                }
+                _ => return Err(format!("unknown field '{field}'")),
+            }
+        }
+        if rule.error.is_none() && rule.truncate.is_none() {
+            return Err(String::from("expected an 'error' or 'truncate' field"));
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, operation: &str, path: &Path, stream: bool) -> bool {
+        if !stream && self.error.is_none() {
+            return false;
+        }
+        if let Some(operations) = &self.operations {
+            if !operations.iter().any(|op| op == operation) {
+                return false;
+            }
+        }
+        match &self.path {
+            Some(glob) => glob.is_match(&path.to_string_lossy()),
+            None => true,
+        }
+    }
+
+    fn fault(&self, stream: bool) -> Fault {
+        match self.truncate {
+            Some(after) if stream => Fault::Truncate {
+                after,
+                error: self.error.clone(),
+            },
+            _ => Fault::Error(self.error.clone().unwrap_or(ErrorCode::Io)),
+        }
+    }
+}
+
+struct Chaos {
+    rules: Vec<Rule>,
+    random: Xorshift,
+}
+
+impl Chaos {
+    fn from_config() -> Self {
+        let mut entries = store::get_all().unwrap_or_default();
+        entries.sort();
+        let rules = entries
+            .into_iter()
+            .filter(|(key, _value)| key.starts_with(CHAOS_KEY_PREFIX))
+            .filter_map(|(key, value)| match Rule::parse(&value) {
+                Ok(rule) => Some(rule),
+                Err(message) => {
+                    log(
+                        Level::Warn,
+                        "filesystem",
+                        &format!("ignoring chaos rule '{key}': {message}"),
+                    );
+                    None
+                }
+            })
+            .collect();
+        let now = system_clock::now();
+        Self {
+            rules,
+            random: Xorshift::new(now.seconds as u64 ^ now.nanoseconds as u64),
+        }
+    }
+
+    fn fault(&mut self, operation: &str, path: &Path, stream: bool) -> Option<Fault> {
+        for i in 0..self.rules.len() {
+            if !self.rules[i].matches(operation, path, stream) {
+                continue;
+            }
+            let rule = &mut self.rules[i];
+            rule.calls += 1;
+            if rule.nth.is_some_and(|nth| nth != rule.calls) {
+                continue;
+            }
+            let probability = rule.probability;
+            if probability < 1.0 && self.random.next_f64() >= probability {
+                continue;
+            }
+            let fault = self.rules[i].fault(stream);
+            log(
+                Level::Debug,
+                "filesystem",
+                &format!("CHAOS {operation} PATH={}", path.display()),
+            );
+            return Some(fault);
+        }
+        None
+    }
+}
+
+fn error_code(name: &str) -> Result<ErrorCode, String> {
+    let error = match name {
+        "access" => ErrorCode::Access,
+        "already" => ErrorCode::Already,
+        "bad-descriptor" => ErrorCode::BadDescriptor,
+        "busy" => ErrorCode::Busy,
+        "deadlock" => ErrorCode::Deadlock,
+        "quota" => ErrorCode::Quota,
+        "exist" => ErrorCode::Exist,
+        "file-too-large" => ErrorCode::FileTooLarge,
+        "illegal-byte-sequence" => ErrorCode::IllegalByteSequence,
+        "in-progress" => ErrorCode::InProgress,
+        "interrupted" => ErrorCode::Interrupted,
+        "invalid" => ErrorCode::Invalid,
+        "io" => ErrorCode::Io,
+        "is-directory" => ErrorCode::IsDirectory,
+        "loop" => ErrorCode::Loop,
+        "too-many-links" => ErrorCode::TooManyLinks,
+        "message-size" => ErrorCode::MessageSize,
+        "name-too-long" => ErrorCode::NameTooLong,
+        "no-device" => ErrorCode::NoDevice,
+        "no-entry" => ErrorCode::NoEntry,
+        "no-lock" => ErrorCode::NoLock,
+        "insufficient-memory" => ErrorCode::InsufficientMemory,
+        "insufficient-space" => ErrorCode::InsufficientSpace,
+        "not-directory" => ErrorCode::NotDirectory,
+        "not-empty" => ErrorCode::NotEmpty,
+        "not-recoverable" => ErrorCode::NotRecoverable,
+        "unsupported" => ErrorCode::Unsupported,
+        "no-tty" => ErrorCode::NoTty,
+        "no-such-device" => ErrorCode::NoSuchDevice,
+        "overflow" => ErrorCode::Overflow,
+        "not-permitted" => ErrorCode::NotPermitted,
+        "pipe" => ErrorCode::Pipe,
+        "read-only" => ErrorCode::ReadOnly,
+        "invalid-seek" => ErrorCode::InvalidSeek,
+        "text-file-busy" => ErrorCode::TextFileBusy,
+        "cross-device" => ErrorCode::CrossDevice,
+        _ => return Err(format!("unknown error code '{name}'")),
+    };
+    Ok(error)
+}
+
+/// The fault to inject into a call, if any rule triggers.
+pub(crate) fn fault(operation: &str, path: &Path) -> Option<Fault> {
+    CHAOS.with_borrow_mut(|chaos| chaos.fault(operation, path, false))
+}
+
+/// The fault to inject into a call transferring a stream, if any rule triggers.
+pub(crate) fn stream_fault(operation: &str, path: &Path) -> Option<Fault> {
+    CHAOS.with_borrow_mut(|chaos| chaos.fault(operation, path, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fields() {
+        let rule = Rule::parse("op=read,write path=/data/*.txt probability=2 nth=3 error=no-entry")
+            .unwrap();
+        assert_eq!(
+            rule.operations,
+            Some(vec![String::from("read"), String::from("write")])
+        );
+        assert_eq!(rule.probability, 1.0);
+        assert_eq!(rule.nth, Some(3));
+        assert!(matches!(rule.error, Some(ErrorCode::NoEntry)));
+        assert_eq!(rule.truncate, None);
+
+        let rule = Rule::parse("truncate=10").unwrap();
+        assert_eq!(rule.operations, None);
+        assert_eq!(rule.truncate, Some(10));
+        assert!(rule.error.is_none());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_rules() {
+        for value in [
+            "",
+            "op=read",
+            "error",
+            "error=missing",
+            "error=io colour=red",
+            "error=io nth=-1",
+            "error=io probability=often",
+            "truncate=ten",
+        ] {
+            assert!(Rule::parse(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn matches_operations_and_paths() {
+        let rule = Rule::parse("op=read path=/data/*.txt error=io").unwrap();
+        assert!(rule.matches("read", Path::new("/data/a.txt"), false));
+        assert!(!rule.matches("write", Path::new("/data/a.txt"), false));
+        assert!(!rule.matches("read", Path::new("/data/a.bin"), false));
+        assert!(!rule.matches("read", Path::new("/other/a.txt"), false));
+
+        let rule = Rule::parse("error=io").unwrap();
+        assert!(rule.matches("stat", Path::new("/anything"), false));
+    }
+
+    #[test]
+    fn truncation_only_applies_to_streams() {
+        let rule = Rule::parse("truncate=5").unwrap();
+        assert!(!rule.matches("read", Path::new("/a"), false));
+        assert!(rule.matches("read", Path::new("/a"), true));
+        assert!(matches!(
+            rule.fault(true),
+            Fault::Truncate {
+                after: 5,
+                error: None
+            }
+        ));
+
+        let rule = Rule::parse("truncate=5 error=pipe").unwrap();
+        assert!(matches!(
+            rule.fault(true),
+            Fault::Truncate {
+                after: 5,
+                error: Some(ErrorCode::Pipe)
+            }
+        ));
+        assert!(matches!(rule.fault(false), Fault::Error(ErrorCode::Pipe)));
+    }
+
+    #[test]
+    fn error_codes_by_name() {
+        assert!(matches!(
+            error_code("cross-device"),
+            Ok(ErrorCode::CrossDevice)
+        ));
+        assert!(matches!(error_code("read-only"), Ok(ErrorCode::ReadOnly)));
+        assert!(error_code("ReadOnly").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::rc::Rc;
+
+use seekable::Writer;
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+mod seekable;
+
+struct FilesystemCompress {}
+
+impl Preopens for FilesystemCompress {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| {
+                let fd = Descriptor::new(CompressDescriptor::new(fd));
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemCompress {
+    type Descriptor = CompressDescriptor;
+}
+
+struct CompressDescriptor {
+    fd: Rc<types::Descriptor>,
+}
+
+impl CompressDescriptor {
+    fn new(fd: types::Descriptor) -> Self {
+        Self { fd: Rc::new(fd) }
+    }
+
+    /// Compress data written from an offset, or appended when there is no offset.
+    fn compress_write(
+        &self,
+        mut data: wit_bindgen::StreamReader<u8>,
+        offset: Option<Filesize>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let fd = self.fd.clone();
+        wit_bindgen::spawn(async move {
+            let result = async {
+                let mut writer = Writer::new(&fd, offset).await?;
+                let written = async {
+                    loop {
+                        let (status, buf) =
+                            data.read(Vec::with_capacity(seekable::FRAME_SIZE)).await;
+                        if buf.is_empty() {
+                            match status {
+                                wit_bindgen::StreamResult::Complete(_) => continue,
+                                _ => break,
+                            }
+                        }
+                        writer.write(&buf).await?;
+                    }
+                    Ok(())
+                }
+                .await;
+                match written {
+                    Ok(()) => writer.finish().await,
+                    Err(error) => {
+                        writer.abort().await;
+                        Err(error)
+                    }
+                }
+            }
+            .await;
+            drop(data);
+            let _ = result_tx.write(result).await;
+        });
+        result_rx
+    }
+}
+
+/// Report the decompressed size of regular files.
+async fn decompressed_stat(
+    fd: &types::Descriptor,
+    mut stat: DescriptorStat,
+) -> Result<DescriptorStat, ErrorCode> {
+    if matches!(stat.type_, DescriptorType::RegularFile) {
+        stat.size = seekable::size(&seekable::read_frames(fd).await?);
+    }
+    Ok(stat)
+}
+
+impl GuestDescriptor for CompressDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let fd = self.fd.clone();
+        wit_bindgen::spawn(async move {
+            let result = seekable::decompress_stream(&fd, &mut data_tx, offset).await;
+            drop(data_tx);
+            let _ = result_tx.write(result).await;
+        });
+        (data_rx, result_rx)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.compress_write(data, Some(offset))
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.compress_write(data, None)
+    }
+
+    async fn advise(
+        &self,
+        _offset: Filesize,
+        _length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        // offsets within the file don't map onto the stored file, so advise all of it
+        self.fd.advise(0, 0, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        let mut writer = Writer::new(&self.fd, Some(size)).await?;
+        writer.truncate();
+        writer.finish().await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_directory()
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.create_directory_at(path).await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        decompressed_stat(&self.fd, self.fd.stat().await?).await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        let stat = self.fd.stat_at(path_flags, path.clone()).await?;
+        if !matches!(stat.type_, DescriptorType::RegularFile) {
+            return Ok(stat);
+        }
+        let fd = self
+            .fd
+            .open_at(path_flags, path, OpenFlags::empty(), DescriptorFlags::READ)
+            .await?;
+        decompressed_stat(&fd, stat).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        // writing rewrites existing frames, so needs to read them
+        let flags = if flags.contains(DescriptorFlags::WRITE) {
+            flags | DescriptorFlags::READ
+        } else {
+            flags
+        };
+        self.fd
+            .open_at(path_flags, path, open_flags, flags)
+            .await
+            .map(|fd| Descriptor::new(CompressDescriptor::new(fd)))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.remove_directory_at(path).await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        self.fd.symlink_at(old_path, new_path).await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.unlink_file_at(path).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemCompress);
@@ -0,0 +1,502 @@
+//! Files are stored in the Zstandard seekable format: a sequence of independently compressed
+//! frames, each holding up to `FRAME_SIZE` bytes of the file, followed by a seek table recording
+//! the compressed and decompressed size of every frame. Any offset can be read by decompressing
+//! only the frames from that offset onwards.
+
+use std::collections::VecDeque;
+
+use ruzstd::decoding::StreamingDecoder;
+use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+use ruzstd::io::Read;
+
+use crate::wasi::filesystem::types::{Descriptor, ErrorCode, Filesize};
+use crate::wit_stream;
+
+/// Bytes of the file in each frame.
+pub(crate) const FRAME_SIZE: usize = 64 * 1024;
+
+/// Bytes of zeros a write past the end of a file may fill in, as stored files can't be sparse.
+const MAX_GAP: Filesize = 16 * 1024 * 1024;
+
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const SKIPPABLE_HEADER_SIZE: Filesize = 8;
+const ENTRY_SIZE: Filesize = 8;
+const FOOTER_SIZE: Filesize = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Frame {
+    compressed: u32,
+    decompressed: u32,
+}
+
+/// The size of a file, given the frames it is stored as.
+pub(crate) fn size(frames: &[Frame]) -> Filesize {
+    frames
+        .iter()
+        .map(|frame| frame.decompressed as Filesize)
+        .sum()
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+/// Read up to `len` bytes from an offset, fewer only at the end of the file.
+async fn read_at(fd: &Descriptor, offset: Filesize, len: usize) -> Result<Vec<u8>, ErrorCode> {
+    let (mut rx, result) = fd.read_via_stream(offset);
+    let mut data = Vec::with_capacity(len.min(FRAME_SIZE));
+    while data.len() < len {
+        let (status, buf) = rx.read(Vec::with_capacity(len - data.len())).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        data.extend(buf);
+    }
+    drop(rx);
+    if data.len() < len {
+        result.await?;
+    }
+    Ok(data)
+}
+
+async fn write_at(fd: &Descriptor, offset: Filesize, data: Vec<u8>) -> Result<(), ErrorCode> {
+    let (mut tx, rx) = wit_stream::new();
+    let result = fd.write_via_stream(rx, offset);
+    let remaining = tx.write_all(data).await;
+    drop(tx);
+    result.await?;
+    if !remaining.is_empty() {
+        return Err(ErrorCode::Io);
+    }
+    Ok(())
+}
+
+/// Read the seek table at the end of a stored file. An empty file has no frames.
+pub(crate) async fn read_frames(fd: &Descriptor) -> Result<Vec<Frame>, ErrorCode> {
+    let stored_size = fd.stat().await?.size;
+    if stored_size == 0 {
+        return Ok(Vec::new());
+    }
+    if stored_size < SKIPPABLE_HEADER_SIZE + FOOTER_SIZE {
+        return Err(ErrorCode::Io);
+    }
+
+    let footer = read_at(fd, stored_size - FOOTER_SIZE, FOOTER_SIZE as usize).await?;
+    if footer.len() < FOOTER_SIZE as usize || u32_at(&footer, 5) != SEEKABLE_MAGIC {
+        return Err(ErrorCode::Io);
+    }
+    let count = u32_at(&footer, 0) as Filesize;
+    let checksums = footer[4] & 0x80 != 0;
+    let entry_size = if checksums {
+        ENTRY_SIZE + 4
+    } else {
+        ENTRY_SIZE
+    };
+    let table_size = count
+        .checked_mul(entry_size)
+        .and_then(|entries_size| entries_size.checked_add(SKIPPABLE_HEADER_SIZE + FOOTER_SIZE))
+        .filter(|table_size| *table_size <= stored_size)
+        .ok_or(ErrorCode::Io)?;
+    let len = usize::try_from(table_size).map_err(|_| ErrorCode::Io)?;
+
+    let table = read_at(fd, stored_size - table_size, len).await?;
+    parse_seek_table(&table)
+}
+
+/// Parse a seek table, from its skippable frame header to its footer.
+fn parse_seek_table(table: &[u8]) -> Result<Vec<Frame>, ErrorCode> {
+    let footer_offset = table
+        .len()
+        .checked_sub(FOOTER_SIZE as usize)
+        .ok_or(ErrorCode::Io)?;
+    if footer_offset < SKIPPABLE_HEADER_SIZE as usize
+        || u32_at(table, 0) != SKIPPABLE_MAGIC
+        || u32_at(table, footer_offset + 5) != SEEKABLE_MAGIC
+    {
+        return Err(ErrorCode::Io);
+    }
+    let count = u32_at(table, footer_offset) as usize;
+    let entry_size = match table[footer_offset + 4] & 0x80 != 0 {
+        true => ENTRY_SIZE as usize + 4,
+        false => ENTRY_SIZE as usize,
+    };
+    let entries_size = count.checked_mul(entry_size).ok_or(ErrorCode::Io)?;
+    if (SKIPPABLE_HEADER_SIZE as usize).checked_add(entries_size) != Some(footer_offset) {
+        return Err(ErrorCode::Io);
+    }
+    Ok((0..count)
+        .map(|i| {
+            let entry = SKIPPABLE_HEADER_SIZE as usize + i * entry_size;
+            Frame {
+                compressed: u32_at(table, entry),
+                decompressed: u32_at(table, entry + 4),
+            }
+        })
+        .collect())
+}
+
+fn seek_table(frames: &[Frame]) -> Vec<u8> {
+    let entries_size = frames.len() as u32 * ENTRY_SIZE as u32;
+    let mut table = Vec::new();
+    table.extend(SKIPPABLE_MAGIC.to_le_bytes());
+    table.extend((entries_size + FOOTER_SIZE as u32).to_le_bytes());
+    for frame in frames {
+        table.extend(frame.compressed.to_le_bytes());
+        table.extend(frame.decompressed.to_le_bytes());
+    }
+    table.extend((frames.len() as u32).to_le_bytes());
+    table.push(0);
+    table.extend(SEEKABLE_MAGIC.to_le_bytes());
+    table
+}
+
+/// Read and decompress a frame stored at an offset. Frames never hold more than `FRAME_SIZE`
+/// bytes, whatever the seek table records.
+async fn read_frame(fd: &Descriptor, offset: Filesize, frame: Frame) -> Result<Vec<u8>, ErrorCode> {
+    let compressed = read_at(fd, offset, frame.compressed as usize).await?;
+    decompress(&compressed, frame)
+}
+
+fn decompress(compressed: &[u8], frame: Frame) -> Result<Vec<u8>, ErrorCode> {
+    let decoder = StreamingDecoder::new(compressed).map_err(|_| ErrorCode::Io)?;
+    let mut data = Vec::with_capacity((frame.decompressed as usize).min(FRAME_SIZE));
+    decoder
+        .take(FRAME_SIZE as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|_| ErrorCode::Io)?;
+    if data.len() > FRAME_SIZE {
+        return Err(ErrorCode::Io);
+    }
+    Ok(data)
+}
+
+/// Decompress a stored file from an offset onwards into a stream.
+pub(crate) async fn decompress_stream(
+    fd: &Descriptor,
+    tx: &mut wit_bindgen::StreamWriter<u8>,
+    offset: Filesize,
+) -> Result<(), ErrorCode> {
+    let mut start = 0;
+    let mut stored_offset = 0;
+    for frame in read_frames(fd).await? {
+        let end = start + frame.decompressed as Filesize;
+        if end > offset {
+            let mut data = read_frame(fd, stored_offset, frame).await?;
+            data.drain(..offset.saturating_sub(start) as usize);
+            if !tx.write_all(data).await.is_empty() {
+                return Ok(());
+            }
+        }
+        start = end;
+        stored_offset += frame.compressed as Filesize;
+    }
+    Ok(())
+}
+
+/// Writes to a file from a position onwards.
+///
+/// Only the frame being written is held decompressed. The frames written, followed by the
+/// stored frames after them moved without recompressing, and a new seek table are staged after
+/// the end of the stored file, leaving its frames untouched until finished, when they are moved
+/// into place after the frames kept. A write failing before then truncates the staged frames, so
+/// the file is unchanged, while a failure moving them truncates the file after the last frame
+/// moved, ending it with a valid seek table. A crash while staging or moving the frames leaves
+/// the file without a seek table at its end.
+pub(crate) struct Writer<'a> {
+    fd: &'a Descriptor,
+    frames: Vec<Frame>,
+    /// How many of the frames are kept in place.
+    kept: usize,
+    /// Where the stored frames kept end, and the new frames go once finished.
+    kept_offset: Filesize,
+    /// Where the new frames are staged, at the end of the stored file.
+    staged_offset: Filesize,
+    /// Bytes of new frames staged.
+    staged: Filesize,
+    /// Stored frames after those in the buffer, with their offsets.
+    tail: VecDeque<(Filesize, Frame)>,
+    buffer: Vec<u8>,
+    cursor: usize,
+}
+
+impl<'a> Writer<'a> {
+    /// Start writing at a position, or at the end of the file when there is none. Positions
+    /// past the end of the file are filled with zeros.
+    pub(crate) async fn new(
+        fd: &'a Descriptor,
+        position: Option<Filesize>,
+    ) -> Result<Self, ErrorCode> {
+        let mut frames = read_frames(fd).await?;
+        let size = size(&frames);
+        let position = position.unwrap_or(size);
+        let gap = position.saturating_sub(size);
+        if gap > MAX_GAP {
+            return Err(ErrorCode::FileTooLarge);
+        }
+
+        // keep the full frames before the position untouched
+        let mut start = 0;
+        let mut kept_offset = 0;
+        let mut kept = 0;
+        for frame in &frames {
+            let end = start + frame.decompressed as Filesize;
+            if end > position || frame.decompressed as usize != FRAME_SIZE {
+                break;
+            }
+            start = end;
+            kept_offset += frame.compressed as Filesize;
+            kept += 1;
+        }
+
+        let mut tail = VecDeque::new();
+        let mut frame_offset = kept_offset;
+        for frame in frames.drain(kept..) {
+            tail.push_back((frame_offset, frame));
+            frame_offset += frame.compressed as Filesize;
+        }
+
+        let mut writer = Self {
+            fd,
+            frames,
+            kept,
+            kept_offset,
+            staged_offset: fd.stat().await?.size,
+            staged: 0,
+            tail,
+            buffer: Vec::new(),
+            cursor: (position.min(size) - start) as usize,
+        };
+        let filled = async {
+            writer.load(writer.cursor).await?;
+            writer.fill(gap).await
+        }
+        .await;
+        if let Err(error) = filled {
+            writer.abort().await;
+            return Err(error);
+        }
+        Ok(writer)
+    }
+
+    /// Decompress stored frames into the buffer until it holds `len` bytes or none are left.
+    async fn load(&mut self, len: usize) -> Result<(), ErrorCode> {
+        while self.buffer.len() < len {
+            let Some((offset, frame)) = self.tail.pop_front() else {
+                break;
+            };
+            self.buffer
+                .extend(read_frame(self.fd, offset, frame).await?);
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn write(&mut self, data: &[u8]) -> Result<(), ErrorCode> {
+        let end = self.cursor + data.len();
+        self.load(end).await?;
+        if self.buffer.len() < end {
+            self.buffer.resize(end, 0);
+        }
+        self.buffer[self.cursor..end].copy_from_slice(data);
+        self.cursor = end;
+
+        // frames before the cursor won't change again
+        while self.cursor >= FRAME_SIZE {
+            self.write_frame().await?;
+            self.cursor -= FRAME_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Write zeros, as when a file is extended.
+    async fn fill(&mut self, mut len: Filesize) -> Result<(), ErrorCode> {
+        let zeros = vec![0; FRAME_SIZE];
+        while len > 0 {
+            let n = len.min(FRAME_SIZE as Filesize);
+            self.write(&zeros[..n as usize]).await?;
+            len -= n;
+        }
+        Ok(())
+    }
+
+    /// Drop everything after the cursor, ending the file there.
+    pub(crate) fn truncate(&mut self) {
+        self.buffer.truncate(self.cursor);
+        self.tail.clear();
+    }
+
+    async fn stage(&mut self, data: Vec<u8>) -> Result<(), ErrorCode> {
+        let len = data.len() as Filesize;
+        write_at(self.fd, self.staged_offset + self.staged, data).await?;
+        self.staged += len;
+        Ok(())
+    }
+
+    async fn write_frame(&mut self) -> Result<(), ErrorCode> {
+        let len = self.buffer.len().min(FRAME_SIZE);
+        let data = self.buffer.drain(..len).collect::<Vec<_>>();
+        let compressed = compress_to_vec(data.as_slice(), CompressionLevel::Fastest);
+        let frame = Frame {
+            compressed: compressed.len() as u32,
+            decompressed: len as u32,
+        };
+        self.stage(compressed).await?;
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// Stage a stored frame after those written, as it was.
+    async fn copy_frame(&mut self, offset: Filesize, frame: Frame) -> Result<(), ErrorCode> {
+        let compressed = read_at(self.fd, offset, frame.compressed as usize).await?;
+        if compressed.len() < frame.compressed as usize {
+            return Err(ErrorCode::Io);
+        }
+        self.stage(compressed).await?;
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// Drop the staged frames, leaving the file as it was.
+    pub(crate) async fn abort(self) {
+        let _ = self.fd.set_size(self.staged_offset).await;
+    }
+
+    /// End the file after the frames moved into place so far, so it is readable again.
+    async fn recover(self, moved: Filesize) {
+        let mut end = self.kept_offset;
+        let mut count = self.kept;
+        for frame in &self.frames[self.kept..] {
+            if end + frame.compressed as Filesize > self.kept_offset + moved {
+                break;
+            }
+            end += frame.compressed as Filesize;
+            count += 1;
+        }
+        if self.fd.set_size(end).await.is_ok() {
+            let _ = write_at(self.fd, end, seek_table(&self.frames[..count])).await;
+        }
+    }
+
+    /// Write the remaining frames and the seek table, then move them into place.
+    pub(crate) async fn finish(mut self) -> Result<(), ErrorCode> {
+        let staged = async {
+            while !self.buffer.is_empty() {
+                self.write_frame().await?;
+            }
+            // the frames after those written end where the buffer did, so they are unchanged
+            while let Some((offset, frame)) = self.tail.pop_front() {
+                self.copy_frame(offset, frame).await?;
+            }
+            self.stage(seek_table(&self.frames)).await
+        }
+        .await;
+        if let Err(error) = staged {
+            self.abort().await;
+            return Err(error);
+        }
+
+        // copying forwards never overwrites what is still to be copied, as the staged frames
+        // are after where they go
+        let mut moved = 0;
+        while moved < self.staged && self.kept_offset != self.staged_offset {
+            let len = (self.staged - moved).min(FRAME_SIZE as Filesize) as usize;
+            let copied = async {
+                let data = read_at(self.fd, self.staged_offset + moved, len).await?;
+                if data.len() < len {
+                    return Err(ErrorCode::Io);
+                }
+                write_at(self.fd, self.kept_offset + moved, data).await
+            }
+            .await;
+            if let Err(error) = copied {
+                self.recover(moved).await;
+                return Err(error);
+            }
+            moved += len as Filesize;
+        }
+        self.fd.set_size(self.kept_offset + self.staged).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(compressed: u32, decompressed: u32) -> Frame {
+        Frame {
+            compressed,
+            decompressed,
+        }
+    }
+
+    #[test]
+    fn seek_table_round_trips() {
+        let frames = [frame(100, FRAME_SIZE as u32), frame(7, 3)];
+        let table = seek_table(&frames);
+        assert_eq!(
+            table.len() as Filesize,
+            SKIPPABLE_HEADER_SIZE + 2 * ENTRY_SIZE + FOOTER_SIZE
+        );
+        assert_eq!(parse_seek_table(&table).unwrap(), frames);
+        assert_eq!(size(&frames), FRAME_SIZE as Filesize + 3);
+    }
+
+    #[test]
+    fn empty_seek_table() {
+        assert_eq!(parse_seek_table(&seek_table(&[])).unwrap(), []);
+    }
+
+    #[test]
+    fn seek_table_with_checksums() {
+        let mut table = Vec::new();
+        table.extend(SKIPPABLE_MAGIC.to_le_bytes());
+        table.extend(21u32.to_le_bytes());
+        table.extend([5, 0, 0, 0, 9, 0, 0, 0, 0xaa, 0xbb, 0xcc, 0xdd]);
+        table.extend(1u32.to_le_bytes());
+        table.push(0x80);
+        table.extend(SEEKABLE_MAGIC.to_le_bytes());
+        assert_eq!(parse_seek_table(&table).unwrap(), [frame(5, 9)]);
+    }
+
+    #[test]
+    fn rejects_corrupt_seek_tables() {
+        let table = seek_table(&[frame(100, 200)]);
+        assert!(parse_seek_table(&table[1..]).is_err());
+        assert!(parse_seek_table(&table[..FOOTER_SIZE as usize]).is_err());
+        let mut wrong_count = table.clone();
+        wrong_count[table.len() - FOOTER_SIZE as usize] = 2;
+        assert!(parse_seek_table(&wrong_count).is_err());
+        let mut wrong_magic = table;
+        wrong_magic[0] ^= 1;
+        assert!(parse_seek_table(&wrong_magic).is_err());
+    }
+
+    #[test]
+    fn rejects_seek_tables_with_too_many_frames() {
+        let mut table = seek_table(&[]);
+        let footer_offset = table.len() - FOOTER_SIZE as usize;
+        table[footer_offset..footer_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        table[footer_offset + 4] = 0x80;
+        assert!(parse_seek_table(&table).is_err());
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let data = vec![42; 1000];
+        let compressed = compress_to_vec(data.as_slice(), CompressionLevel::Fastest);
+        let stored = frame(compressed.len() as u32, data.len() as u32);
+        assert_eq!(decompress(&compressed, stored).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_frames_larger_than_frame_size() {
+        let data = vec![0; FRAME_SIZE + 1];
+        let compressed = compress_to_vec(data.as_slice(), CompressionLevel::Fastest);
+        // the seek table doesn't bound how much is decompressed
+        let stored = frame(compressed.len() as u32, u32::MAX);
+        assert!(decompress(&compressed, stored).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::rc::Rc;
+
+use vfs::exports::wasi::filesystem::preopens::Guest as Preopens;
+use vfs::exports::wasi::filesystem::types::{Descriptor, ErrorCode, Filesize, Guest as Types};
+use vfs::wasi::config::store;
+use vfs::wasi::logging::logging::{log, Level};
+use vfs::{wit_future, wit_stream, Node, Tree, VfsDescriptor};
+
+const PREOPEN_KEY: &str = "preopen";
+
+thread_local! {
+    static TREE: Rc<Tree<Contents>> = Rc::new(tree_from_config());
+}
+
+/// A file for each config key, with dots in the key separating directories.
+fn tree_from_config() -> Tree<Contents> {
+    let (tree, skipped) = tree(store::get_all().expect("Config must resolve"));
+    for (key, error) in skipped {
+        log(
+            Level::Warn,
+            "filesystem",
+            &format!("skipping config key '{key}': {error:?}"),
+        );
+    }
+    tree
+}
+
+/// A tree of the config entries, and the keys that could not be added to it.
+fn tree(mut entries: Vec<(String, String)>) -> (Tree<Contents>, Vec<(String, ErrorCode)>) {
+    // a key sorts before the keys nested within it, so a key that is both a file and a
+    // directory is kept as a file
+    entries.sort();
+    let mut tree = Tree::new();
+    let mut skipped = Vec::new();
+    for (key, value) in entries {
+        if key == PREOPEN_KEY {
+            continue;
+        }
+        let path = key.replace('.', "/");
+        let contents = Contents(value.into_bytes());
+        if let Err(error) = tree.insert(&path, Node::File(contents), None) {
+            skipped.push((key, error));
+        }
+    }
+    (tree, skipped)
+}
+
+/// The value of a config key.
+pub(crate) struct Contents(Vec<u8>);
+
+impl vfs::Contents for Contents {
+    fn size(&self) -> Filesize {
+        self.0.len() as Filesize
+    }
+
+    fn read(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let data = self.0[offset.min(self.size()) as usize..].to_vec();
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            data_tx.write_all(data).await;
+            drop(data_tx);
+            let _ = result_tx.write(Ok(())).await;
+        });
+        (data_rx, result_rx)
+    }
+}
+
+struct FilesystemConfig {}
+
+impl Preopens for FilesystemConfig {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let path = store::get(PREOPEN_KEY)
+            .expect("Config must resolve")
+            .unwrap_or(String::from("/"));
+        let fd = Descriptor::new(VfsDescriptor::root(TREE.with(Rc::clone)));
+        vec![(fd, path)]
+    }
+}
+
+impl Types for FilesystemConfig {
+    type Descriptor = VfsDescriptor<Contents>;
+}
+
+vfs::export!(FilesystemConfig);
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    use vfs::exports::wasi::filesystem::types::{DescriptorType, GuestDescriptor, PathFlags};
+
+    use super::*;
+
+    /// The tree of config entries, and the keys skipped.
+    fn config(entries: &[(&str, &str)]) -> (Rc<Tree<Contents>>, Vec<(String, ErrorCode)>) {
+        let entries = entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let (tree, skipped) = tree(entries);
+        (Rc::new(tree), skipped)
+    }
+
+    /// The type and size of what a path in a tree resolves to.
+    fn stat(tree: &Rc<Tree<Contents>>, path: &str) -> Option<(DescriptorType, Filesize)> {
+        let root = VfsDescriptor::root(tree.clone());
+        let stat = pin!(root.stat_at(PathFlags::empty(), path.to_string()));
+        // the tree is in memory, so the stat is ready without waiting
+        let Poll::Ready(stat) = stat.poll(&mut Context::from_waker(Waker::noop())) else {
+            unreachable!("stat of a tree is ready")
+        };
+        stat.ok().map(|stat| (stat.type_, stat.size))
+    }
+
+    #[test]
+    fn dots_separate_directories() {
+        let (tree, skipped) = config(&[("db.primary.host", "localhost"), ("name", "app")]);
+        assert!(skipped.is_empty());
+        assert!(matches!(
+            stat(&tree, "db/primary/host"),
+            Some((DescriptorType::RegularFile, 9))
+        ));
+        assert!(matches!(
+            stat(&tree, "db/primary"),
+            Some((DescriptorType::Directory, _))
+        ));
+        assert!(matches!(
+            stat(&tree, "name"),
+            Some((DescriptorType::RegularFile, 3))
+        ));
+    }
+
+    #[test]
+    fn values_are_kept_over_nested_keys() {
+        // listed in any order, the value sorts first
+        let (tree, skipped) = config(&[("db.host", "localhost"), ("db", "primary")]);
+        assert!(matches!(
+            skipped.as_slice(),
+            [(key, ErrorCode::NotDirectory)] if key == "db.host"
+        ));
+        assert!(matches!(
+            stat(&tree, "db"),
+            Some((DescriptorType::RegularFile, 7))
+        ));
+    }
+
+    #[test]
+    fn preopen_key_is_not_listed() {
+        let (tree, skipped) = config(&[(PREOPEN_KEY, "/config")]);
+        assert!(skipped.is_empty());
+        assert!(stat(&tree, PREOPEN_KEY).is_none());
+    }
+
+    #[test]
+    fn empty_names_are_ignored() {
+        let (tree, skipped) = config(&[("a..b.", "x")]);
+        assert!(skipped.is_empty());
+        assert!(matches!(
+            stat(&tree, "a/b"),
+            Some((DescriptorType::RegularFile, 1))
+        ));
+    }
+
+    #[test]
+    fn keys_stay_within_the_root() {
+        // every dot separates names, so no name is '..'
+        let (tree, skipped) = config(&[("../secret", "x")]);
+        assert!(skipped.is_empty());
+        assert!(matches!(
+            stat(&tree, "secret"),
+            Some((DescriptorType::RegularFile, 1))
+        ));
+    }
+}
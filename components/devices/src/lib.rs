@@ -0,0 +1,529 @@
+#![no_main]
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+const PREOPEN_KEY: &str = "preopen";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Device {
+    /// Reads nothing and discards writes.
+    Null,
+    /// Reads zeros and discards writes.
+    Zero,
+    /// Reads zeros and fails writes as if there is no space left.
+    Full,
+}
+
+impl Device {
+    const ALL: [Device; 3] = [Device::Null, Device::Zero, Device::Full];
+
+    fn name(self) -> &'static str {
+        match self {
+            Device::Null => "null",
+            Device::Zero => "zero",
+            Device::Full => "full",
+        }
+    }
+
+    fn named(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|device| device.name() == name)
+    }
+}
+
+/// The device at a path within the devices directory, or none for the directory itself.
+fn device_at(path: &str) -> Result<Option<Device>, ErrorCode> {
+    if path.starts_with('/') {
+        return Err(ErrorCode::NotPermitted);
+    }
+    let names = path
+        .split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .collect::<Vec<_>>();
+    match names.as_slice() {
+        [] => Ok(None),
+        ["..", ..] => Err(ErrorCode::NotPermitted),
+        [name] => Device::named(name).map(Some).ok_or(ErrorCode::NoEntry),
+        [name, ..] => match Device::named(name) {
+            Some(_) => Err(ErrorCode::NotDirectory),
+            None => Err(ErrorCode::NoEntry),
+        },
+    }
+}
+
+fn stat(device: Option<Device>) -> DescriptorStat {
+    DescriptorStat {
+        type_: match device {
+            Some(_) => DescriptorType::CharacterDevice,
+            None => DescriptorType::Directory,
+        },
+        link_count: 1,
+        size: 0,
+        data_access_timestamp: None,
+        data_modification_timestamp: None,
+        status_change_timestamp: None,
+    }
+}
+
+fn metadata_hash(device: Option<Device>) -> MetadataHashValue {
+    MetadataHashValue {
+        lower: device.map_or(0, |device| device as u64 + 1),
+        upper: 0,
+    }
+}
+
+fn failed(error: ErrorCode) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    let (tx, rx) = wit_future::new(|| Err(ErrorCode::Io));
+    wit_bindgen::spawn(async move {
+        let _ = tx.write(Err(error)).await;
+    });
+    rx
+}
+
+/// Read from a device, as with Linux.
+fn read(
+    device: Device,
+) -> (
+    wit_bindgen::StreamReader<u8>,
+    wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) {
+    let (mut data_tx, data_rx) = wit_stream::new();
+    let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+    wit_bindgen::spawn(async move {
+        if device != Device::Null {
+            // zeros without end, until the reader stops reading
+            while data_tx.write_all(vec![0; CHUNK_SIZE]).await.is_empty() {}
+        }
+        drop(data_tx);
+        let _ = result_tx.write(Ok(())).await;
+    });
+    (data_rx, result_rx)
+}
+
+/// Write to a device, as with Linux.
+fn write(
+    device: Device,
+    mut data: wit_bindgen::StreamReader<u8>,
+) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    if device == Device::Full {
+        return failed(ErrorCode::InsufficientSpace);
+    }
+    let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+    wit_bindgen::spawn(async move {
+        // discard everything written
+        loop {
+            let (status, buf) = data.read(Vec::with_capacity(CHUNK_SIZE)).await;
+            if buf.is_empty() {
+                match status {
+                    wit_bindgen::StreamResult::Complete(_) => continue,
+                    _ => break,
+                }
+            }
+        }
+        drop(data);
+        let _ = result_tx.write(Ok(())).await;
+    });
+    result_rx
+}
+
+struct FilesystemDevices {}
+
+impl Preopens for FilesystemDevices {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let mut dirs = preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| (Descriptor::new(DevicesDescriptor::Host(fd)), path))
+            .collect::<Vec<_>>();
+        let path = wasi::config::store::get(PREOPEN_KEY)
+            .expect("Config must resolve")
+            .unwrap_or(String::from("/dev"));
+        dirs.push((
+            Descriptor::new(DevicesDescriptor::Directory(DescriptorFlags::READ)),
+            path,
+        ));
+        dirs
+    }
+}
+
+impl Types for FilesystemDevices {
+    type Descriptor = DevicesDescriptor;
+}
+
+enum DevicesDescriptor {
+    /// A descriptor of the imported file system, passed through unchanged.
+    Host(types::Descriptor),
+    /// The directory holding the devices.
+    Directory(DescriptorFlags),
+    Device(Device, DescriptorFlags),
+}
+
+impl DevicesDescriptor {
+    /// The device at a path within this directory, or none for the directory itself.
+    fn device_at(&self, path: &str) -> Result<Option<Device>, ErrorCode> {
+        match self {
+            DevicesDescriptor::Directory(_) => device_at(path),
+            _ => Err(ErrorCode::NotDirectory),
+        }
+    }
+
+    fn device(&self) -> Option<Device> {
+        match self {
+            DevicesDescriptor::Device(device, _) => Some(*device),
+            _ => None,
+        }
+    }
+}
+
+impl GuestDescriptor for DevicesDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.read_via_stream(offset),
+            DevicesDescriptor::Directory(_) => {
+                (wit_stream::new().1, failed(ErrorCode::IsDirectory))
+            }
+            DevicesDescriptor::Device(_, flags) if !flags.contains(DescriptorFlags::READ) => {
+                (wit_stream::new().1, failed(ErrorCode::BadDescriptor))
+            }
+            DevicesDescriptor::Device(device, _) => read(*device),
+        }
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.write_via_stream(data, offset),
+            DevicesDescriptor::Directory(_) => failed(ErrorCode::IsDirectory),
+            DevicesDescriptor::Device(_, flags) if !flags.contains(DescriptorFlags::WRITE) => {
+                failed(ErrorCode::BadDescriptor)
+            }
+            DevicesDescriptor::Device(device, _) => write(*device, data),
+        }
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.append_via_stream(data),
+            DevicesDescriptor::Directory(_) => failed(ErrorCode::IsDirectory),
+            DevicesDescriptor::Device(_, flags) if !flags.contains(DescriptorFlags::WRITE) => {
+                failed(ErrorCode::BadDescriptor)
+            }
+            DevicesDescriptor::Device(device, _) => write(*device, data),
+        }
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.advise(offset, length, advice).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.sync_data().await,
+            _ => Err(ErrorCode::Invalid),
+        }
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.get_flags().await,
+            DevicesDescriptor::Directory(flags) | DevicesDescriptor::Device(_, flags) => Ok(*flags),
+        }
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.get_type().await,
+            _ => Ok(stat(self.device()).type_),
+        }
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.set_size(size).await,
+            DevicesDescriptor::Directory(_) => Err(ErrorCode::IsDirectory),
+            DevicesDescriptor::Device(..) => Err(ErrorCode::Invalid),
+        }
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => {
+                fd.set_times(data_access_timestamp, data_modification_timestamp)
+                    .await
+            }
+            _ => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.read_directory(),
+            DevicesDescriptor::Directory(_) => {
+                let (mut tx, rx) = wit_stream::new();
+                let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+                wit_bindgen::spawn(async move {
+                    let entries = Device::ALL
+                        .into_iter()
+                        .map(|device| DirectoryEntry {
+                            type_: DescriptorType::CharacterDevice,
+                            name: device.name().to_string(),
+                        })
+                        .collect();
+                    tx.write_all(entries).await;
+                    drop(tx);
+                    let _ = result_tx.write(Ok(())).await;
+                });
+                (rx, result_rx)
+            }
+            DevicesDescriptor::Device(..) => (wit_stream::new().1, failed(ErrorCode::NotDirectory)),
+        }
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.sync().await,
+            _ => Err(ErrorCode::Invalid),
+        }
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd.create_directory_at(path).await;
+        }
+        match self.device_at(&path) {
+            Ok(_) => Err(ErrorCode::Exist),
+            Err(ErrorCode::NoEntry) => Err(ErrorCode::ReadOnly),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.stat().await,
+            _ => Ok(stat(self.device())),
+        }
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd.stat_at(path_flags, path).await;
+        }
+        Ok(stat(self.device_at(&path)?))
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd
+                .set_times_at(
+                    path_flags,
+                    path,
+                    data_access_timestamp,
+                    data_modification_timestamp,
+                )
+                .await;
+        }
+        self.device_at(&path)?;
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        match (self, new_descriptor) {
+            (DevicesDescriptor::Host(fd), DevicesDescriptor::Host(new_fd)) => {
+                fd.link_at(old_path_flags, old_path, new_fd, new_path).await
+            }
+            (DevicesDescriptor::Host(_), _) | (_, DevicesDescriptor::Host(_)) => {
+                Err(ErrorCode::CrossDevice)
+            }
+            _ => {
+                self.device_at(&old_path)?;
+                Err(ErrorCode::ReadOnly)
+            }
+        }
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd
+                .open_at(path_flags, path, open_flags, flags)
+                .await
+                .map(|fd| Descriptor::new(DevicesDescriptor::Host(fd)));
+        }
+        let device = match self.device_at(&path) {
+            Err(ErrorCode::NoEntry) if open_flags.contains(OpenFlags::CREATE) => {
+                return Err(ErrorCode::ReadOnly)
+            }
+            result => result?,
+        };
+        if open_flags.contains(OpenFlags::CREATE) && open_flags.contains(OpenFlags::EXCLUSIVE) {
+            return Err(ErrorCode::Exist);
+        }
+        match device {
+            None if flags.contains(DescriptorFlags::WRITE)
+                || flags.contains(DescriptorFlags::MUTATE_DIRECTORY) =>
+            {
+                Err(ErrorCode::IsDirectory)
+            }
+            None => Ok(Descriptor::new(DevicesDescriptor::Directory(flags))),
+            Some(_) if open_flags.contains(OpenFlags::DIRECTORY) => Err(ErrorCode::NotDirectory),
+            // truncating a device has no effect
+            Some(device) => Ok(Descriptor::new(DevicesDescriptor::Device(device, flags))),
+        }
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd.readlink_at(path).await;
+        }
+        self.device_at(&path)?;
+        Err(ErrorCode::Invalid)
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd.remove_directory_at(path).await;
+        }
+        match self.device_at(&path)? {
+            Some(_) => Err(ErrorCode::NotDirectory),
+            None => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        match (self, new_descriptor) {
+            (DevicesDescriptor::Host(fd), DevicesDescriptor::Host(new_fd)) => {
+                fd.rename_at(old_path, new_fd, new_path).await
+            }
+            (DevicesDescriptor::Host(_), _) | (_, DevicesDescriptor::Host(_)) => {
+                Err(ErrorCode::CrossDevice)
+            }
+            _ => {
+                self.device_at(&old_path)?;
+                Err(ErrorCode::ReadOnly)
+            }
+        }
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd.symlink_at(old_path, new_path).await;
+        }
+        match self.device_at(&new_path) {
+            Ok(_) => Err(ErrorCode::Exist),
+            Err(ErrorCode::NoEntry) => Err(ErrorCode::ReadOnly),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd.unlink_file_at(path).await;
+        }
+        match self.device_at(&path)? {
+            Some(_) => Err(ErrorCode::ReadOnly),
+            None => Err(ErrorCode::IsDirectory),
+        }
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        match (self, other) {
+            (DevicesDescriptor::Host(fd), DevicesDescriptor::Host(other_fd)) => {
+                fd.is_same_object(other_fd).await
+            }
+            (DevicesDescriptor::Host(_), _) | (_, DevicesDescriptor::Host(_)) => false,
+            _ => self.device() == other.device(),
+        }
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        match self {
+            DevicesDescriptor::Host(fd) => fd.metadata_hash().await,
+            _ => Ok(metadata_hash(self.device())),
+        }
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        if let DevicesDescriptor::Host(fd) = self {
+            return fd.metadata_hash_at(path_flags, path).await;
+        }
+        Ok(metadata_hash(self.device_at(&path)?))
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemDevices);
@@ -0,0 +1,56 @@
+//! Lists the contents of the 'files' directory as entries of the embedded file system, with the
+//! data of each file included in the component.
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn main() {
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("files");
+    println!("cargo:rerun-if-changed={}", root.display());
+
+    let mut entries = String::new();
+    if root.exists() {
+        visit(&root, "", &mut entries);
+    }
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("entries.rs");
+    fs::write(
+        out,
+        format!("const ENTRIES: &[Embedded] = &[\n{entries}];\n"),
+    )
+    .unwrap();
+}
+
+fn visit(dir: &Path, prefix: &str, entries: &mut String) {
+    let mut children = fs::read_dir(dir)
+        .unwrap()
+        .map(|child| child.unwrap())
+        .collect::<Vec<_>>();
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let name = child
+            .file_name()
+            .into_string()
+            .expect("Embedded file names must be UTF-8");
+        let path = format!("{prefix}{name}");
+        let metadata = fs::symlink_metadata(child.path()).unwrap();
+        if metadata.is_symlink() {
+            let target = fs::read_link(child.path()).unwrap();
+            let target = target
+                .to_str()
+                .expect("Embedded symlink targets must be UTF-8");
+            writeln!(entries, "    Embedded::Symlink({path:?}, {target:?}),").unwrap();
+        } else if metadata.is_dir() {
+            writeln!(entries, "    Embedded::Directory({path:?}),").unwrap();
+            visit(&child.path(), &format!("{path}/"), entries);
+        } else if metadata.is_file() {
+            let file = child.path().display().to_string();
+            writeln!(
+                entries,
+                "    Embedded::File({path:?}, include_bytes!({file:?})),"
+            )
+            .unwrap();
+        }
+    }
+}
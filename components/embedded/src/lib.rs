@@ -0,0 +1,85 @@
+#![no_main]
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use vfs::exports::wasi::filesystem::preopens::Guest as Preopens;
+use vfs::exports::wasi::filesystem::types::{Descriptor, ErrorCode, Filesize, Guest as Types};
+use vfs::{wit_future, wit_stream, Node, Tree, VfsDescriptor};
+
+const PREOPEN_KEY: &str = "preopen";
+
+/// An entry of the 'files' directory, listed by the build script with its path relative to the
+/// directory.
+#[allow(dead_code)] // the listed files may not need every kind of entry
+enum Embedded {
+    Directory(&'static str),
+    File(&'static str, &'static [u8]),
+    Symlink(&'static str, &'static str),
+}
+
+include!(concat!(env!("OUT_DIR"), "/entries.rs"));
+
+thread_local! {
+    static TREE: Rc<Tree<Contents>> = Rc::new(tree());
+}
+
+fn tree() -> Tree<Contents> {
+    let mut tree = Tree::new();
+    for entry in ENTRIES {
+        let result = match entry {
+            Embedded::Directory(path) => tree.insert(path, Node::Directory(BTreeMap::new()), None),
+            Embedded::File(path, data) => tree.insert(path, Node::File(Contents(data)), None),
+            Embedded::Symlink(path, target) => {
+                tree.insert(path, Node::Symlink(target.to_string()), None)
+            }
+        };
+        result.expect("Embedded paths must be relative");
+    }
+    tree
+}
+
+/// The data of a file, embedded in the component.
+pub(crate) struct Contents(&'static [u8]);
+
+impl vfs::Contents for Contents {
+    fn size(&self) -> Filesize {
+        self.0.len() as Filesize
+    }
+
+    fn read(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let data = &self.0[offset.min(self.size()) as usize..];
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            data_tx.write_all(data.to_vec()).await;
+            drop(data_tx);
+            let _ = result_tx.write(Ok(())).await;
+        });
+        (data_rx, result_rx)
+    }
+}
+
+struct FilesystemEmbedded {}
+
+impl Preopens for FilesystemEmbedded {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let path = vfs::wasi::config::store::get(PREOPEN_KEY)
+            .expect("Config must resolve")
+            .unwrap_or(String::from("/"));
+        let fd = Descriptor::new(VfsDescriptor::root(TREE.with(Rc::clone)));
+        vec![(fd, path)]
+    }
+}
+
+impl Types for FilesystemEmbedded {
+    type Descriptor = VfsDescriptor<Contents>;
+}
+
+vfs::export!(FilesystemEmbedded);
@@ -0,0 +1,413 @@
+//! File contents are stored as a random per-file header followed by a sequence of independently
+//! encrypted chunks. Each chunk holds up to `CHUNK_SIZE` bytes of plaintext, stored as a random
+//! nonce followed by the ciphertext and its authentication tag. The file's header, the chunk's
+//! index and whether it is the last chunk are authenticated with it, so chunks cannot be
+//! reordered within a file, moved between files, or dropped from the end of a file.
+//!
+//! An empty file is stored empty, without a header, which is written before its first chunk.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::key;
+use crate::wasi::filesystem::types::{Descriptor, ErrorCode, Filesize};
+use crate::wasi::random::random;
+use crate::wit_stream;
+
+/// Bytes of plaintext in each chunk.
+pub(crate) const CHUNK_SIZE: Filesize = 4096;
+/// Bytes of the random header identifying a file.
+const HEADER_SIZE: Filesize = 16;
+const NONCE_SIZE: Filesize = 24;
+const TAG_SIZE: Filesize = 16;
+/// Bytes each chunk adds to the plaintext.
+const OVERHEAD: Filesize = NONCE_SIZE + TAG_SIZE;
+/// Bytes of a full chunk as stored.
+const STORED_CHUNK_SIZE: Filesize = CHUNK_SIZE + OVERHEAD;
+
+thread_local! {
+    static CIPHER: XChaCha20Poly1305 = cipher_from_config();
+}
+
+fn cipher_from_config() -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new_from_slice(&key()).expect("Config 'key' must be 32 bytes")
+}
+
+/// The size of a file's plaintext, given the size it is stored as.
+pub(crate) fn plaintext_size(stored_size: Filesize) -> Filesize {
+    let Some(stored_size) = stored_size.checked_sub(HEADER_SIZE) else {
+        return 0;
+    };
+    let chunks = stored_size / STORED_CHUNK_SIZE;
+    let rest = stored_size % STORED_CHUNK_SIZE;
+    chunks * CHUNK_SIZE + rest.saturating_sub(OVERHEAD)
+}
+
+/// The size a file is stored as, given the size of its plaintext.
+pub(crate) fn stored_size(plaintext_size: Filesize) -> Filesize {
+    let chunks = plaintext_size / CHUNK_SIZE;
+    match plaintext_size % CHUNK_SIZE {
+        0 if chunks == 0 => 0,
+        0 => HEADER_SIZE + chunks * STORED_CHUNK_SIZE,
+        rest => HEADER_SIZE + chunks * STORED_CHUNK_SIZE + rest + OVERHEAD,
+    }
+}
+
+/// Where a chunk is stored, saturating for chunks far beyond any file.
+pub(crate) fn stored_offset(index: Filesize) -> Filesize {
+    index
+        .saturating_mul(STORED_CHUNK_SIZE)
+        .saturating_add(HEADER_SIZE)
+}
+
+/// The data authenticated with a chunk.
+fn aad(header: &[u8], index: Filesize, last: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend(index.to_le_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+fn seal(
+    cipher: &XChaCha20Poly1305,
+    mut nonce: Vec<u8>,
+    header: &[u8],
+    index: Filesize,
+    last: bool,
+    plaintext: &[u8],
+) -> Vec<u8> {
+    let ciphertext = cipher.encrypt(
+        XNonce::from_slice(&nonce),
+        Payload {
+            msg: plaintext,
+            aad: &aad(header, index, last),
+        },
+    );
+    nonce.extend(ciphertext.expect("Encryption must succeed"));
+    nonce
+}
+
+fn open(
+    cipher: &XChaCha20Poly1305,
+    header: &[u8],
+    index: Filesize,
+    last: bool,
+    chunk: &[u8],
+) -> Result<Vec<u8>, ErrorCode> {
+    if (chunk.len() as Filesize) < OVERHEAD {
+        return Err(ErrorCode::Io);
+    }
+    let (nonce, ciphertext) = chunk.split_at(NONCE_SIZE as usize);
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad(header, index, last),
+            },
+        )
+        .map_err(|_| ErrorCode::Io)
+}
+
+pub(crate) fn encrypt(header: &[u8], index: Filesize, last: bool, plaintext: &[u8]) -> Vec<u8> {
+    let nonce = random::get_random_bytes(NONCE_SIZE);
+    CIPHER.with(|cipher| seal(cipher, nonce, header, index, last, plaintext))
+}
+
+pub(crate) fn decrypt(
+    header: &[u8],
+    index: Filesize,
+    last: bool,
+    chunk: &[u8],
+) -> Result<Vec<u8>, ErrorCode> {
+    CIPHER.with(|cipher| open(cipher, header, index, last, chunk))
+}
+
+/// Read up to `len` bytes from a stream, fewer only once the stream has ended.
+async fn read_up_to(rx: &mut wit_bindgen::StreamReader<u8>, len: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let (status, buf) = rx.read(Vec::with_capacity(len - data.len())).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        data.extend(buf);
+    }
+    data
+}
+
+/// Read the header of a file, which an empty file doesn't have yet.
+pub(crate) async fn read_header(fd: &Descriptor) -> Result<Option<Vec<u8>>, ErrorCode> {
+    let (mut rx, result) = fd.read_via_stream(0);
+    let header = read_up_to(&mut rx, HEADER_SIZE as usize).await;
+    drop(rx);
+    if header.len() < HEADER_SIZE as usize {
+        result.await?;
+    }
+    match header.len() {
+        0 => Ok(None),
+        len if len < HEADER_SIZE as usize => Err(ErrorCode::Io),
+        _ => Ok(Some(header)),
+    }
+}
+
+/// Read the header of a file, storing a new one when the file is empty.
+async fn header(fd: &Descriptor) -> Result<Vec<u8>, ErrorCode> {
+    if let Some(header) = read_header(fd).await? {
+        return Ok(header);
+    }
+    let header = random::get_random_bytes(HEADER_SIZE);
+    write_at(fd, 0, header.clone()).await?;
+    Ok(header)
+}
+
+async fn write_at(fd: &Descriptor, offset: Filesize, data: Vec<u8>) -> Result<(), ErrorCode> {
+    let (mut tx, rx) = wit_stream::new();
+    let result = fd.write_via_stream(rx, offset);
+    let remaining = tx.write_all(data).await;
+    drop(tx);
+    result.await?;
+    if !remaining.is_empty() {
+        return Err(ErrorCode::Io);
+    }
+    Ok(())
+}
+
+/// Read and decrypt the plaintext of a chunk, which is empty past the end of the file.
+pub(crate) async fn read_chunk(
+    fd: &Descriptor,
+    header: &[u8],
+    index: Filesize,
+) -> Result<Vec<u8>, ErrorCode> {
+    // reading a byte beyond the chunk tells whether it is the last
+    let (mut rx, result) = fd.read_via_stream(stored_offset(index));
+    let mut chunk = read_up_to(&mut rx, STORED_CHUNK_SIZE as usize + 1).await;
+    drop(rx);
+    let last = chunk.len() <= STORED_CHUNK_SIZE as usize;
+    if last {
+        result.await?;
+    }
+    if chunk.is_empty() {
+        return Ok(chunk);
+    }
+    chunk.truncate(STORED_CHUNK_SIZE as usize);
+    decrypt(header, index, last, &chunk)
+}
+
+/// Encrypt and store the plaintext of a chunk.
+pub(crate) async fn write_chunk(
+    fd: &Descriptor,
+    header: &[u8],
+    index: Filesize,
+    last: bool,
+    plaintext: &[u8],
+) -> Result<(), ErrorCode> {
+    write_at(
+        fd,
+        stored_offset(index),
+        encrypt(header, index, last, plaintext),
+    )
+    .await
+}
+
+/// Decrypt a stream of stored chunks into plaintext, starting at a plaintext offset.
+pub(crate) async fn decrypt_stream(
+    rx: &mut wit_bindgen::StreamReader<u8>,
+    tx: &mut wit_bindgen::StreamWriter<u8>,
+    header: &[u8],
+    offset: Filesize,
+) -> Result<(), ErrorCode> {
+    let mut index = offset / CHUNK_SIZE;
+    let mut skip = (offset % CHUNK_SIZE) as usize;
+    let mut chunk = read_up_to(rx, STORED_CHUNK_SIZE as usize).await;
+    while !chunk.is_empty() {
+        // the chunk is the last when nothing follows it
+        let next = match chunk.len() < STORED_CHUNK_SIZE as usize {
+            true => Vec::new(),
+            false => read_up_to(rx, STORED_CHUNK_SIZE as usize).await,
+        };
+        let mut plaintext = decrypt(header, index, next.is_empty(), &chunk)?;
+        plaintext.drain(..skip.min(plaintext.len()));
+        skip = 0;
+        index += 1;
+        if !tx.write_all(plaintext).await.is_empty() {
+            return Ok(());
+        }
+        chunk = next;
+    }
+    Ok(())
+}
+
+/// Writes plaintext from a position onwards, re-encrypting each chunk it touches while keeping
+/// any existing plaintext around the written range.
+///
+/// A chunk is only stored once the writer moves past it or is flushed, so it is known whether
+/// it ends the file.
+pub(crate) struct Writer<'a> {
+    fd: &'a Descriptor,
+    header: Vec<u8>,
+    index: Filesize,
+    chunk: Vec<u8>,
+    offset: usize,
+    dirty: bool,
+    /// Whether the chunk is stored as the last of the file.
+    last: bool,
+    /// The size of the file's plaintext, including what has been written.
+    size: Filesize,
+}
+
+impl<'a> Writer<'a> {
+    /// Start writing at a position of a file of a size, which must not be beyond its end.
+    pub(crate) async fn new(
+        fd: &'a Descriptor,
+        position: Filesize,
+        size: Filesize,
+    ) -> Result<Self, ErrorCode> {
+        let header = header(fd).await?;
+        // appending after a full last chunk starts from it, so it is stored as no longer last
+        let (index, offset) = match position == size && size > 0 && size % CHUNK_SIZE == 0 {
+            true => (size / CHUNK_SIZE - 1, CHUNK_SIZE as usize),
+            false => (position / CHUNK_SIZE, (position % CHUNK_SIZE) as usize),
+        };
+        let chunk = read_chunk(fd, &header, index).await?;
+        let mut writer = Self {
+            fd,
+            header,
+            index,
+            chunk,
+            offset,
+            dirty: false,
+            last: false,
+            size,
+        };
+        writer.last = !writer.chunk.is_empty() && writer.ends_file();
+        Ok(writer)
+    }
+
+    /// Whether the file ends within the chunk.
+    fn ends_file(&self) -> bool {
+        (self.index + 1) * CHUNK_SIZE >= self.size
+    }
+
+    pub(crate) async fn write(&mut self, mut data: &[u8]) -> Result<(), ErrorCode> {
+        while !data.is_empty() {
+            if self.offset == CHUNK_SIZE as usize {
+                self.next().await?;
+            }
+            let len = data.len().min(CHUNK_SIZE as usize - self.offset);
+            let end = self.offset + len;
+            if self.chunk.len() < end {
+                self.chunk.resize(end, 0);
+            }
+            self.chunk[self.offset..end].copy_from_slice(&data[..len]);
+            self.offset = end;
+            self.dirty = true;
+            self.size = self.size.max(self.index * CHUNK_SIZE + end as Filesize);
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Move on to the next chunk as more is written, storing this one as not the last.
+    async fn next(&mut self) -> Result<(), ErrorCode> {
+        if self.dirty || self.last {
+            write_chunk(self.fd, &self.header, self.index, false, &self.chunk).await?;
+        }
+        self.index += 1;
+        self.chunk = read_chunk(self.fd, &self.header, self.index).await?;
+        self.offset = 0;
+        self.dirty = false;
+        self.last = !self.chunk.is_empty() && self.ends_file();
+        Ok(())
+    }
+
+    /// Write zeros, as when a file is extended.
+    pub(crate) async fn fill(&mut self, mut len: Filesize) -> Result<(), ErrorCode> {
+        let zeros = vec![0; CHUNK_SIZE as usize];
+        while len > 0 {
+            let n = len.min(CHUNK_SIZE);
+            self.write(&zeros[..n as usize]).await?;
+            len -= n;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<(), ErrorCode> {
+        if self.dirty {
+            let last = self.ends_file();
+            write_chunk(self.fd, &self.header, self.index, last, &self.chunk).await?;
+            self.dirty = false;
+            self.last = last;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chacha20poly1305::aead::KeyInit;
+    use chacha20poly1305::XChaCha20Poly1305;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn cipher() -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new_from_slice(&[7; 32]).unwrap()
+    }
+
+    const HEADER: [u8; HEADER_SIZE as usize] = [1; HEADER_SIZE as usize];
+
+    #[test]
+    fn empty_file_has_no_header() {
+        assert_eq!(stored_size(0), 0);
+        assert_eq!(plaintext_size(0), 0);
+        assert_eq!(plaintext_size(HEADER_SIZE), 0);
+    }
+
+    #[test]
+    fn sizes_of_whole_and_partial_chunks() {
+        assert_eq!(stored_size(1), HEADER_SIZE + 1 + OVERHEAD);
+        assert_eq!(stored_size(CHUNK_SIZE), HEADER_SIZE + STORED_CHUNK_SIZE);
+        assert_eq!(
+            stored_size(CHUNK_SIZE + 1),
+            HEADER_SIZE + STORED_CHUNK_SIZE + 1 + OVERHEAD
+        );
+    }
+
+    #[test]
+    fn stored_offset_saturates() {
+        assert_eq!(stored_offset(0), HEADER_SIZE);
+        assert_eq!(stored_offset(Filesize::MAX), Filesize::MAX);
+    }
+
+    proptest! {
+        #[test]
+        fn sizes_round_trip(size in 0..Filesize::MAX / STORED_CHUNK_SIZE) {
+            prop_assert_eq!(plaintext_size(stored_size(size)), size);
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let cipher = cipher();
+        let chunk = seal(&cipher, vec![2; 24], &HEADER, 3, true, b"plaintext");
+        assert_eq!(chunk.len() as Filesize, 9 + OVERHEAD);
+        assert_eq!(
+            open(&cipher, &HEADER, 3, true, &chunk).unwrap(),
+            b"plaintext"
+        );
+    }
+
+    #[test]
+    fn chunks_are_bound_to_their_place() {
+        let cipher = cipher();
+        let chunk = seal(&cipher, vec![2; 24], &HEADER, 3, true, b"plaintext");
+        assert!(open(&cipher, &HEADER, 4, true, &chunk).is_err());
+        assert!(open(&cipher, &HEADER, 3, false, &chunk).is_err());
+        assert!(open(&cipher, &[9; HEADER_SIZE as usize], 3, true, &chunk).is_err());
+        assert!(open(&cipher, &HEADER, 3, true, &chunk[..OVERHEAD as usize - 1]).is_err());
+    }
+}
@@ -0,0 +1,372 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::rc::Rc;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chunks::{Writer, CHUNK_SIZE};
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+mod chunks;
+mod names;
+
+const KEY_KEY: &str = "key";
+
+/// Bytes of zeros a write past the end of a file or a resize may fill in, as encrypted files can't be sparse.
+const MAX_GAP: Filesize = 16 * 1024 * 1024;
+
+/// The key material, base64 encoded in config.
+fn key() -> Vec<u8> {
+    let key = wasi::config::store::get(KEY_KEY)
+        .expect("Config must resolve")
+        .expect("Config 'key' must be set");
+    BASE64_STANDARD
+        .decode(key.trim())
+        .expect("Config 'key' must be base64 encoded")
+}
+
+struct FilesystemEncrypt {}
+
+impl Preopens for FilesystemEncrypt {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| {
+                let fd = Descriptor::new(EncryptDescriptor::new(fd));
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemEncrypt {
+    type Descriptor = EncryptDescriptor;
+}
+
+struct EncryptDescriptor {
+    fd: Rc<types::Descriptor>,
+}
+
+impl EncryptDescriptor {
+    fn new(fd: types::Descriptor) -> Self {
+        Self { fd: Rc::new(fd) }
+    }
+
+    /// Encrypt data written from an offset, or appended when there is no offset.
+    fn encrypt_write(
+        &self,
+        mut data: wit_bindgen::StreamReader<u8>,
+        offset: Option<Filesize>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let fd = self.fd.clone();
+        wit_bindgen::spawn(async move {
+            let result = async {
+                let size = chunks::plaintext_size(fd.stat().await?.size);
+                let position = offset.unwrap_or(size);
+                // writing past the end of the file fills the gap with zeros
+                let gap = position.saturating_sub(size);
+                if gap > MAX_GAP {
+                    return Err(ErrorCode::FileTooLarge);
+                }
+                let mut writer = Writer::new(&fd, position.min(size), size).await?;
+                writer.fill(gap).await?;
+                loop {
+                    let (status, buf) = data.read(Vec::with_capacity(CHUNK_SIZE as usize)).await;
+                    if buf.is_empty() {
+                        match status {
+                            wit_bindgen::StreamResult::Complete(_) => continue,
+                            _ => break,
+                        }
+                    }
+                    writer.write(&buf).await?;
+                }
+                writer.flush().await
+            }
+            .await;
+            drop(data);
+            let _ = result_tx.write(result).await;
+        });
+        result_rx
+    }
+}
+
+/// Report the plaintext size of regular files.
+fn plaintext_stat(mut stat: DescriptorStat) -> DescriptorStat {
+    if matches!(stat.type_, DescriptorType::RegularFile) {
+        stat.size = chunks::plaintext_size(stat.size);
+    }
+    stat
+}
+
+impl GuestDescriptor for EncryptDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let fd = self.fd.clone();
+        wit_bindgen::spawn(async move {
+            let result = async {
+                // an empty file has no header, nor anything to read
+                let Some(header) = chunks::read_header(&fd).await? else {
+                    return Ok(());
+                };
+                let (mut data, result) =
+                    fd.read_via_stream(chunks::stored_offset(offset / CHUNK_SIZE));
+                let decrypted =
+                    chunks::decrypt_stream(&mut data, &mut data_tx, &header, offset).await;
+                drop(data);
+                decrypted?;
+                result.await
+            }
+            .await;
+            drop(data_tx);
+            let _ = result_tx.write(result).await;
+        });
+        (data_rx, result_rx)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.encrypt_write(data, Some(offset))
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.encrypt_write(data, None)
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        let start = chunks::stored_offset(offset / CHUNK_SIZE);
+        let length = match length {
+            // zero advises to the end of the file
+            0 => 0,
+            length => {
+                chunks::stored_offset(offset.saturating_add(length).div_ceil(CHUNK_SIZE)) - start
+            }
+        };
+        self.fd.advise(start, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        let current = chunks::plaintext_size(self.fd.stat().await?.size);
+        if size >= current {
+            if size - current > MAX_GAP {
+                return Err(ErrorCode::FileTooLarge);
+            }
+            let mut writer = Writer::new(&self.fd, current, current).await?;
+            writer.fill(size - current).await?;
+            return writer.flush().await;
+        }
+        if size == 0 {
+            return self.fd.set_size(0).await;
+        }
+
+        // re-encrypt the chunk the file now ends within as the last, before dropping the rest
+        let header = chunks::read_header(&self.fd).await?.ok_or(ErrorCode::Io)?;
+        let index = (size - 1) / CHUNK_SIZE;
+        let mut chunk = chunks::read_chunk(&self.fd, &header, index).await?;
+        chunk.truncate((size - index * CHUNK_SIZE) as usize);
+        chunks::write_chunk(&self.fd, &header, index, true, &chunk).await?;
+        self.fd.set_size(chunks::stored_size(size)).await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        names::decrypt_entries(self.fd.read_directory())
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd
+            .create_directory_at(names::encrypt_path(path)?)
+            .await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat().await.map(plaintext_stat)
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        self.fd
+            .stat_at(path_flags, names::encrypt_path(path)?)
+            .await
+            .map(plaintext_stat)
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times_at(
+                path_flags,
+                names::encrypt_path(path)?,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .link_at(
+                old_path_flags,
+                names::encrypt_path(old_path)?,
+                &new_descriptor.fd,
+                names::encrypt_path(new_path)?,
+            )
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        // writing re-encrypts existing chunks, so needs to read them
+        let flags = if flags.contains(DescriptorFlags::WRITE) {
+            flags | DescriptorFlags::READ
+        } else {
+            flags
+        };
+        self.fd
+            .open_at(path_flags, names::encrypt_path(path)?, open_flags, flags)
+            .await
+            .map(|fd| Descriptor::new(EncryptDescriptor::new(fd)))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        names::decrypt_path(self.fd.readlink_at(names::encrypt_path(path)?).await?)
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd
+            .remove_directory_at(names::encrypt_path(path)?)
+            .await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .rename_at(
+                names::encrypt_path(old_path)?,
+                &new_descriptor.fd,
+                names::encrypt_path(new_path)?,
+            )
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        self.fd
+            .symlink_at(
+                names::encrypt_path(old_path)?,
+                names::encrypt_path(new_path)?,
+            )
+            .await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.unlink_file_at(names::encrypt_path(path)?).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd
+            .metadata_hash_at(path_flags, names::encrypt_path(path)?)
+            .await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemEncrypt);
@@ -0,0 +1,249 @@
+//! File names are optionally encrypted one path component at a time. Each name is encrypted
+//! deterministically, with a nonce derived from an HMAC of the name, so the same name always
+//! encrypts to the same stored name and can be looked up. The nonce and ciphertext are stored
+//! base64url encoded.
+
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::wasi::config::store;
+use crate::wasi::filesystem::types::{DirectoryEntry, ErrorCode};
+use crate::{key, wit_stream};
+
+const ENCRYPT_NAMES_KEY: &str = "encrypt-names";
+
+const NONCE_SIZE: usize = 24;
+/// The longest stored name most file systems allow.
+const MAX_NAME_SIZE: usize = 255;
+
+thread_local! {
+    static NAMES: Option<Names> = Names::from_config();
+}
+
+struct Names {
+    cipher: XChaCha20Poly1305,
+    mac: Hmac<Sha256>,
+}
+
+impl Names {
+    fn from_config() -> Option<Self> {
+        let enabled = store::get(ENCRYPT_NAMES_KEY)
+            .expect("Config must resolve")
+            .is_some_and(|value| value == "true");
+        if !enabled {
+            return None;
+        }
+        Some(Self::new(&key()))
+    }
+
+    fn new(key: &[u8]) -> Self {
+        // derive a separate key for the synthetic nonces
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key");
+        mac.update(b"encrypt names");
+        let nonce_key = mac.finalize().into_bytes();
+        Self {
+            cipher: <XChaCha20Poly1305 as KeyInit>::new_from_slice(key)
+                .expect("Config 'key' must be 32 bytes"),
+            mac: <Hmac<Sha256> as Mac>::new_from_slice(&nonce_key).expect("HMAC accepts any key"),
+        }
+    }
+
+    fn nonce(&self, name: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac.clone();
+        mac.update(name);
+        mac.finalize().into_bytes()[..NONCE_SIZE].to_vec()
+    }
+
+    fn encrypt(&self, name: &str) -> Result<String, ErrorCode> {
+        let mut stored = self.nonce(name.as_bytes());
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&stored), name.as_bytes())
+            .expect("Encryption must succeed");
+        stored.extend(ciphertext);
+        let stored = BASE64_URL_SAFE_NO_PAD.encode(stored);
+        if stored.len() > MAX_NAME_SIZE {
+            return Err(ErrorCode::NameTooLong);
+        }
+        Ok(stored)
+    }
+
+    fn decrypt(&self, stored: &str) -> Option<String> {
+        let stored = BASE64_URL_SAFE_NO_PAD.decode(stored).ok()?;
+        if stored.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = stored.split_at(NONCE_SIZE);
+        let name = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        // only names encrypted by `encrypt` can be looked up again
+        if self.nonce(&name) != nonce {
+            return None;
+        }
+        String::from_utf8(name).ok()
+    }
+}
+
+fn is_special(component: &str) -> bool {
+    matches!(component, "" | "." | "..")
+}
+
+/// The path stored for a guest path, encrypting each of its names when enabled.
+pub(crate) fn encrypt_path(path: String) -> Result<String, ErrorCode> {
+    NAMES.with(|names| {
+        let Some(names) = names else {
+            return Ok(path);
+        };
+        path.split('/')
+            .map(|component| match component {
+                component if is_special(component) => Ok(component.to_string()),
+                component => names.encrypt(component),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|components| components.join("/"))
+    })
+}
+
+/// The guest path for a stored path, such as a symlink target.
+pub(crate) fn decrypt_path(path: String) -> Result<String, ErrorCode> {
+    NAMES.with(|names| {
+        let Some(names) = names else {
+            return Ok(path);
+        };
+        path.split('/')
+            .map(|component| match component {
+                component if is_special(component) => Some(component.to_string()),
+                component => names.decrypt(component),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|components| components.join("/"))
+            .ok_or(ErrorCode::Io)
+    })
+}
+
+/// Decrypt the names of directory entries, skipping entries that were not stored by this
+/// component.
+pub(crate) fn decrypt_entries(
+    (mut entries, result): (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ),
+) -> (
+    wit_bindgen::StreamReader<DirectoryEntry>,
+    wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) {
+    if NAMES.with(Option::is_none) {
+        return (entries, result);
+    }
+    let (mut entries_tx, entries_rx) = wit_stream::new();
+    wit_bindgen::spawn(async move {
+        loop {
+            let (status, buf) = entries.read(Vec::with_capacity(64)).await;
+            if buf.is_empty() {
+                match status {
+                    wit_bindgen::StreamResult::Complete(_) => continue,
+                    _ => break,
+                }
+            }
+            let buf = NAMES.with(|names| {
+                let names = names.as_ref().expect("Names are encrypted");
+                buf.into_iter()
+                    .filter_map(|mut entry| {
+                        entry.name = names.decrypt(&entry.name)?;
+                        Some(entry)
+                    })
+                    .collect::<Vec<_>>()
+            });
+            if !entries_tx.write_all(buf).await.is_empty() {
+                break;
+            }
+        }
+    });
+    (entries_rx, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn names() -> Names {
+        Names::new(&[7; 32])
+    }
+
+    proptest! {
+        #[test]
+        fn names_round_trip(name in "[^/]{1,64}") {
+            let names = names();
+            let stored = names.encrypt(&name).unwrap();
+            prop_assert!(!stored.contains('/'));
+            prop_assert_eq!(names.decrypt(&stored), Some(name));
+        }
+    }
+
+    #[test]
+    fn names_encrypt_deterministically() {
+        let names = names();
+        assert_eq!(names.encrypt("a").unwrap(), names.encrypt("a").unwrap());
+        assert_ne!(names.encrypt("a").unwrap(), names.encrypt("b").unwrap());
+        assert_ne!(
+            names.encrypt("a").unwrap(),
+            Names::new(&[8; 32]).encrypt("a").unwrap()
+        );
+    }
+
+    #[test]
+    fn long_names_are_refused() {
+        assert!(names().encrypt(&"a".repeat(150)).is_ok());
+        assert!(matches!(
+            names().encrypt(&"a".repeat(200)),
+            Err(ErrorCode::NameTooLong)
+        ));
+    }
+
+    #[test]
+    fn tampered_names_are_rejected() {
+        let names = names();
+        let stored = BASE64_URL_SAFE_NO_PAD
+            .decode(names.encrypt("name").unwrap())
+            .unwrap();
+        for i in 0..stored.len() {
+            let mut tampered = stored.clone();
+            tampered[i] ^= 1;
+            assert_eq!(
+                names.decrypt(&BASE64_URL_SAFE_NO_PAD.encode(tampered)),
+                None
+            );
+        }
+        assert_eq!(
+            names.decrypt(&BASE64_URL_SAFE_NO_PAD.encode(&stored[..NONCE_SIZE - 1])),
+            None
+        );
+        assert_eq!(names.decrypt("not base64!"), None);
+        assert_eq!(
+            Names::new(&[8; 32]).decrypt(&BASE64_URL_SAFE_NO_PAD.encode(stored)),
+            None
+        );
+    }
+
+    #[test]
+    fn names_with_other_nonces_are_rejected() {
+        // a name encrypted under a nonce other than the one derived from it can't be looked up
+        let names = names();
+        let nonce = [3; NONCE_SIZE];
+        let mut stored = nonce.to_vec();
+        stored.extend(
+            names
+                .cipher
+                .encrypt(XNonce::from_slice(&nonce), b"name".as_slice())
+                .unwrap(),
+        );
+        assert_eq!(names.decrypt(&BASE64_URL_SAFE_NO_PAD.encode(stored)), None);
+    }
+}
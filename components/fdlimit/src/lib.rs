@@ -0,0 +1,313 @@
+#![no_main]
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+use wasi::logging::logging::{log, Level};
+
+const MAX_DESCRIPTORS_KEY: &str = "max-descriptors";
+const LOG_OUTSTANDING_KEY: &str = "log-outstanding";
+
+type DescriptorId = u64;
+
+thread_local! {
+    static NEXT_ID: Cell<DescriptorId> = const { Cell::new(0) };
+    static OUTSTANDING: RefCell<BTreeMap<DescriptorId, PathBuf>> =
+        const { RefCell::new(BTreeMap::new()) };
+    static MAX_DESCRIPTORS: Option<usize> =
+        config(MAX_DESCRIPTORS_KEY).and_then(|value| value.parse().ok());
+    static LOG_OUTSTANDING: bool =
+        config(LOG_OUTSTANDING_KEY).is_some_and(|value| value == "true");
+}
+
+fn config(key: &str) -> Option<String> {
+    wasi::config::store::get(key).expect("Config must resolve")
+}
+
+/// Fail once the number of open descriptors reaches the limit, optionally logging the paths of
+/// the descriptors still open.
+fn check_limit(path: &Path) -> Result<(), ErrorCode> {
+    let Some(max) = MAX_DESCRIPTORS.with(|max| *max) else {
+        return Ok(());
+    };
+    OUTSTANDING.with_borrow(|outstanding| {
+        if outstanding.len() < max {
+            return Ok(());
+        }
+        log(
+            Level::Warn,
+            "filesystem",
+            &format!(
+                "LIMIT wasi:filesystem/types#descriptor.open-at PATH={} OUTSTANDING={}",
+                path.display(),
+                outstanding.len()
+            ),
+        );
+        if LOG_OUTSTANDING.with(|log_outstanding| *log_outstanding) {
+            for path in outstanding.values() {
+                log(
+                    Level::Warn,
+                    "filesystem",
+                    &format!("OUTSTANDING PATH={}", path.display()),
+                );
+            }
+        }
+        Err(ErrorCode::InsufficientMemory)
+    })
+}
+
+/// Track a descriptor as open. Opens are tracked before they complete, so concurrent opens
+/// cannot exceed the limit.
+fn register(path: PathBuf) -> DescriptorId {
+    let id = NEXT_ID.replace(NEXT_ID.get() + 1);
+    OUTSTANDING.with_borrow_mut(|outstanding| outstanding.insert(id, path));
+    id
+}
+
+fn unregister(id: DescriptorId) {
+    OUTSTANDING.with_borrow_mut(|outstanding| outstanding.remove(&id));
+}
+
+struct FilesystemFdLimit {}
+
+impl Preopens for FilesystemFdLimit {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| {
+                let id = register(path.clone().into());
+                let fd = Descriptor::new(FdLimitDescriptor::new(fd, id, path.clone().into()));
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemFdLimit {
+    type Descriptor = FdLimitDescriptor;
+}
+
+struct FdLimitDescriptor {
+    fd: types::Descriptor,
+    id: DescriptorId,
+    path: PathBuf,
+}
+
+impl FdLimitDescriptor {
+    fn new(fd: types::Descriptor, id: DescriptorId, path: PathBuf) -> Self {
+        Self { fd, id, path }
+    }
+
+    fn path_at(&self, path: &str) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for FdLimitDescriptor {
+    fn drop(&mut self) {
+        unregister(self.id);
+    }
+}
+
+impl GuestDescriptor for FdLimitDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_via_stream(offset)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.fd.write_via_stream(data, offset)
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.fd.append_via_stream(data)
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        self.fd.advise(offset, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        self.fd.set_size(size).await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_directory()
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.create_directory_at(path).await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat().await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat_at(path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let path_at = self.path_at(&path);
+        check_limit(&path_at)?;
+        let id = register(path_at.clone());
+        match self.fd.open_at(path_flags, path, open_flags, flags).await {
+            Ok(fd) => Ok(Descriptor::new(FdLimitDescriptor::new(fd, id, path_at))),
+            Err(error) => {
+                unregister(id);
+                Err(error)
+            }
+        }
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.remove_directory_at(path).await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        self.fd.symlink_at(old_path, new_path).await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.unlink_file_at(path).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemFdLimit);
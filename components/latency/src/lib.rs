@@ -0,0 +1,431 @@
+#![no_main]
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use common::random::Xorshift;
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::clocks::monotonic_clock;
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+const LATENCY_KEY: &str = "latency";
+const LATENCY_KEY_PREFIX: &str = "latency.";
+const CHUNK_LATENCY_KEY: &str = "chunk-latency";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const NANOS_PER_MILLISECOND: f64 = 1_000_000.0;
+
+thread_local! {
+    static LATENCY: RefCell<Latency> = RefCell::new(Latency::from_config());
+}
+
+/// A delay of a fixed number of milliseconds, e.g. '20', or randomized between a minimum and
+/// maximum, e.g. '5-50'.
+#[derive(Clone, Copy)]
+struct Delay {
+    min: monotonic_clock::Duration,
+    max: monotonic_clock::Duration,
+}
+
+impl Delay {
+    fn parse(value: &str) -> Option<Self> {
+        let millis = |value: &str| {
+            value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|millis| *millis >= 0.0)
+                .map(|millis| (millis * NANOS_PER_MILLISECOND) as monotonic_clock::Duration)
+        };
+        let (min, max) = match value.split_once('-') {
+            Some((min, max)) => (millis(min)?, millis(max)?),
+            None => (millis(value)?, millis(value)?),
+        };
+        Some(Self {
+            min: min.min(max),
+            max: min.max(max),
+        })
+    }
+}
+
+/// Delays configured by the 'latency' key for every operation, 'latency.<operation>' keys for
+/// specific operations, and the 'chunk-latency' key for each chunk of a stream.
+struct Latency {
+    default: Option<Delay>,
+    operations: BTreeMap<String, Delay>,
+    chunk: Option<Delay>,
+    random: Xorshift,
+}
+
+impl Latency {
+    fn from_config() -> Self {
+        let mut latency = Self {
+            default: None,
+            operations: BTreeMap::new(),
+            chunk: None,
+            random: Xorshift::new(monotonic_clock::now()),
+        };
+        for (key, value) in wasi::config::store::get_all().expect("Config must resolve") {
+            let delay = Delay::parse(&value);
+            if key == LATENCY_KEY {
+                latency.default = delay;
+            } else if key == CHUNK_LATENCY_KEY {
+                latency.chunk = delay;
+            } else if let (Some(operation), Some(delay)) =
+                (key.strip_prefix(LATENCY_KEY_PREFIX), delay)
+            {
+                latency.operations.insert(operation.to_string(), delay);
+            }
+        }
+        latency
+    }
+
+    fn operation(&self, operation: &str) -> Option<Delay> {
+        self.operations.get(operation).copied().or(self.default)
+    }
+
+    fn duration(&mut self, delay: Delay) -> monotonic_clock::Duration {
+        if delay.min == delay.max {
+            return delay.min;
+        }
+        let random = self.random.next_u64();
+        // the range spans every duration when it is too wide to count
+        match (delay.max - delay.min).checked_add(1) {
+            Some(range) => delay.min + random % range,
+            None => random,
+        }
+    }
+}
+
+async fn wait(delay: Option<Delay>) {
+    let Some(delay) = delay else {
+        return;
+    };
+    let duration = LATENCY.with_borrow_mut(|latency| latency.duration(delay));
+    if duration > 0 {
+        monotonic_clock::wait_for(duration).await;
+    }
+}
+
+/// Delay an operation by its configured latency.
+async fn delay(operation: &str) {
+    wait(LATENCY.with_borrow(|latency| latency.operation(operation))).await;
+}
+
+/// Whether a stream for the operation needs to be delayed.
+fn delays_stream(operation: &str) -> bool {
+    LATENCY.with_borrow(|latency| latency.operation(operation).is_some() || latency.chunk.is_some())
+}
+
+/// Copy items from one stream to another, delaying the start of the stream by the operation's
+/// latency and each chunk by the chunk latency.
+async fn pipe<T: wit_stream::StreamPayload>(
+    operation: &str,
+    rx: &mut wit_bindgen::StreamReader<T>,
+    tx: &mut wit_bindgen::StreamWriter<T>,
+) {
+    delay(operation).await;
+    loop {
+        let (status, buf) = rx.read(Vec::with_capacity(CHUNK_SIZE)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        wait(LATENCY.with_borrow(|latency| latency.chunk)).await;
+        let remaining = tx.write_all(buf).await;
+        if !remaining.is_empty() {
+            break;
+        }
+    }
+}
+
+/// Delay a stream read from a descriptor.
+fn delay_read<T: wit_stream::StreamPayload>(
+    operation: &'static str,
+    (mut data, result): (
+        wit_bindgen::StreamReader<T>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ),
+) -> (
+    wit_bindgen::StreamReader<T>,
+    wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) {
+    if !delays_stream(operation) {
+        return (data, result);
+    }
+    let (mut data_tx, data_rx) = wit_stream::new();
+    let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+    wit_bindgen::spawn(async move {
+        pipe(operation, &mut data, &mut data_tx).await;
+        drop(data_tx);
+        let _ = result_tx.write(result.await).await;
+    });
+    (data_rx, result_rx)
+}
+
+/// Delay a stream written to a descriptor.
+fn delay_write(
+    operation: &'static str,
+    mut data: wit_bindgen::StreamReader<u8>,
+    write: impl FnOnce(
+        wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    if !delays_stream(operation) {
+        return write(data);
+    }
+    let (mut data_tx, data_rx) = wit_stream::new();
+    wit_bindgen::spawn(async move {
+        pipe(operation, &mut data, &mut data_tx).await;
+    });
+    write(data_rx)
+}
+
+struct FilesystemLatency {}
+
+impl Preopens for FilesystemLatency {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| {
+                let fd = Descriptor::new(LatencyDescriptor::new(fd));
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemLatency {
+    type Descriptor = LatencyDescriptor;
+}
+
+struct LatencyDescriptor {
+    fd: types::Descriptor,
+}
+
+impl LatencyDescriptor {
+    fn new(fd: types::Descriptor) -> Self {
+        Self { fd }
+    }
+}
+
+impl GuestDescriptor for LatencyDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        delay_read("read-via-stream", self.fd.read_via_stream(offset))
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        delay_write("write-via-stream", data, |data| {
+            self.fd.write_via_stream(data, offset)
+        })
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        delay_write("append-via-stream", data, |data| {
+            self.fd.append_via_stream(data)
+        })
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        delay("advise").await;
+        self.fd.advise(offset, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        delay("sync-data").await;
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        delay("get-flags").await;
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        delay("get-type").await;
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        delay("set-size").await;
+        self.fd.set_size(size).await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        delay("set-times").await;
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        delay_read("read-directory", self.fd.read_directory())
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        delay("sync").await;
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        delay("create-directory-at").await;
+        self.fd.create_directory_at(path).await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        delay("stat").await;
+        self.fd.stat().await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        delay("stat-at").await;
+        self.fd.stat_at(path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        delay("set-times-at").await;
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        delay("link-at").await;
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        delay("open-at").await;
+        self.fd
+            .open_at(path_flags, path, open_flags, flags)
+            .await
+            .map(|fd| Descriptor::new(LatencyDescriptor::new(fd)))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        delay("readlink-at").await;
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        delay("remove-directory-at").await;
+        self.fd.remove_directory_at(path).await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        delay("rename-at").await;
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        delay("symlink-at").await;
+        self.fd.symlink_at(old_path, new_path).await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        delay("unlink-file-at").await;
+        self.fd.unlink_file_at(path).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        delay("is-same-object").await;
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        delay("metadata-hash").await;
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        delay("metadata-hash-at").await;
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemLatency);
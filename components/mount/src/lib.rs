@@ -0,0 +1,824 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::config::store;
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+use wasi::logging::logging::{log, Level};
+
+const MOUNT_KEY_PREFIX: &str = "mount.";
+
+const CHUNK_SIZE: usize = 64;
+
+thread_local! {
+    static MOUNTS: Rc<Mounts> = Rc::new(Mounts::from_config());
+}
+
+/// Join a path to a guest path, resolving '.' and '..' without following symbolic links. The
+/// result may not leave the root.
+fn join(base: &Path, path: &str) -> Result<PathBuf, ErrorCode> {
+    if path.starts_with('/') {
+        return Err(ErrorCode::NotPermitted);
+    }
+    let mut joined = base.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => joined.push(name),
+            Component::ParentDir => {
+                if !joined.pop() {
+                    return Err(ErrorCode::NotPermitted);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(joined)
+}
+
+fn failed(error: ErrorCode) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    let (tx, rx) = wit_future::new(|| Err(ErrorCode::Io));
+    wit_bindgen::spawn(async move {
+        let _ = tx.write(Err(error)).await;
+    });
+    rx
+}
+
+/// A directory of an imported preopen, mounted at a guest path.
+struct Mount<F = types::Descriptor> {
+    /// The guest path, relative to the root.
+    path: PathBuf,
+    fd: F,
+}
+
+/// The mounts, configured by wasi:config keys prefixed with 'mount.' followed by the guest path,
+/// with a value of the host path. The host path is within one of the imported preopens.
+struct Mounts<F = types::Descriptor> {
+    mounts: Vec<Mount<F>>,
+}
+
+impl Mounts {
+    fn from_config() -> Self {
+        let dirs = preopens::get_directories();
+        let mut entries = store::get_all().expect("Config must resolve");
+        entries.sort();
+        let mut mounts = Vec::new();
+        for (key, value) in entries {
+            let Some(guest) = key.strip_prefix(MOUNT_KEY_PREFIX) else {
+                continue;
+            };
+            let path = match join(Path::new(""), guest.trim_start_matches('/')) {
+                Ok(path) => path,
+                Err(error) => {
+                    log(
+                        Level::Warn,
+                        "filesystem",
+                        &format!("ignoring mount '{key}': {error:?}"),
+                    );
+                    continue;
+                }
+            };
+            match wit_bindgen::block_on(open_host(&dirs, &value)) {
+                Ok(fd) => mounts.push(Mount { path, fd }),
+                Err(error) => log(
+                    Level::Warn,
+                    "filesystem",
+                    &format!("ignoring mount '{key}': {error:?}"),
+                ),
+            }
+        }
+        Self { mounts }
+    }
+}
+
+impl<F> Mounts<F> {
+    /// The mount holding a guest path, which is the deepest when mounts are nested, with the path
+    /// relative to the mount.
+    fn find(&self, path: &Path) -> Option<(usize, PathBuf)> {
+        self.mounts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, mount)| {
+                let relative = path.strip_prefix(&mount.path).ok()?;
+                Some((i, mount.path.components().count(), relative.to_path_buf()))
+            })
+            .max_by_key(|(_, depth, _)| *depth)
+            .map(|(i, _, relative)| (i, relative))
+    }
+
+    /// Whether a guest path is a mount point.
+    fn is_mount_point(&self, path: &Path) -> bool {
+        self.mounts.iter().any(|mount| mount.path == path)
+    }
+
+    /// Whether a guest path outside any mount is a synthesized directory, being the root or
+    /// holding a mount point.
+    fn is_virtual(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty() || self.mounts.iter().any(|mount| mount.path.starts_with(path))
+    }
+
+    /// The names within a guest path leading to the mount points below it.
+    fn children(&self, path: &Path) -> BTreeSet<String> {
+        self.mounts
+            .iter()
+            .filter_map(
+                |mount| match mount.path.strip_prefix(path).ok()?.components().next() {
+                    Some(Component::Normal(name)) => Some(name.to_string_lossy().into_owned()),
+                    _ => None,
+                },
+            )
+            .collect()
+    }
+
+    /// Resolve a path relative to a guest path, which is within a mount when a descriptor for it
+    /// is given. Paths staying within that mount are resolved by the descriptor, others are
+    /// resolved from the mount holding them.
+    fn route<'a>(
+        &'a self,
+        base: &Path,
+        host: Option<(usize, &'a F)>,
+        path: &str,
+    ) -> Result<Target<'a, F>, ErrorCode> {
+        let guest = join(base, path)?;
+        let Some((mount, relative)) = self.find(&guest) else {
+            return match self.is_virtual(&guest) {
+                true => Ok(Target::Virtual(guest)),
+                false => Err(ErrorCode::NoEntry),
+            };
+        };
+        if let Some((host, fd)) = host {
+            let parent = Path::new(path)
+                .components()
+                .any(|component| component == Component::ParentDir);
+            if host == mount && !parent {
+                return Ok(Target::Host {
+                    mount,
+                    fd,
+                    path: path.to_string(),
+                    guest,
+                });
+            }
+        }
+        let relative = match relative.as_os_str().is_empty() {
+            true => String::from("."),
+            false => relative.to_string_lossy().into_owned(),
+        };
+        Ok(Target::Host {
+            mount,
+            fd: &self.mounts[mount].fd,
+            path: relative,
+            guest,
+        })
+    }
+}
+
+/// Open the directory at a host path, within the imported preopen most specific to it.
+async fn open_host(
+    dirs: &[(types::Descriptor, String)],
+    host: &str,
+) -> Result<types::Descriptor, ErrorCode> {
+    let (fd, relative) = dirs
+        .iter()
+        .filter_map(|(fd, path)| {
+            let relative = Path::new(host).strip_prefix(path).ok()?;
+            Some((fd, relative.to_string_lossy().into_owned()))
+        })
+        .min_by_key(|(_, relative)| Path::new(relative).components().count())
+        .ok_or(ErrorCode::NoEntry)?;
+    let relative = match relative.is_empty() {
+        true => String::from("."),
+        false => relative,
+    };
+    let flags = fd
+        .get_flags()
+        .await?
+        .intersection(types::DescriptorFlags::READ | types::DescriptorFlags::MUTATE_DIRECTORY);
+    fd.open_at(
+        types::PathFlags::SYMLINK_FOLLOW,
+        relative,
+        types::OpenFlags::DIRECTORY,
+        flags,
+    )
+    .await
+}
+
+/// Where a path resolves to.
+enum Target<'a, F = types::Descriptor> {
+    /// A synthesized directory, at a guest path.
+    Virtual(PathBuf),
+    /// A path relative to a descriptor within a mount.
+    Host {
+        mount: usize,
+        fd: &'a F,
+        path: String,
+        guest: PathBuf,
+    },
+}
+
+struct FilesystemMount {}
+
+impl Preopens for FilesystemMount {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let mounts = MOUNTS.with(Rc::clone);
+        let root = PathBuf::new();
+        let host = match mounts.find(&root) {
+            // the root itself is mounted
+            Some((mount, _)) => {
+                let mount_fd = &mounts.mounts[mount].fd;
+                let fd = wit_bindgen::block_on(async {
+                    let flags = mount_fd.get_flags().await?;
+                    mount_fd
+                        .open_at(
+                            types::PathFlags::empty(),
+                            String::from("."),
+                            types::OpenFlags::DIRECTORY,
+                            flags,
+                        )
+                        .await
+                })
+                .expect("Root mount must open");
+                Some(Host { mount, fd })
+            }
+            None => None,
+        };
+        let fd = Descriptor::new(MountDescriptor {
+            mounts,
+            path: root,
+            host,
+        });
+        vec![(fd, String::from("/"))]
+    }
+}
+
+impl Types for FilesystemMount {
+    type Descriptor = MountDescriptor;
+}
+
+struct Host {
+    mount: usize,
+    fd: types::Descriptor,
+}
+
+struct MountDescriptor {
+    mounts: Rc<Mounts>,
+    /// The guest path, relative to the root.
+    path: PathBuf,
+    /// The descriptor within a mount, or none for a synthesized directory.
+    host: Option<Host>,
+}
+
+impl MountDescriptor {
+    /// Resolve a path relative to this descriptor.
+    fn route(&self, path: &str) -> Result<Target<'_>, ErrorCode> {
+        let host = self.host.as_ref().map(|host| (host.mount, &host.fd));
+        self.mounts.route(&self.path, host, path)
+    }
+
+    /// Resolve a path to be removed or replaced, which may not be a mount point or a synthesized
+    /// directory.
+    fn route_mutable(&self, path: &str) -> Result<Target<'_>, ErrorCode> {
+        match self.route(path)? {
+            Target::Virtual(_) => Err(ErrorCode::Busy),
+            Target::Host { guest, .. } if self.mounts.is_mount_point(&guest) => {
+                Err(ErrorCode::Busy)
+            }
+            target => Ok(target),
+        }
+    }
+
+    /// Resolve a path to be created, which outside of mounts already exists or cannot be created.
+    fn route_new(&self, path: &str) -> Result<Target<'_>, ErrorCode> {
+        match self.route(path) {
+            Ok(Target::Virtual(_)) => Err(ErrorCode::Exist),
+            Err(ErrorCode::NoEntry) => Err(ErrorCode::ReadOnly),
+            target => target,
+        }
+    }
+
+    fn virtual_stat() -> DescriptorStat {
+        DescriptorStat {
+            type_: DescriptorType::Directory,
+            link_count: 1,
+            size: 0,
+            data_access_timestamp: None,
+            data_modification_timestamp: None,
+            status_change_timestamp: None,
+        }
+    }
+
+    fn virtual_metadata_hash(path: &Path) -> MetadataHashValue {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        MetadataHashValue {
+            lower: hasher.finish(),
+            upper: 0,
+        }
+    }
+
+    /// List synthesized directories leading to mount points.
+    fn mounted_entries(names: BTreeSet<String>) -> Vec<DirectoryEntry> {
+        names
+            .into_iter()
+            .map(|name| DirectoryEntry {
+                type_: DescriptorType::Directory,
+                name,
+            })
+            .collect()
+    }
+}
+
+impl GuestDescriptor for MountDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        match &self.host {
+            Some(host) => host.fd.read_via_stream(offset),
+            None => (wit_stream::new().1, failed(ErrorCode::IsDirectory)),
+        }
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        match &self.host {
+            Some(host) => host.fd.write_via_stream(data, offset),
+            None => failed(ErrorCode::IsDirectory),
+        }
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        match &self.host {
+            Some(host) => host.fd.append_via_stream(data),
+            None => failed(ErrorCode::IsDirectory),
+        }
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        match &self.host {
+            Some(host) => host.fd.advise(offset, length, advice).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        match &self.host {
+            Some(host) => host.fd.sync_data().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        match &self.host {
+            Some(host) => host.fd.get_flags().await,
+            None => Ok(DescriptorFlags::READ),
+        }
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        match &self.host {
+            Some(host) => host.fd.get_type().await,
+            None => Ok(DescriptorType::Directory),
+        }
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        match &self.host {
+            Some(host) => host.fd.set_size(size).await,
+            None => Err(ErrorCode::IsDirectory),
+        }
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        match &self.host {
+            Some(host) => {
+                host.fd
+                    .set_times(data_access_timestamp, data_modification_timestamp)
+                    .await
+            }
+            None => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let mounted = self.mounts.children(&self.path);
+        let host = match &self.host {
+            Some(host) if mounted.is_empty() => return host.fd.read_directory(),
+            Some(host) => Some(host.fd.read_directory()),
+            None => None,
+        };
+        let (mut tx, rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            let mut result = Ok(());
+            if let Some((mut entries, entries_result)) = host {
+                // mount points hide the entries they are mounted over
+                loop {
+                    let (status, buf) = entries.read(Vec::with_capacity(CHUNK_SIZE)).await;
+                    if buf.is_empty() {
+                        match status {
+                            wit_bindgen::StreamResult::Complete(_) => continue,
+                            _ => break,
+                        }
+                    }
+                    let buf = buf
+                        .into_iter()
+                        .filter(|entry| !mounted.contains(&entry.name))
+                        .collect::<Vec<_>>();
+                    if !tx.write_all(buf).await.is_empty() {
+                        break;
+                    }
+                }
+                drop(entries);
+                result = entries_result.await;
+            }
+            if result.is_ok() {
+                tx.write_all(MountDescriptor::mounted_entries(mounted))
+                    .await;
+            }
+            drop(tx);
+            let _ = result_tx.write(result).await;
+        });
+        (rx, result_rx)
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        match &self.host {
+            Some(host) => host.fd.sync().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        match self.route_new(&path)? {
+            Target::Host { fd, path, .. } => fd.create_directory_at(path).await,
+            Target::Virtual(_) => unreachable!("synthesized directories exist"),
+        }
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        match &self.host {
+            Some(host) => host.fd.stat().await,
+            None => Ok(Self::virtual_stat()),
+        }
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        match self.route(&path)? {
+            Target::Host { fd, path, .. } => fd.stat_at(path_flags, path).await,
+            Target::Virtual(_) => Ok(Self::virtual_stat()),
+        }
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        match self.route(&path)? {
+            Target::Host { fd, path, .. } => {
+                fd.set_times_at(
+                    path_flags,
+                    path,
+                    data_access_timestamp,
+                    data_modification_timestamp,
+                )
+                .await
+            }
+            Target::Virtual(_) => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        let old = match self.route(&old_path)? {
+            Target::Virtual(_) => return Err(ErrorCode::NotPermitted),
+            old => old,
+        };
+        match (old, new_descriptor.route_new(&new_path)?) {
+            (
+                Target::Host {
+                    mount, fd, path, ..
+                },
+                Target::Host {
+                    mount: new_mount,
+                    fd: new_fd,
+                    path: new_path,
+                    ..
+                },
+            ) if mount == new_mount => fd.link_at(old_path_flags, path, new_fd, new_path).await,
+            _ => Err(ErrorCode::CrossDevice),
+        }
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let target = match open_flags.contains(OpenFlags::CREATE) {
+            true => match self.route(&path) {
+                Err(ErrorCode::NoEntry) => Err(ErrorCode::ReadOnly),
+                target => target,
+            },
+            false => self.route(&path),
+        };
+        match target? {
+            Target::Host {
+                mount,
+                fd,
+                path,
+                guest,
+            } => {
+                let fd = fd.open_at(path_flags, path, open_flags, flags).await?;
+                Ok(Descriptor::new(MountDescriptor {
+                    mounts: self.mounts.clone(),
+                    path: guest,
+                    host: Some(Host { mount, fd }),
+                }))
+            }
+            Target::Virtual(_) if open_flags.contains(OpenFlags::EXCLUSIVE) => {
+                Err(ErrorCode::Exist)
+            }
+            Target::Virtual(_)
+                if flags.contains(DescriptorFlags::WRITE)
+                    || open_flags.contains(OpenFlags::TRUNCATE) =>
+            {
+                Err(ErrorCode::IsDirectory)
+            }
+            Target::Virtual(guest) => Ok(Descriptor::new(MountDescriptor {
+                mounts: self.mounts.clone(),
+                path: guest,
+                host: None,
+            })),
+        }
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        match self.route(&path)? {
+            Target::Host { fd, path, .. } => fd.readlink_at(path).await,
+            Target::Virtual(_) => Err(ErrorCode::Invalid),
+        }
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        match self.route_mutable(&path)? {
+            Target::Host { fd, path, .. } => fd.remove_directory_at(path).await,
+            Target::Virtual(_) => unreachable!("synthesized directories are busy"),
+        }
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        let old = self.route_mutable(&old_path)?;
+        let new = match new_descriptor.route(&new_path) {
+            Err(ErrorCode::NoEntry) => return Err(ErrorCode::CrossDevice),
+            new => new?,
+        };
+        match (old, new) {
+            (_, Target::Host { guest, .. }) if self.mounts.is_mount_point(&guest) => {
+                Err(ErrorCode::Busy)
+            }
+            (
+                Target::Host {
+                    mount, fd, path, ..
+                },
+                Target::Host {
+                    mount: new_mount,
+                    fd: new_fd,
+                    path: new_path,
+                    ..
+                },
+            ) if mount == new_mount => fd.rename_at(path, new_fd, new_path).await,
+            (_, Target::Virtual(_)) => Err(ErrorCode::Busy),
+            _ => Err(ErrorCode::CrossDevice),
+        }
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        match self.route_new(&new_path)? {
+            Target::Host { fd, path, .. } => fd.symlink_at(old_path, path).await,
+            Target::Virtual(_) => unreachable!("synthesized directories exist"),
+        }
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        match self.route_mutable(&path) {
+            Ok(Target::Host { fd, path, .. }) => fd.unlink_file_at(path).await,
+            Ok(Target::Virtual(_)) => unreachable!("synthesized directories are busy"),
+            // mount points and synthesized directories are directories
+            Err(ErrorCode::Busy) => Err(ErrorCode::IsDirectory),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        match (&self.host, &other.host) {
+            (Some(host), Some(other_host)) => host.fd.is_same_object(&other_host.fd).await,
+            (None, None) => self.path == other.path,
+            _ => false,
+        }
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        match &self.host {
+            Some(host) => host.fd.metadata_hash().await,
+            None => Ok(Self::virtual_metadata_hash(&self.path)),
+        }
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        match self.route(&path)? {
+            Target::Host { fd, path, .. } => fd.metadata_hash_at(path_flags, path).await,
+            Target::Virtual(guest) => Ok(Self::virtual_metadata_hash(&guest)),
+        }
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemMount);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mounts with the name of each mount in place of its descriptor.
+    fn mounts(paths: &[&'static str]) -> Mounts<&'static str> {
+        let mounts = paths
+            .iter()
+            .map(|path| Mount {
+                path: PathBuf::from(path),
+                fd: *path,
+            })
+            .collect();
+        Mounts { mounts }
+    }
+
+    /// The descriptor and path a target resolves to.
+    fn host<'a>(target: Result<Target<'a, &'static str>, ErrorCode>) -> (&'static str, String) {
+        match target {
+            Ok(Target::Host { fd, path, .. }) => (*fd, path),
+            Ok(Target::Virtual(guest)) => panic!("{guest:?} is virtual"),
+            Err(error) => panic!("{error:?}"),
+        }
+    }
+
+    #[test]
+    fn join_resolves_dots() {
+        let base = Path::new("data/logs");
+        assert_eq!(join(base, "a/./b").unwrap(), Path::new("data/logs/a/b"));
+        assert_eq!(join(base, "../x").unwrap(), Path::new("data/x"));
+        assert_eq!(join(base, "../..").unwrap(), Path::new(""));
+        assert_eq!(join(base, ".").unwrap(), base);
+    }
+
+    #[test]
+    fn join_stays_within_the_root() {
+        let base = Path::new("data");
+        assert!(matches!(join(base, "../.."), Err(ErrorCode::NotPermitted)));
+        assert!(matches!(join(base, "/etc"), Err(ErrorCode::NotPermitted)));
+        assert!(matches!(
+            join(Path::new(""), ".."),
+            Err(ErrorCode::NotPermitted)
+        ));
+    }
+
+    #[test]
+    fn find_picks_the_deepest_mount() {
+        let mounts = mounts(&["data/logs", "data", "tmp"]);
+        let (mount, relative) = mounts.find(Path::new("data/logs/app.log")).unwrap();
+        assert_eq!((mount, relative.as_path()), (0, Path::new("app.log")));
+        let (mount, relative) = mounts.find(Path::new("data/db")).unwrap();
+        assert_eq!((mount, relative.as_path()), (1, Path::new("db")));
+        let (mount, relative) = mounts.find(Path::new("tmp")).unwrap();
+        assert_eq!((mount, relative.as_path()), (2, Path::new("")));
+        // a name merely starting with a mount's name is not within it
+        assert!(mounts.find(Path::new("tmpfile")).is_none());
+        assert!(mounts.find(Path::new("")).is_none());
+    }
+
+    #[test]
+    fn route_outside_mounts() {
+        let mounts = mounts(&["data/logs"]);
+        let root = Path::new("");
+        assert!(matches!(
+            mounts.route(root, None, "data"),
+            Ok(Target::Virtual(guest)) if guest == Path::new("data")
+        ));
+        assert!(matches!(
+            mounts.route(root, None, "other"),
+            Err(ErrorCode::NoEntry)
+        ));
+        assert_eq!(
+            host(mounts.route(root, None, "data/logs")),
+            ("data/logs", String::from("."))
+        );
+        assert_eq!(
+            host(mounts.route(root, None, "data/logs/a/b")),
+            ("data/logs", String::from("a/b"))
+        );
+    }
+
+    #[test]
+    fn route_within_the_descriptor_mount() {
+        let mounts = mounts(&["data", "data/logs"]);
+        let base = Path::new("data/db");
+        let descriptor = Some((0, &"descriptor"));
+        assert_eq!(
+            host(mounts.route(base, descriptor, "users")),
+            ("descriptor", String::from("users"))
+        );
+        // '..' is resolved from the mount, as the descriptor may be reached by a symbolic link
+        assert_eq!(
+            host(mounts.route(base, descriptor, "../cache")),
+            ("data", String::from("cache"))
+        );
+    }
+
+    #[test]
+    fn route_across_mount_boundaries() {
+        let mounts = mounts(&["data", "data/logs", "tmp"]);
+        let base = Path::new("data/db");
+        let descriptor = Some((0, &"descriptor"));
+        // a nested mount is resolved by its own descriptor
+        assert_eq!(
+            host(mounts.route(base, descriptor, "../logs/app.log")),
+            ("data/logs", String::from("app.log"))
+        );
+        // as is a mount beside this one
+        assert_eq!(
+            host(mounts.route(base, descriptor, "../../tmp/x")),
+            ("tmp", String::from("x"))
+        );
+        assert!(matches!(
+            mounts.route(base, descriptor, "../.."),
+            Ok(Target::Virtual(guest)) if guest == Path::new("")
+        ));
+        assert!(matches!(
+            mounts.route(base, descriptor, "../../../etc"),
+            Err(ErrorCode::NotPermitted)
+        ));
+    }
+}
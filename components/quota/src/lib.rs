@@ -0,0 +1,569 @@
+#![cfg_attr(not(test), no_main)]
+
+mod usage;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use usage::{FileId, Limits, Usage};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+const QUOTA_BYTES_KEY: &str = "quota-bytes";
+const QUOTA_FILES_KEY: &str = "quota-files";
+const QUOTA_ERROR_KEY: &str = "quota-error";
+const MAX_FILE_SIZE_KEY: &str = "max-file-size";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn config(key: &str) -> Option<String> {
+    wasi::config::store::get(key).expect("Config must resolve")
+}
+
+/// The usage and limits of a preopened directory, shared by every descriptor opened from it.
+struct Quota {
+    usage: RefCell<Usage>,
+}
+
+impl Quota {
+    fn new(bytes: Filesize, files: u64) -> Self {
+        let error = match config(QUOTA_ERROR_KEY).as_deref() {
+            Some("insufficient-space") => ErrorCode::InsufficientSpace,
+            _ => ErrorCode::Quota,
+        };
+        let limits = Limits {
+            max_bytes: config(QUOTA_BYTES_KEY).and_then(|value| value.parse().ok()),
+            max_files: config(QUOTA_FILES_KEY).and_then(|value| value.parse().ok()),
+            max_file_size: config(MAX_FILE_SIZE_KEY).and_then(|value| value.parse().ok()),
+        };
+        Self {
+            usage: RefCell::new(Usage::new(bytes, files, limits, error)),
+        }
+    }
+
+    fn grow(&self, bytes: Filesize) -> Result<(), ErrorCode> {
+        self.usage.borrow_mut().grow(bytes)
+    }
+
+    fn shrink(&self, bytes: Filesize) {
+        self.usage.borrow_mut().shrink(bytes);
+    }
+
+    fn add_file(&self) -> Result<(), ErrorCode> {
+        self.usage.borrow_mut().add_file()
+    }
+
+    fn remove_file(&self) {
+        self.usage.borrow_mut().remove_file();
+    }
+
+    /// Release the usage of a removed link, whose data is only freed once the last link is
+    /// removed.
+    fn remove(&self, stat: &DescriptorStat) {
+        self.remove_file();
+        if matches!(stat.type_, DescriptorType::RegularFile) && stat.link_count <= 1 {
+            self.shrink(stat.size);
+        }
+    }
+
+    /// Start a write to a file, measuring the file unless writes to it are already in flight.
+    async fn start_write(&self, fd: &types::Descriptor) -> Result<FileId, ErrorCode> {
+        let hash = fd.metadata_hash().await?;
+        let id = (hash.lower, hash.upper);
+        let size = if self.usage.borrow().is_writing(id) {
+            0
+        } else {
+            fd.stat().await?.size
+        };
+        // another write may have started while the file was being measured
+        self.usage.borrow_mut().start_write(id, size);
+        Ok(id)
+    }
+
+    /// Finish a write to a file, correcting usage by its size once no writes to it remain.
+    async fn finish_write(&self, id: FileId, fd: &types::Descriptor) {
+        let Some(reserved) = self.usage.borrow_mut().finish_write(id) else {
+            return;
+        };
+        if let Ok(stat) = fd.stat().await {
+            self.usage.borrow_mut().settle(reserved, stat.size);
+        }
+    }
+}
+
+/// Walk a directory tree counting the bytes and files it uses.
+async fn measure(root: types::Descriptor) -> (Filesize, u64) {
+    let (mut bytes, mut files) = (0, 0);
+    let mut dirs = vec![root];
+    while let Some(dir) = dirs.pop() {
+        let (entries, result) = dir.read_directory();
+        let entries = entries.collect().await;
+        let _ = result.await;
+        for entry in entries {
+            files += 1;
+            match entry.type_ {
+                DescriptorType::Directory => {
+                    if let Ok(fd) = dir
+                        .open_at(
+                            types::PathFlags::empty(),
+                            entry.name,
+                            types::OpenFlags::DIRECTORY,
+                            types::DescriptorFlags::READ,
+                        )
+                        .await
+                    {
+                        dirs.push(fd);
+                    }
+                }
+                DescriptorType::RegularFile => {
+                    if let Ok(stat) = dir.stat_at(types::PathFlags::empty(), entry.name).await {
+                        bytes += stat.size;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    (bytes, files)
+}
+
+struct FilesystemQuota {}
+
+impl Preopens for FilesystemQuota {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| {
+                let (bytes, files) = wit_bindgen::block_on(async {
+                    let root = fd
+                        .open_at(
+                            types::PathFlags::empty(),
+                            String::from("."),
+                            types::OpenFlags::DIRECTORY,
+                            types::DescriptorFlags::READ,
+                        )
+                        .await;
+                    match root {
+                        Ok(root) => measure(root).await,
+                        Err(_) => (0, 0),
+                    }
+                });
+                let quota = Rc::new(Quota::new(bytes, files));
+                let fd = Descriptor::new(QuotaDescriptor::new(fd, quota));
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemQuota {
+    type Descriptor = QuotaDescriptor;
+}
+
+struct QuotaDescriptor {
+    fd: Rc<types::Descriptor>,
+    quota: Rc<Quota>,
+}
+
+impl QuotaDescriptor {
+    fn new(fd: types::Descriptor, quota: Rc<Quota>) -> Self {
+        Self {
+            fd: Rc::new(fd),
+            quota,
+        }
+    }
+
+    /// Forward data to a write, reserving usage before the file is extended and cutting the
+    /// write short once the quota is exhausted or the file reaches its maximum size.
+    fn write_within_quota(
+        &self,
+        mut data: wit_bindgen::StreamReader<u8>,
+        offset: Option<Filesize>,
+        write: impl FnOnce(
+            wit_bindgen::StreamReader<u8>,
+        ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let result = write(data_rx);
+        let fd = self.fd.clone();
+        let quota = self.quota.clone();
+        wit_bindgen::spawn(async move {
+            let id = match quota.start_write(&fd).await {
+                Ok(id) => id,
+                Err(error) => {
+                    drop(data_tx);
+                    drop(data);
+                    let _ = result.await;
+                    let _ = result_tx.write(Err(error)).await;
+                    return;
+                }
+            };
+            let mut position = offset;
+            let mut exceeded = None;
+            loop {
+                let (status, mut buf) = data.read(Vec::with_capacity(CHUNK_SIZE)).await;
+                if buf.is_empty() {
+                    match status {
+                        wit_bindgen::StreamResult::Complete(_) => continue,
+                        _ => break,
+                    }
+                }
+
+                let allowed = quota
+                    .usage
+                    .borrow_mut()
+                    .write(id, position, buf.len() as Filesize);
+                buf.truncate(allowed.len as usize);
+                exceeded = allowed.exceeded;
+                if buf.is_empty() {
+                    break;
+                }
+                let start = allowed.start;
+
+                let len = buf.len() as Filesize;
+                let remaining = data_tx.write_all(buf).await;
+                position = position.map(|_| start + len - remaining.len() as Filesize);
+                if exceeded.is_some() || !remaining.is_empty() {
+                    break;
+                }
+            }
+            drop(data_tx);
+            drop(data);
+
+            let result = result.await;
+            // anything reserved but not written is released once the write completes
+            quota.finish_write(id, &fd).await;
+            let result = match (result, exceeded) {
+                (Ok(()), Some(error)) => Err(error),
+                (result, _) => result,
+            };
+            let _ = result_tx.write(result).await;
+        });
+        result_rx
+    }
+}
+
+impl GuestDescriptor for QuotaDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_via_stream(offset)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.write_within_quota(data, Some(offset), |data| {
+            self.fd.write_via_stream(data, offset)
+        })
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.write_within_quota(data, None, |data| self.fd.append_via_stream(data))
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        self.fd.advise(offset, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        if self
+            .quota
+            .usage
+            .borrow()
+            .max_file_size()
+            .is_some_and(|max_file_size| size > max_file_size)
+        {
+            return Err(ErrorCode::FileTooLarge);
+        }
+        let current = self.fd.stat().await?.size;
+        if size > current {
+            self.quota.grow(size - current)?;
+        }
+        let result = self.fd.set_size(size).await;
+        match (&result, size > current) {
+            (Ok(()), false) => self.quota.shrink(current - size),
+            (Err(_), true) => self.quota.shrink(size - current),
+            _ => {}
+        }
+        result
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_directory()
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.quota.add_file()?;
+        let result = self.fd.create_directory_at(path).await;
+        if result.is_err() {
+            self.quota.remove_file();
+        }
+        result
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat().await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat_at(path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        new_descriptor.quota.add_file()?;
+        let result = self
+            .fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await;
+        if result.is_err() {
+            new_descriptor.quota.remove_file();
+        }
+        result
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let existing =
+            if open_flags.contains(OpenFlags::CREATE) || open_flags.contains(OpenFlags::TRUNCATE) {
+                match self.fd.stat_at(path_flags, path.clone()).await {
+                    Ok(stat) => Some(stat),
+                    Err(ErrorCode::NoEntry) => None,
+                    Err(error) => return Err(error),
+                }
+            } else {
+                None
+            };
+
+        let created = open_flags.contains(OpenFlags::CREATE) && existing.is_none();
+        if created {
+            self.quota.add_file()?;
+        }
+        let result = self.fd.open_at(path_flags, path, open_flags, flags).await;
+        match (&result, existing) {
+            (Err(_), _) if created => self.quota.remove_file(),
+            (Ok(_), Some(stat)) if open_flags.contains(OpenFlags::TRUNCATE) => {
+                self.quota.shrink(stat.size)
+            }
+            _ => {}
+        }
+        result.map(|fd| Descriptor::new(QuotaDescriptor::new(fd, self.quota.clone())))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        let result = self.fd.remove_directory_at(path).await;
+        if result.is_ok() {
+            self.quota.remove_file();
+        }
+        result
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        let replaced = new_descriptor
+            .fd
+            .stat_at(PathFlags::empty(), new_path.clone())
+            .await
+            .ok();
+
+        if Rc::ptr_eq(&self.quota, &new_descriptor.quota) {
+            // renaming onto another link to the same file leaves both in place
+            let same = match replaced {
+                Some(_) => {
+                    let old = self
+                        .fd
+                        .metadata_hash_at(PathFlags::empty(), old_path.clone())
+                        .await;
+                    let new = new_descriptor
+                        .fd
+                        .metadata_hash_at(PathFlags::empty(), new_path.clone())
+                        .await;
+                    matches!((old, new), (Ok(old), Ok(new)) if (old.lower, old.upper) == (new.lower, new.upper))
+                }
+                None => false,
+            };
+            let result = self
+                .fd
+                .rename_at(old_path, &new_descriptor.fd, new_path)
+                .await;
+            if let (Ok(()), Some(replaced), false) = (&result, replaced, same) {
+                self.quota.remove(&replaced);
+            }
+            return result;
+        }
+
+        // moving between preopens moves the usage too, which is only measured for files
+        let moved = self
+            .fd
+            .stat_at(PathFlags::empty(), old_path.clone())
+            .await?;
+        let size = match moved.type_ {
+            DescriptorType::Directory => return Err(ErrorCode::CrossDevice),
+            DescriptorType::RegularFile => moved.size,
+            _ => 0,
+        };
+        new_descriptor.quota.add_file()?;
+        if let Err(error) = new_descriptor.quota.grow(size) {
+            new_descriptor.quota.remove_file();
+            return Err(error);
+        }
+        let result = self
+            .fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await;
+        match (&result, replaced) {
+            (Err(_), _) => {
+                new_descriptor.quota.remove_file();
+                new_descriptor.quota.shrink(size);
+            }
+            (Ok(()), replaced) => {
+                self.quota.remove(&moved);
+                if let Some(replaced) = replaced {
+                    new_descriptor.quota.remove(&replaced);
+                }
+            }
+        }
+        result
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        self.quota.add_file()?;
+        let result = self.fd.symlink_at(old_path, new_path).await;
+        if result.is_err() {
+            self.quota.remove_file();
+        }
+        result
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        let stat = self.fd.stat_at(PathFlags::empty(), path.clone()).await.ok();
+        let result = self.fd.unlink_file_at(path).await;
+        if result.is_ok() {
+            match stat {
+                Some(stat) => self.quota.remove(&stat),
+                None => self.quota.remove_file(),
+            }
+        }
+        result
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemQuota);
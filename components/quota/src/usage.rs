@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use crate::exports::wasi::filesystem::types::{ErrorCode, Filesize};
+
+/// A file with writes in flight, identified by its metadata hash.
+pub(crate) type FileId = (u64, u64);
+
+/// The size a file is reserved up to by the writes in flight to it, so concurrent writes grow
+/// usage from the same size rather than each from the size the file had when they started.
+struct Reservation {
+    size: Filesize,
+    writes: usize,
+}
+
+/// The limits of a preopened directory, any of which may be absent.
+#[derive(Default)]
+pub(crate) struct Limits {
+    pub(crate) max_bytes: Option<Filesize>,
+    pub(crate) max_files: Option<u64>,
+    pub(crate) max_file_size: Option<Filesize>,
+}
+
+/// The part of a write allowed by the limits.
+pub(crate) struct Allowed {
+    /// Where the write starts in the file.
+    pub(crate) start: Filesize,
+    /// How many bytes may be written.
+    pub(crate) len: Filesize,
+    /// The error the write fails with once the allowed bytes are written.
+    pub(crate) exceeded: Option<ErrorCode>,
+}
+
+/// The bytes and files used within a preopened directory, checked against its limits.
+pub(crate) struct Usage {
+    bytes: Filesize,
+    files: u64,
+    limits: Limits,
+    /// The error for exceeding the byte or file limit.
+    error: ErrorCode,
+    reservations: HashMap<FileId, Reservation>,
+}
+
+impl Usage {
+    pub(crate) fn new(bytes: Filesize, files: u64, limits: Limits, error: ErrorCode) -> Self {
+        Self {
+            bytes,
+            files,
+            limits,
+            error,
+            reservations: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn max_file_size(&self) -> Option<Filesize> {
+        self.limits.max_file_size
+    }
+
+    /// Bytes that may still be used before the limit is reached.
+    pub(crate) fn available_bytes(&self) -> Filesize {
+        match self.limits.max_bytes {
+            Some(max_bytes) => max_bytes.saturating_sub(self.bytes),
+            None => Filesize::MAX,
+        }
+    }
+
+    /// Reserve bytes, failing if doing so would exceed the limit.
+    pub(crate) fn grow(&mut self, bytes: Filesize) -> Result<(), ErrorCode> {
+        if bytes > self.available_bytes() {
+            return Err(self.error.clone());
+        }
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    pub(crate) fn shrink(&mut self, bytes: Filesize) {
+        self.bytes = self.bytes.saturating_sub(bytes);
+    }
+
+    /// Reserve a file, failing if doing so would exceed the limit.
+    pub(crate) fn add_file(&mut self) -> Result<(), ErrorCode> {
+        if let Some(max_files) = self.limits.max_files {
+            if self.files >= max_files {
+                return Err(self.error.clone());
+            }
+        }
+        self.files += 1;
+        Ok(())
+    }
+
+    pub(crate) fn remove_file(&mut self) {
+        self.files = self.files.saturating_sub(1);
+    }
+
+    /// Whether writes to a file are in flight, so its size is already reserved.
+    pub(crate) fn is_writing(&self, id: FileId) -> bool {
+        self.reservations.contains_key(&id)
+    }
+
+    /// Start a write to a file of a size, unless writes to it are already in flight.
+    pub(crate) fn start_write(&mut self, id: FileId, size: Filesize) {
+        self.reservations
+            .entry(id)
+            .or_insert(Reservation { size, writes: 0 })
+            .writes += 1;
+    }
+
+    /// Reserve usage for the next chunk of a write, starting at an offset or appended after
+    /// everything reserved for the file, and allow as much of it as fits within the limits.
+    pub(crate) fn write(&mut self, id: FileId, offset: Option<Filesize>, len: Filesize) -> Allowed {
+        let reserved = self
+            .reservations
+            .get(&id)
+            .map_or(0, |reservation| reservation.size);
+        let start = offset.unwrap_or(reserved);
+        let Some(mut end) = start.checked_add(len) else {
+            return Allowed {
+                start,
+                len: 0,
+                exceeded: Some(ErrorCode::Overflow),
+            };
+        };
+        let mut exceeded = None;
+        if let Some(max_file_size) = self.limits.max_file_size {
+            if end > max_file_size {
+                // write up to the file size limit, then stop
+                end = end.min(max_file_size.max(start));
+                exceeded = Some(ErrorCode::FileTooLarge);
+            }
+        }
+        if let Err(error) = self.grow(end.saturating_sub(reserved)) {
+            // write as much as the quota allows, then stop
+            end = end.min(reserved.saturating_add(self.available_bytes()).max(start));
+            let _ = self.grow(end.saturating_sub(reserved));
+            exceeded = Some(error);
+        }
+        if let Some(reservation) = self.reservations.get_mut(&id) {
+            reservation.size = reservation.size.max(end);
+        }
+        Allowed {
+            start,
+            len: end - start,
+            exceeded,
+        }
+    }
+
+    /// Finish a write to a file, returning the size reserved for it once no writes to it remain.
+    pub(crate) fn finish_write(&mut self, id: FileId) -> Option<Filesize> {
+        let reservation = self.reservations.get_mut(&id)?;
+        reservation.writes -= 1;
+        if reservation.writes > 0 {
+            return None;
+        }
+        self.reservations
+            .remove(&id)
+            .map(|reservation| reservation.size)
+    }
+
+    /// Correct usage by how the size of a file differs from what was reserved for it, releasing
+    /// bytes reserved for data that was never written, such as when a write fails.
+    pub(crate) fn settle(&mut self, reserved: Filesize, size: Filesize) {
+        if size < reserved {
+            self.shrink(reserved - size);
+        } else {
+            self.bytes = self.bytes.saturating_add(size - reserved);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: FileId = (1, 0);
+
+    fn usage(max_bytes: Option<Filesize>, max_file_size: Option<Filesize>) -> Usage {
+        let limits = Limits {
+            max_bytes,
+            max_files: Some(2),
+            max_file_size,
+        };
+        Usage::new(0, 0, limits, ErrorCode::Quota)
+    }
+
+    #[test]
+    fn grow_within_limit() {
+        let mut usage = usage(Some(10), None);
+        assert!(usage.grow(6).is_ok());
+        assert!(matches!(usage.grow(5), Err(ErrorCode::Quota)));
+        assert_eq!(usage.available_bytes(), 4);
+        usage.shrink(20);
+        assert_eq!(usage.available_bytes(), 10);
+    }
+
+    #[test]
+    fn files_within_limit() {
+        let mut usage = usage(None, None);
+        assert!(usage.add_file().is_ok());
+        assert!(usage.add_file().is_ok());
+        assert!(matches!(usage.add_file(), Err(ErrorCode::Quota)));
+        usage.remove_file();
+        assert!(usage.add_file().is_ok());
+    }
+
+    #[test]
+    fn overwrites_use_nothing() {
+        let mut usage = usage(Some(10), None);
+        usage.start_write(FILE, 8);
+        usage.grow(8).unwrap();
+        let allowed = usage.write(FILE, Some(0), 8);
+        assert_eq!((allowed.start, allowed.len), (0, 8));
+        assert!(allowed.exceeded.is_none());
+        assert_eq!(usage.available_bytes(), 2);
+    }
+
+    #[test]
+    fn writes_stop_at_quota() {
+        let mut usage = usage(Some(10), None);
+        usage.start_write(FILE, 0);
+        let allowed = usage.write(FILE, Some(0), 6);
+        assert_eq!((allowed.start, allowed.len), (0, 6));
+        assert!(allowed.exceeded.is_none());
+        let allowed = usage.write(FILE, Some(6), 6);
+        assert_eq!((allowed.start, allowed.len), (6, 4));
+        assert!(matches!(allowed.exceeded, Some(ErrorCode::Quota)));
+        assert_eq!(usage.available_bytes(), 0);
+        // a gap beyond the quota writes nothing
+        let allowed = usage.write(FILE, Some(20), 1);
+        assert_eq!((allowed.start, allowed.len), (20, 0));
+        assert!(matches!(allowed.exceeded, Some(ErrorCode::Quota)));
+    }
+
+    #[test]
+    fn writes_stop_at_max_file_size() {
+        let mut usage = usage(None, Some(8));
+        usage.start_write(FILE, 0);
+        let allowed = usage.write(FILE, Some(4), 6);
+        assert_eq!((allowed.start, allowed.len), (4, 4));
+        assert!(matches!(allowed.exceeded, Some(ErrorCode::FileTooLarge)));
+        let allowed = usage.write(FILE, Some(9), 1);
+        assert_eq!((allowed.start, allowed.len), (9, 0));
+        assert!(matches!(allowed.exceeded, Some(ErrorCode::FileTooLarge)));
+    }
+
+    #[test]
+    fn writes_report_overflow() {
+        let mut usage = usage(None, None);
+        usage.start_write(FILE, 0);
+        let allowed = usage.write(FILE, Some(Filesize::MAX), 1);
+        assert_eq!(allowed.len, 0);
+        assert!(matches!(allowed.exceeded, Some(ErrorCode::Overflow)));
+        assert_eq!(usage.available_bytes(), Filesize::MAX);
+    }
+
+    #[test]
+    fn concurrent_appends_share_a_reservation() {
+        let mut usage = usage(Some(100), None);
+        usage.start_write(FILE, 10);
+        usage.grow(10).unwrap();
+        usage.start_write(FILE, 0);
+        assert_eq!(usage.write(FILE, None, 5).start, 10);
+        assert_eq!(usage.write(FILE, None, 5).start, 15);
+        assert_eq!(usage.available_bytes(), 80);
+        assert_eq!(usage.finish_write(FILE), None);
+        assert_eq!(usage.finish_write(FILE), Some(20));
+        assert!(!usage.is_writing(FILE));
+        // only some of what was reserved was written
+        usage.settle(20, 15);
+        assert_eq!(usage.available_bytes(), 85);
+        usage.settle(15, 25);
+        assert_eq!(usage.available_bytes(), 75);
+    }
+}
@@ -0,0 +1,432 @@
+#![no_main]
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::clocks::monotonic_clock;
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+const RATE_LIMIT_PREFIX: &str = "rate-limit.";
+const RATE_LIMIT_ACTION_KEY: &str = "rate-limit-action";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const NANOS_PER_SECOND: f64 = 1_000_000_000.0;
+
+thread_local! {
+    static LIMITER: RefCell<Limiter> = RefCell::new(Limiter::from_config());
+}
+
+/// A token bucket refilled continuously at `rate` calls per second, holding at most one second
+/// worth of calls.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: monotonic_clock::Mark,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        let burst = rate.max(1.0);
+        Self {
+            rate,
+            tokens: burst,
+            updated: monotonic_clock::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = monotonic_clock::now();
+        let elapsed = now.saturating_sub(self.updated) as f64 / NANOS_PER_SECOND;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.updated = now;
+    }
+}
+
+/// Limits the rate of each operation configured with a 'rate-limit.<operation>' key, e.g.
+/// 'rate-limit.open-at', to a number of calls per second.
+///
+/// Calls over the limit fail with 'busy', or are delayed until the limit allows them when
+/// 'rate-limit-action' is 'delay'.
+struct Limiter {
+    buckets: BTreeMap<String, Bucket>,
+    delay: bool,
+}
+
+impl Limiter {
+    fn from_config() -> Self {
+        let buckets = wasi::config::store::get_all()
+            .expect("Config must resolve")
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let operation = key.strip_prefix(RATE_LIMIT_PREFIX)?;
+                let rate = value.parse::<f64>().ok().filter(|rate| *rate > 0.0)?;
+                Some((operation.to_string(), Bucket::new(rate)))
+            })
+            .collect();
+        let delay = wasi::config::store::get(RATE_LIMIT_ACTION_KEY)
+            .expect("Config must resolve")
+            .is_some_and(|action| action == "delay");
+        Self { buckets, delay }
+    }
+
+    /// Take a call from the operation's budget, returning how long to wait before making it.
+    fn take(&mut self, operation: &str) -> Result<monotonic_clock::Duration, ErrorCode> {
+        let Some(bucket) = self.buckets.get_mut(operation) else {
+            return Ok(0);
+        };
+        bucket.refill();
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(0);
+        }
+        if !self.delay {
+            return Err(ErrorCode::Busy);
+        }
+        // delayed calls borrow from the future budget
+        bucket.tokens -= 1.0;
+        Ok((-bucket.tokens / bucket.rate * NANOS_PER_SECOND) as monotonic_clock::Duration)
+    }
+}
+
+/// Wait for the operation's budget to allow another call.
+async fn limit(operation: &str) -> Result<(), ErrorCode> {
+    let wait = LIMITER.with_borrow_mut(|limiter| limiter.take(operation))?;
+    if wait > 0 {
+        monotonic_clock::wait_for(wait).await;
+    }
+    Ok(())
+}
+
+/// Copy items from one stream to another until either end is dropped.
+async fn pipe<T: wit_stream::StreamPayload>(
+    rx: &mut wit_bindgen::StreamReader<T>,
+    tx: &mut wit_bindgen::StreamWriter<T>,
+) {
+    loop {
+        let (status, buf) = rx.read(Vec::with_capacity(CHUNK_SIZE)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        if !tx.write_all(buf).await.is_empty() {
+            break;
+        }
+    }
+}
+
+/// Limit a stream read from a descriptor, holding back the items read until the operation's
+/// budget allows the call.
+fn limit_read<T: wit_stream::StreamPayload>(
+    operation: &str,
+    read: impl FnOnce() -> (
+        wit_bindgen::StreamReader<T>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ),
+) -> (
+    wit_bindgen::StreamReader<T>,
+    wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) {
+    let Ok(wait) = LIMITER.with_borrow_mut(|limiter| limiter.take(operation)) else {
+        return busy();
+    };
+    let (mut data, result) = read();
+    if wait == 0 {
+        return (data, result);
+    }
+    let (mut data_tx, data_rx) = wit_stream::new();
+    let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+    wit_bindgen::spawn(async move {
+        monotonic_clock::wait_for(wait).await;
+        pipe(&mut data, &mut data_tx).await;
+        drop(data_tx);
+        drop(data);
+        let _ = result_tx.write(result.await).await;
+    });
+    (data_rx, result_rx)
+}
+
+/// Limit a stream written to a descriptor, holding back the items written until the
+/// operation's budget allows the call.
+fn limit_write(
+    operation: &str,
+    mut data: wit_bindgen::StreamReader<u8>,
+    write: impl FnOnce(
+        wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    let Ok(wait) = LIMITER.with_borrow_mut(|limiter| limiter.take(operation)) else {
+        return busy_result();
+    };
+    if wait == 0 {
+        return write(data);
+    }
+    let (mut data_tx, data_rx) = wit_stream::new();
+    wit_bindgen::spawn(async move {
+        monotonic_clock::wait_for(wait).await;
+        pipe(&mut data, &mut data_tx).await;
+    });
+    write(data_rx)
+}
+
+fn busy<T: wit_stream::StreamPayload>() -> (
+    wit_bindgen::StreamReader<T>,
+    wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) {
+    let (_, data_rx) = wit_stream::new();
+    (data_rx, busy_result())
+}
+
+fn busy_result() -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    let (tx, rx) = wit_future::new(|| Err(ErrorCode::Busy));
+    tx.write(Err(ErrorCode::Busy));
+    rx
+}
+
+struct FilesystemRateLimit {}
+
+impl Preopens for FilesystemRateLimit {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| {
+                let fd = Descriptor::new(RateLimitDescriptor::new(fd));
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemRateLimit {
+    type Descriptor = RateLimitDescriptor;
+}
+
+struct RateLimitDescriptor {
+    fd: types::Descriptor,
+}
+
+impl RateLimitDescriptor {
+    fn new(fd: types::Descriptor) -> Self {
+        Self { fd }
+    }
+}
+
+impl GuestDescriptor for RateLimitDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        limit_read("read-via-stream", || self.fd.read_via_stream(offset))
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        limit_write("write-via-stream", data, |data| {
+            self.fd.write_via_stream(data, offset)
+        })
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        limit_write("append-via-stream", data, |data| {
+            self.fd.append_via_stream(data)
+        })
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        limit("advise").await?;
+        self.fd.advise(offset, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        limit("sync-data").await?;
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        limit("get-flags").await?;
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        limit("get-type").await?;
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        limit("set-size").await?;
+        self.fd.set_size(size).await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        limit("set-times").await?;
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        limit_read("read-directory", || self.fd.read_directory())
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        limit("sync").await?;
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        limit("create-directory-at").await?;
+        self.fd.create_directory_at(path).await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        limit("stat").await?;
+        self.fd.stat().await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        limit("stat-at").await?;
+        self.fd.stat_at(path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        limit("set-times-at").await?;
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        limit("link-at").await?;
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        limit("open-at").await?;
+        self.fd
+            .open_at(path_flags, path, open_flags, flags)
+            .await
+            .map(|fd| Descriptor::new(RateLimitDescriptor::new(fd)))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        limit("readlink-at").await?;
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        limit("remove-directory-at").await?;
+        self.fd.remove_directory_at(path).await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        limit("rename-at").await?;
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        limit("symlink-at").await?;
+        self.fd.symlink_at(old_path, new_path).await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        limit("unlink-file-at").await?;
+        self.fd.unlink_file_at(path).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        limit("metadata-hash").await?;
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        limit("metadata-hash-at").await?;
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemRateLimit);
@@ -0,0 +1,557 @@
+#![no_main]
+
+use std::cell::{Cell, OnceCell};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::channel::mpsc;
+use futures::StreamExt;
+
+use journal::exports::wasi::filesystem::preopens::Guest as Preopens;
+use journal::exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use journal::wasi::filesystem::preopens;
+use journal::wasi::filesystem::types;
+use journal::{
+    wit_future, wit_stream, Call, DescriptorId, Entry, Line, Return, Stream, StreamId,
+    DEFAULT_JOURNAL_PATH, JOURNAL_PATH_KEY, JOURNAL_PREOPEN_KEY,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    static JOURNAL: OnceCell<mpsc::UnboundedSender<Vec<u8>>> = const { OnceCell::new() };
+    static NEXT_ID: Cell<DescriptorId> = const { Cell::new(0) };
+    static NEXT_STREAM: Cell<StreamId> = const { Cell::new(0) };
+}
+
+fn config(key: &str) -> Option<String> {
+    journal::wasi::config::store::get(key).expect("Config must resolve")
+}
+
+/// Open the journal file, truncating any previous content, and start a task appending each
+/// entry sent to the returned channel.
+fn open_journal(dir: types::Descriptor) -> mpsc::UnboundedSender<Vec<u8>> {
+    let path = config(JOURNAL_PATH_KEY).unwrap_or(String::from(DEFAULT_JOURNAL_PATH));
+    let fd = wit_bindgen::block_on(async {
+        dir.open_at(
+            types::PathFlags::empty(),
+            path.clone(),
+            types::OpenFlags::CREATE | types::OpenFlags::TRUNCATE,
+            types::DescriptorFlags::WRITE,
+        )
+        .await
+        .unwrap_or_else(|error| panic!("journal '{path}' must be writable: {error:?}"))
+    });
+
+    let (tx, mut lines) = mpsc::unbounded::<Vec<u8>>();
+    let (mut data_tx, data_rx) = wit_stream::new();
+    let result = fd.write_via_stream(data_rx, 0);
+    wit_bindgen::spawn(async move {
+        while let Some(line) = lines.next().await {
+            if !data_tx.write_all(line).await.is_empty() {
+                break;
+            }
+        }
+        drop(data_tx);
+        let _ = result.await;
+        drop(fd);
+    });
+    tx
+}
+
+/// Append a line to the journal.
+fn journal(line: &Line) {
+    let line = line.encode();
+    JOURNAL.with(|journal| {
+        if let Some(journal) = journal.get() {
+            let _ = journal.unbounded_send(line);
+        }
+    });
+}
+
+/// Append an entry to the journal.
+fn record(
+    fd: Option<DescriptorId>,
+    call: Call,
+    stream: Option<StreamId>,
+    result: Result<Return, ErrorCode>,
+) {
+    journal(&Line::Entry(Entry {
+        fd,
+        call,
+        stream,
+        result,
+    }));
+}
+
+/// Copy a stream until either end is dropped, journaling the items transferred in chunks as
+/// they pass, and returning the id of the stream they are journaled under.
+async fn pipe<T: wit_stream::StreamPayload + Clone>(
+    rx: &mut wit_bindgen::StreamReader<T>,
+    tx: &mut wit_bindgen::StreamWriter<T>,
+    encode: impl Fn(&[T]) -> Stream,
+) -> StreamId {
+    let id = NEXT_STREAM.replace(NEXT_STREAM.get() + 1);
+    loop {
+        let (status, buf) = rx.read(Vec::with_capacity(CHUNK_SIZE)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        let items = buf.clone();
+        let remaining = tx.write_all(buf).await;
+        let transferred = &items[..items.len() - remaining.len()];
+        if !transferred.is_empty() {
+            journal(&Line::Chunk {
+                chunk: id,
+                stream: encode(transferred),
+            });
+        }
+        if !remaining.is_empty() {
+            break;
+        }
+    }
+    id
+}
+
+fn encode_bytes(bytes: &[u8]) -> Stream {
+    Stream::Bytes(STANDARD.encode(bytes))
+}
+
+fn encode_entries(entries: &[DirectoryEntry]) -> Stream {
+    Stream::Entries(entries.to_vec())
+}
+
+struct FilesystemRecord {}
+
+impl Preopens for FilesystemRecord {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let journal_preopen = config(JOURNAL_PREOPEN_KEY)
+            .unwrap_or_else(|| panic!("Config must contain '{JOURNAL_PREOPEN_KEY}'"));
+
+        let mut directories = vec![];
+        let mut ids = vec![];
+        for (fd, path) in preopens::get_directories() {
+            if path == journal_preopen {
+                // the journal's preopen is hidden from the guest
+                JOURNAL.with(|journal| {
+                    journal.get_or_init(|| open_journal(fd));
+                });
+                continue;
+            }
+            let fd = RecordDescriptor::new(fd);
+            ids.push((fd.id, path.clone()));
+            directories.push((Descriptor::new(fd), path));
+        }
+
+        record(
+            None,
+            Call::GetDirectories,
+            None,
+            Ok(Return::Directories(ids)),
+        );
+        directories
+    }
+}
+
+impl Types for FilesystemRecord {
+    type Descriptor = RecordDescriptor;
+}
+
+struct RecordDescriptor {
+    fd: types::Descriptor,
+    id: DescriptorId,
+}
+
+impl RecordDescriptor {
+    fn new(fd: types::Descriptor) -> Self {
+        let id = NEXT_ID.replace(NEXT_ID.get() + 1);
+        Self { fd, id }
+    }
+
+    fn record<T>(&self, call: Call, result: &Result<T, ErrorCode>, ret: impl FnOnce(&T) -> Return) {
+        record(
+            Some(self.id),
+            call,
+            None,
+            result.as_ref().map(ret).map_err(Clone::clone),
+        );
+    }
+
+    fn record_write(
+        &self,
+        call: Call,
+        mut data: wit_bindgen::StreamReader<u8>,
+        write: impl FnOnce(
+            wit_bindgen::StreamReader<u8>,
+        ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let result = write(data_rx);
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let id = self.id;
+        wit_bindgen::spawn(async move {
+            let stream = pipe(&mut data, &mut data_tx, encode_bytes).await;
+            drop(data_tx);
+            let result = result.await;
+            record(
+                Some(id),
+                call,
+                Some(stream),
+                result.clone().map(|()| Return::Unit),
+            );
+            let _ = result_tx.write(result).await;
+        });
+        result_rx
+    }
+}
+
+impl GuestDescriptor for RecordDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (mut data, result) = self.fd.read_via_stream(offset);
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let id = self.id;
+        wit_bindgen::spawn(async move {
+            let stream = pipe(&mut data, &mut data_tx, encode_bytes).await;
+            drop(data_tx);
+            let result = result.await;
+            record(
+                Some(id),
+                Call::ReadViaStream { offset },
+                Some(stream),
+                result.clone().map(|()| Return::Unit),
+            );
+            let _ = result_tx.write(result).await;
+        });
+        (data_rx, result_rx)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.record_write(Call::WriteViaStream { offset }, data, |data| {
+            self.fd.write_via_stream(data, offset)
+        })
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.record_write(Call::AppendViaStream, data, |data| {
+            self.fd.append_via_stream(data)
+        })
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        let result = self.fd.advise(offset, length, advice).await;
+        self.record(
+            Call::Advise {
+                offset,
+                length,
+                advice,
+            },
+            &result,
+            |()| Return::Unit,
+        );
+        result
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        let result = self.fd.sync_data().await;
+        self.record(Call::SyncData, &result, |()| Return::Unit);
+        result
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        let result = self.fd.get_flags().await;
+        self.record(Call::GetFlags, &result, |flags| Return::Flags(flags.bits()));
+        result
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        let result = self.fd.get_type().await;
+        self.record(Call::GetType, &result, |descriptor_type| {
+            Return::Type(descriptor_type.clone())
+        });
+        result
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        let result = self.fd.set_size(size).await;
+        self.record(Call::SetSize { size }, &result, |()| Return::Unit);
+        result
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        let result = self
+            .fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await;
+        self.record(
+            Call::SetTimes {
+                data_access_timestamp,
+                data_modification_timestamp,
+            },
+            &result,
+            |()| Return::Unit,
+        );
+        result
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (mut entries, result) = self.fd.read_directory();
+        let (mut entries_tx, entries_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let id = self.id;
+        wit_bindgen::spawn(async move {
+            let stream = pipe(&mut entries, &mut entries_tx, encode_entries).await;
+            drop(entries_tx);
+            let result = result.await;
+            record(
+                Some(id),
+                Call::ReadDirectory,
+                Some(stream),
+                result.clone().map(|()| Return::Unit),
+            );
+            let _ = result_tx.write(result).await;
+        });
+        (entries_rx, result_rx)
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        let result = self.fd.sync().await;
+        self.record(Call::Sync, &result, |()| Return::Unit);
+        result
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        let result = self.fd.create_directory_at(path.clone()).await;
+        self.record(Call::CreateDirectoryAt { path }, &result, |()| Return::Unit);
+        result
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        let result = self.fd.stat().await;
+        self.record(Call::Stat, &result, |stat| Return::Stat(stat.clone()));
+        result
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        let result = self.fd.stat_at(path_flags, path.clone()).await;
+        self.record(
+            Call::StatAt {
+                path_flags: path_flags.bits(),
+                path,
+            },
+            &result,
+            |stat| Return::Stat(stat.clone()),
+        );
+        result
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        let result = self
+            .fd
+            .set_times_at(
+                path_flags,
+                path.clone(),
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await;
+        self.record(
+            Call::SetTimesAt {
+                path_flags: path_flags.bits(),
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            },
+            &result,
+            |()| Return::Unit,
+        );
+        result
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        let result = self
+            .fd
+            .link_at(
+                old_path_flags,
+                old_path.clone(),
+                &new_descriptor.fd,
+                new_path.clone(),
+            )
+            .await;
+        self.record(
+            Call::LinkAt {
+                old_path_flags: old_path_flags.bits(),
+                old_path,
+                new_descriptor: new_descriptor.id,
+                new_path,
+            },
+            &result,
+            |()| Return::Unit,
+        );
+        result
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let result = self
+            .fd
+            .open_at(path_flags, path.clone(), open_flags, flags)
+            .await
+            .map(RecordDescriptor::new);
+        self.record(
+            Call::OpenAt {
+                path_flags: path_flags.bits(),
+                path,
+                open_flags: open_flags.bits(),
+                flags: flags.bits(),
+            },
+            &result,
+            |fd| Return::Descriptor(fd.id),
+        );
+        result.map(Descriptor::new)
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        let result = self.fd.readlink_at(path.clone()).await;
+        self.record(Call::ReadlinkAt { path }, &result, |target| {
+            Return::Path(target.clone())
+        });
+        result
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        let result = self.fd.remove_directory_at(path.clone()).await;
+        self.record(Call::RemoveDirectoryAt { path }, &result, |()| Return::Unit);
+        result
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        let result = self
+            .fd
+            .rename_at(old_path.clone(), &new_descriptor.fd, new_path.clone())
+            .await;
+        self.record(
+            Call::RenameAt {
+                old_path,
+                new_descriptor: new_descriptor.id,
+                new_path,
+            },
+            &result,
+            |()| Return::Unit,
+        );
+        result
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        let result = self.fd.symlink_at(old_path.clone(), new_path.clone()).await;
+        self.record(Call::SymlinkAt { old_path, new_path }, &result, |()| {
+            Return::Unit
+        });
+        result
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        let result = self.fd.unlink_file_at(path.clone()).await;
+        self.record(Call::UnlinkFileAt { path }, &result, |()| Return::Unit);
+        result
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        let result = self.fd.is_same_object(&other.fd).await;
+        record(
+            Some(self.id),
+            Call::IsSameObject { other: other.id },
+            None,
+            Ok(Return::Bool(result)),
+        );
+        result
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        let result = self.fd.metadata_hash().await;
+        self.record(Call::MetadataHash, &result, |hash| Return::Hash(*hash));
+        result
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        let result = self.fd.metadata_hash_at(path_flags, path.clone()).await;
+        self.record(
+            Call::MetadataHashAt {
+                path_flags: path_flags.bits(),
+                path,
+            },
+            &result,
+            |hash| Return::Hash(*hash),
+        );
+        result
+    }
+}
+
+journal::export!(FilesystemRecord);
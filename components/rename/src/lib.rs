@@ -0,0 +1,299 @@
+#![cfg_attr(not(test), no_main)]
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+use wasi::logging::logging::{log, Level};
+
+const PREOPENS_KEY: &str = "preopens";
+
+/// The preopens to export in order, from a comma separated list of host preopen paths each
+/// optionally followed by '=' and the guest path to rename it to.
+fn parse_preopens(value: &str) -> Vec<(&str, &str)> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((host, guest)) => (host.trim(), guest.trim()),
+            None => (entry, entry),
+        })
+        .collect()
+}
+
+struct FilesystemRename {}
+
+impl Preopens for FilesystemRename {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let dirs = preopens::get_directories();
+        let Some(value) = wasi::config::store::get(PREOPENS_KEY).expect("Config must resolve")
+        else {
+            return dirs
+                .into_iter()
+                .map(|(fd, path)| (Descriptor::new(RenameDescriptor { fd }), path))
+                .collect();
+        };
+        // preopens not listed are dropped
+        let mut dirs = dirs.into_iter().map(Some).collect::<Vec<_>>();
+        let mut renamed = Vec::new();
+        for (host, guest) in parse_preopens(&value) {
+            let dir = dirs
+                .iter_mut()
+                .find(|dir| dir.as_ref().is_some_and(|(_, path)| path == host));
+            match dir.and_then(Option::take) {
+                Some((fd, _)) => {
+                    renamed.push((Descriptor::new(RenameDescriptor { fd }), guest.to_string()))
+                }
+                None => log(
+                    Level::Warn,
+                    "filesystem",
+                    &format!("skipping preopen '{host}': not found or already listed"),
+                ),
+            }
+        }
+        renamed
+    }
+}
+
+impl Types for FilesystemRename {
+    type Descriptor = RenameDescriptor;
+}
+
+struct RenameDescriptor {
+    fd: types::Descriptor,
+}
+
+impl GuestDescriptor for RenameDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_via_stream(offset)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.fd.write_via_stream(data, offset)
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.fd.append_via_stream(data)
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        self.fd.advise(offset, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        self.fd.set_size(size).await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_directory()
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.create_directory_at(path).await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat().await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat_at(path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        self.fd
+            .open_at(path_flags, path, open_flags, flags)
+            .await
+            .map(|fd| Descriptor::new(RenameDescriptor { fd }))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.remove_directory_at(path).await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        self.fd.symlink_at(old_path, new_path).await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.unlink_file_at(path).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemRename);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preopens_are_renamed() {
+        assert_eq!(
+            parse_preopens("/mnt/volume-7=/data,/tmp"),
+            [("/mnt/volume-7", "/data"), ("/tmp", "/tmp")]
+        );
+    }
+
+    #[test]
+    fn preopens_keep_their_order() {
+        assert_eq!(parse_preopens("/b=/x,/a=/y"), [("/b", "/x"), ("/a", "/y")]);
+    }
+
+    #[test]
+    fn whitespace_and_empty_entries_are_ignored() {
+        assert_eq!(
+            parse_preopens(" /mnt = /data , ,/tmp,"),
+            [("/mnt", "/data"), ("/tmp", "/tmp")]
+        );
+        assert!(parse_preopens("").is_empty());
+        assert!(parse_preopens(" , ").is_empty());
+    }
+
+    #[test]
+    fn guest_paths_may_contain_equals() {
+        assert_eq!(parse_preopens("/mnt=/a=b"), [("/mnt", "/a=b")]);
+    }
+
+    #[test]
+    fn repeated_preopens_are_kept() {
+        // skipped when the preopens are exported
+        assert_eq!(
+            parse_preopens("/tmp=/a,/tmp=/b"),
+            [("/tmp", "/a"), ("/tmp", "/b")]
+        );
+    }
+}
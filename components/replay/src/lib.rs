@@ -0,0 +1,436 @@
+#![no_main]
+
+use std::cell::RefCell;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use journal::exports::wasi::filesystem::preopens::Guest as Preopens;
+use journal::exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use journal::wasi::filesystem::preopens;
+use journal::wasi::filesystem::types;
+use journal::wasi::logging::logging::{log, Level};
+use journal::{
+    wit_future, wit_stream, Call, DescriptorId, Entry, Journal, Return, Stream,
+    DEFAULT_JOURNAL_PATH, JOURNAL_PATH_KEY, JOURNAL_PREOPEN_KEY,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    static JOURNAL: RefCell<Option<Journal>> = const { RefCell::new(None) };
+}
+
+fn config(key: &str) -> Option<String> {
+    journal::wasi::config::store::get(key).expect("Config must resolve")
+}
+
+/// Load the journal named in config from its preopen.
+fn load() -> Journal {
+    let journal_preopen = config(JOURNAL_PREOPEN_KEY)
+        .unwrap_or_else(|| panic!("Config must contain '{JOURNAL_PREOPEN_KEY}'"));
+    let path = config(JOURNAL_PATH_KEY).unwrap_or(String::from(DEFAULT_JOURNAL_PATH));
+    let (dir, _) = preopens::get_directories()
+        .into_iter()
+        .find(|(_, path)| *path == journal_preopen)
+        .unwrap_or_else(|| panic!("preopen '{journal_preopen}' must exist"));
+
+    let content = wit_bindgen::block_on(async {
+        let fd = dir
+            .open_at(
+                types::PathFlags::empty(),
+                path.clone(),
+                types::OpenFlags::empty(),
+                types::DescriptorFlags::READ,
+            )
+            .await
+            .unwrap_or_else(|error| panic!("journal '{path}' must be readable: {error:?}"));
+        let (data, result) = fd.read_via_stream(0);
+        let content = data.collect().await;
+        result
+            .await
+            .unwrap_or_else(|error| panic!("journal '{path}' must be readable: {error:?}"));
+        content
+    });
+
+    Journal::parse(&content).unwrap_or_else(|(number, error)| {
+        panic!("journal '{path}' line {number} must be valid: {error}")
+    })
+}
+
+/// Find the recorded entry for a call, failing the call if it was never recorded.
+fn replay(fd: Option<DescriptorId>, call: Call) -> Result<Entry, ErrorCode> {
+    let entry = JOURNAL.with_borrow_mut(|journal| journal.get_or_insert_with(load).take(fd, &call));
+    entry.ok_or_else(|| {
+        log(
+            Level::Warn,
+            "filesystem",
+            &format!("MISSING {call:?} FD={fd:?}"),
+        );
+        ErrorCode::Other(Some(String::from("call not found in journal")))
+    })
+}
+
+/// Take the chunks of a recorded stream, in the order they were transferred.
+fn chunks(entry: &Entry) -> Vec<Stream> {
+    let Some(stream) = entry.stream else {
+        return vec![];
+    };
+    JOURNAL.with_borrow_mut(|journal| {
+        journal
+            .as_mut()
+            .map(|journal| journal.chunks(stream))
+            .unwrap_or_default()
+    })
+}
+
+/// An error for a recorded result that does not match the type of the call.
+fn mismatch() -> ErrorCode {
+    ErrorCode::Other(Some(String::from("journal entry does not match call")))
+}
+
+struct FilesystemReplay {}
+
+impl Preopens for FilesystemReplay {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        match replay(None, Call::GetDirectories).and_then(|entry| entry.result) {
+            Ok(Return::Directories(directories)) => directories
+                .into_iter()
+                .map(|(id, path)| (Descriptor::new(ReplayDescriptor::new(id)), path))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+impl Types for FilesystemReplay {
+    type Descriptor = ReplayDescriptor;
+}
+
+struct ReplayDescriptor {
+    id: DescriptorId,
+}
+
+impl ReplayDescriptor {
+    fn new(id: DescriptorId) -> Self {
+        Self { id }
+    }
+
+    fn replay(&self, call: Call) -> Result<Return, ErrorCode> {
+        replay(Some(self.id), call)?.result
+    }
+
+    fn replay_unit(&self, call: Call) -> Result<(), ErrorCode> {
+        match self.replay(call)? {
+            Return::Unit => Ok(()),
+            _ => Err(mismatch()),
+        }
+    }
+
+    fn replay_stat(&self, call: Call) -> Result<DescriptorStat, ErrorCode> {
+        match self.replay(call)? {
+            Return::Stat(stat) => Ok(stat),
+            _ => Err(mismatch()),
+        }
+    }
+
+    fn replay_hash(&self, call: Call) -> Result<MetadataHashValue, ErrorCode> {
+        match self.replay(call)? {
+            Return::Hash(hash) => Ok(hash),
+            _ => Err(mismatch()),
+        }
+    }
+
+    /// Consume the data written by the guest, resolving with the recorded result.
+    fn replay_write(
+        &self,
+        call: Call,
+        mut data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let result = replay(Some(self.id), call).and_then(|entry| entry.result);
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            if result.is_ok() {
+                // drain what the guest writes, it was already written when recorded
+                while let (wit_bindgen::StreamResult::Complete(_), _) =
+                    data.read(Vec::with_capacity(CHUNK_SIZE)).await
+                {}
+            }
+            drop(data);
+            let result = result.and_then(|ret| match ret {
+                Return::Unit => Ok(()),
+                _ => Err(mismatch()),
+            });
+            let _ = result_tx.write(result).await;
+        });
+        result_rx
+    }
+}
+
+impl GuestDescriptor for ReplayDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let entry = replay(Some(self.id), Call::ReadViaStream { offset });
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            let result = match entry {
+                Ok(entry) => {
+                    let mut result = entry.result.clone().map(|_| ());
+                    for chunk in chunks(&entry) {
+                        let Stream::Bytes(data) = chunk else {
+                            result = Err(mismatch());
+                            break;
+                        };
+                        let Ok(data) = STANDARD.decode(data) else {
+                            // corrupt data is not replayed as if the file were shorter
+                            result = Err(ErrorCode::Io);
+                            break;
+                        };
+                        if !data_tx.write_all(data).await.is_empty() {
+                            break;
+                        }
+                    }
+                    result
+                }
+                Err(error) => Err(error),
+            };
+            drop(data_tx);
+            let _ = result_tx.write(result).await;
+        });
+        (data_rx, result_rx)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.replay_write(Call::WriteViaStream { offset }, data)
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.replay_write(Call::AppendViaStream, data)
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::Advise {
+            offset,
+            length,
+            advice,
+        })
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::SyncData)
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        match self.replay(Call::GetFlags)? {
+            Return::Flags(flags) => Ok(DescriptorFlags::from_bits_retain(flags)),
+            _ => Err(mismatch()),
+        }
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        match self.replay(Call::GetType)? {
+            Return::Type(descriptor_type) => Ok(descriptor_type),
+            _ => Err(mismatch()),
+        }
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::SetSize { size })
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::SetTimes {
+            data_access_timestamp,
+            data_modification_timestamp,
+        })
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let entry = replay(Some(self.id), Call::ReadDirectory);
+        let (mut entries_tx, entries_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            let result = match entry {
+                Ok(entry) => {
+                    let mut result = entry.result.clone().map(|_| ());
+                    for chunk in chunks(&entry) {
+                        let Stream::Entries(entries) = chunk else {
+                            result = Err(mismatch());
+                            break;
+                        };
+                        if !entries_tx.write_all(entries).await.is_empty() {
+                            break;
+                        }
+                    }
+                    result
+                }
+                Err(error) => Err(error),
+            };
+            drop(entries_tx);
+            let _ = result_tx.write(result).await;
+        });
+        (entries_rx, result_rx)
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::Sync)
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::CreateDirectoryAt { path })
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        self.replay_stat(Call::Stat)
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        self.replay_stat(Call::StatAt {
+            path_flags: path_flags.bits(),
+            path,
+        })
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::SetTimesAt {
+            path_flags: path_flags.bits(),
+            path,
+            data_access_timestamp,
+            data_modification_timestamp,
+        })
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.replay_unit(Call::LinkAt {
+            old_path_flags: old_path_flags.bits(),
+            old_path,
+            new_descriptor: new_descriptor.id,
+            new_path,
+        })
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let call = Call::OpenAt {
+            path_flags: path_flags.bits(),
+            path,
+            open_flags: open_flags.bits(),
+            flags: flags.bits(),
+        };
+        match self.replay(call)? {
+            Return::Descriptor(id) => Ok(Descriptor::new(ReplayDescriptor::new(id))),
+            _ => Err(mismatch()),
+        }
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        match self.replay(Call::ReadlinkAt { path })? {
+            Return::Path(target) => Ok(target),
+            _ => Err(mismatch()),
+        }
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::RemoveDirectoryAt { path })
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.replay_unit(Call::RenameAt {
+            old_path,
+            new_descriptor: new_descriptor.id,
+            new_path,
+        })
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::SymlinkAt { old_path, new_path })
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.replay_unit(Call::UnlinkFileAt { path })
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        match self.replay(Call::IsSameObject { other: other.id }) {
+            Ok(Return::Bool(result)) => result,
+            _ => self.id == other.id,
+        }
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        self.replay_hash(Call::MetadataHash)
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        self.replay_hash(Call::MetadataHashAt {
+            path_flags: path_flags.bits(),
+            path,
+        })
+    }
+}
+
+journal::export!(FilesystemReplay);
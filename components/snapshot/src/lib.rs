@@ -0,0 +1,668 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use snapshot::{relative, Found, Preserved, Snapshot};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+mod snapshot;
+
+const SOURCE_KEY: &str = "source";
+const STORE_KEY: &str = "store";
+const SNAPSHOT_KEY: &str = "snapshot";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    static SNAPSHOT: Rc<Snapshot> = Rc::new(snapshot_from_config());
+}
+
+fn config(key: &str) -> Option<String> {
+    wasi::config::store::get(key).expect("Config must resolve")
+}
+
+fn store_path() -> String {
+    config(STORE_KEY).expect("Store preopen must be configured")
+}
+
+/// The preopen to snapshot, defaulting to the first imported preopen other than the store.
+fn source_path(dirs: &[(types::Descriptor, String)], store: &str) -> String {
+    config(SOURCE_KEY)
+        .or_else(|| {
+            dirs.iter()
+                .map(|(_, path)| path.clone())
+                .find(|path| path != store)
+        })
+        .expect("Source preopen must exist")
+}
+
+/// Take the snapshot of the source preopen, which stays as it is now.
+fn snapshot_from_config() -> Snapshot {
+    let mut dirs = preopens::get_directories();
+    let store = store_path();
+    let source = source_path(&dirs, &store);
+    let mut take = |path: &str| {
+        let index = dirs
+            .iter()
+            .position(|(_, dir)| dir == path)
+            .unwrap_or_else(|| panic!("Preopen '{path}' must exist"));
+        dirs.swap_remove(index).0
+    };
+    let source = take(&source);
+    let store = take(&store);
+    Snapshot::new(source, store)
+}
+
+/// Join a path to a path relative to the snapshotted directory, resolving '.' and '..' without
+/// following symbolic links. The result may not leave the directory.
+fn join(base: &Path, path: &str) -> Result<PathBuf, ErrorCode> {
+    if path.starts_with('/') {
+        return Err(ErrorCode::NotPermitted);
+    }
+    let mut joined = base.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => joined.push(name),
+            Component::ParentDir => {
+                if !joined.pop() {
+                    return Err(ErrorCode::NotPermitted);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(joined)
+}
+
+fn failed(error: ErrorCode) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    let (tx, rx) = wit_future::new(|| Err(ErrorCode::Io));
+    wit_bindgen::spawn(async move {
+        let _ = tx.write(Err(error)).await;
+    });
+    rx
+}
+
+fn metadata_hash(path: &Path) -> MetadataHashValue {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    MetadataHashValue {
+        lower: hasher.finish(),
+        upper: 0,
+    }
+}
+
+/// Where the data of a file of the snapshot is read from.
+#[derive(PartialEq)]
+enum Source {
+    /// A path of the source directory.
+    Live(PathBuf),
+    /// The name of a copy in the store.
+    Copy(String),
+}
+
+/// Read a chunk of a file from an offset, which is empty at the end of the file.
+async fn read_chunk(fd: &types::Descriptor, offset: Filesize) -> Result<Vec<u8>, ErrorCode> {
+    let (mut data, result) = fd.read_via_stream(offset);
+    loop {
+        let (status, buf) = data.read(Vec::with_capacity(CHUNK_SIZE)).await;
+        if !buf.is_empty() {
+            return Ok(buf);
+        }
+        match status {
+            wit_bindgen::StreamResult::Complete(_) => continue,
+            _ => break,
+        }
+    }
+    drop(data);
+    result.await.map(|()| Vec::new())
+}
+
+/// Read a file of the snapshot. A file of the source directory is read a chunk at a time, which
+/// a change to it waits for before preserving it, so later chunks are read from the copy.
+async fn read(
+    snapshot: &Snapshot,
+    path: &Path,
+    mut offset: Filesize,
+    data_tx: &mut wit_bindgen::StreamWriter<u8>,
+) -> Result<(), ErrorCode> {
+    let mut open: Option<(Source, types::Descriptor)> = None;
+    loop {
+        let reading = snapshot.start_read(path);
+        let source = match snapshot.lookup(path)? {
+            Found::Live(live) => Source::Live(live),
+            Found::Preserved(Preserved::File(name, _)) => Source::Copy(name),
+            Found::Preserved(Preserved::Directory(..)) => return Err(ErrorCode::IsDirectory),
+            Found::Preserved(_) => return Err(ErrorCode::Invalid),
+        };
+        if open.as_ref().is_none_or(|(opened, _)| *opened != source) {
+            let fd = match &source {
+                Source::Live(live) => {
+                    snapshot
+                        .source
+                        .open_at(
+                            types::PathFlags::empty(),
+                            relative(live),
+                            types::OpenFlags::empty(),
+                            types::DescriptorFlags::READ,
+                        )
+                        .await?
+                }
+                Source::Copy(name) => snapshot.open_copy(name.clone()).await?,
+            };
+            open = Some((source, fd));
+        }
+        let (_, fd) = open.as_ref().expect("source is open");
+        let chunk = read_chunk(fd, offset).await?;
+        // the guest may take its time with the chunk, which no longer depends on the file
+        drop(reading);
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        offset += chunk.len() as Filesize;
+        if !data_tx.write_all(chunk).await.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+/// Stat a path of the snapshot.
+async fn stat(snapshot: &Snapshot, path: &Path) -> Result<DescriptorStat, ErrorCode> {
+    match snapshot.lookup(path)? {
+        Found::Live(live) => {
+            snapshot
+                .source
+                .stat_at(types::PathFlags::empty(), relative(&live))
+                .await
+        }
+        Found::Preserved(
+            Preserved::Directory(stat, _) | Preserved::File(_, stat) | Preserved::Symlink(_, stat),
+        ) => Ok(stat),
+        Found::Preserved(Preserved::Absent) => Err(ErrorCode::NoEntry),
+    }
+}
+
+struct FilesystemSnapshot {}
+
+impl Preopens for FilesystemSnapshot {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let snapshot = SNAPSHOT.with(Rc::clone);
+        let dirs = preopens::get_directories();
+        let store = store_path();
+        let source = source_path(&dirs, &store);
+        let mut exported = dirs
+            .into_iter()
+            .filter(|(_, path)| *path != store)
+            .map(|(fd, path)| {
+                let tracked = (path == source).then(|| Tracked {
+                    snapshot: snapshot.clone(),
+                    path: PathBuf::new(),
+                });
+                (
+                    Descriptor::new(SnapshotDescriptor::Live {
+                        fd: Rc::new(fd),
+                        tracked,
+                    }),
+                    path,
+                )
+            })
+            .collect::<Vec<_>>();
+        let path = config(SNAPSHOT_KEY).unwrap_or(String::from("/snapshot"));
+        let fd = Descriptor::new(SnapshotDescriptor::Frozen {
+            snapshot,
+            path: PathBuf::new(),
+        });
+        exported.push((fd, path));
+        exported
+    }
+}
+
+impl Types for FilesystemSnapshot {
+    type Descriptor = SnapshotDescriptor;
+}
+
+/// A descriptor within the snapshotted directory.
+struct Tracked {
+    snapshot: Rc<Snapshot>,
+    /// The path relative to the snapshotted directory.
+    path: PathBuf,
+}
+
+enum SnapshotDescriptor {
+    /// A descriptor of an imported preopen, preserving the snapshot state of the snapshotted
+    /// directory before it changes.
+    Live {
+        fd: Rc<types::Descriptor>,
+        tracked: Option<Tracked>,
+    },
+    /// A read-only descriptor of the snapshot, at a path relative to the snapshotted directory.
+    Frozen {
+        snapshot: Rc<Snapshot>,
+        path: PathBuf,
+    },
+}
+
+impl SnapshotDescriptor {
+    /// The snapshotted path a path relative to this descriptor is at, if it is within the
+    /// snapshotted directory.
+    fn tracked_at(&self, path: &str) -> Option<Tracked> {
+        match self {
+            SnapshotDescriptor::Live {
+                tracked: Some(tracked),
+                ..
+            } => Some(Tracked {
+                snapshot: tracked.snapshot.clone(),
+                path: join(&tracked.path, path).ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Preserve the snapshot state of a path relative to this descriptor before it changes.
+    async fn preserve_at(&self, path: &str) -> Result<(), ErrorCode> {
+        match self.tracked_at(path) {
+            Some(tracked) => tracked.snapshot.preserve(&tracked.path).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Preserve the file before forwarding a write to it, from a spawned task so the write isn't
+    /// blocked on.
+    fn preserve_then(
+        &self,
+        write: impl FnOnce(&types::Descriptor) -> wit_bindgen::FutureReader<Result<(), ErrorCode>>
+            + 'static,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let SnapshotDescriptor::Live { fd, .. } = self else {
+            return failed(ErrorCode::ReadOnly);
+        };
+        let fd = fd.clone();
+        let tracked = self.tracked_at("");
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            let preserved = match tracked {
+                Some(tracked) => tracked.snapshot.preserve(&tracked.path).await,
+                None => Ok(()),
+            };
+            let result = match preserved {
+                Ok(()) => write(&fd).await,
+                Err(error) => Err(error),
+            };
+            let _ = result_tx.write(result).await;
+        });
+        result_rx
+    }
+}
+
+impl GuestDescriptor for SnapshotDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (snapshot, path) = match self {
+            SnapshotDescriptor::Live { fd, .. } => return fd.read_via_stream(offset),
+            SnapshotDescriptor::Frozen { snapshot, path } => (snapshot.clone(), path.clone()),
+        };
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            let result = read(&snapshot, &path, offset, &mut data_tx).await;
+            drop(data_tx);
+            let _ = result_tx.write(result).await;
+        });
+        (data_rx, result_rx)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.preserve_then(move |fd| fd.write_via_stream(data, offset))
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.preserve_then(move |fd| fd.append_via_stream(data))
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.advise(offset, length, advice).await,
+            SnapshotDescriptor::Frozen { .. } => Ok(()),
+        }
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.sync_data().await,
+            SnapshotDescriptor::Frozen { .. } => Ok(()),
+        }
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.get_flags().await,
+            SnapshotDescriptor::Frozen { .. } => Ok(DescriptorFlags::READ),
+        }
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.get_type().await,
+            SnapshotDescriptor::Frozen { snapshot, path } => Ok(stat(snapshot, path).await?.type_),
+        }
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => {
+                self.preserve_at("").await?;
+                fd.set_size(size).await
+            }
+            SnapshotDescriptor::Frozen { .. } => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => {
+                self.preserve_at("").await?;
+                fd.set_times(data_access_timestamp, data_modification_timestamp)
+                    .await
+            }
+            SnapshotDescriptor::Frozen { .. } => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (snapshot, path) = match self {
+            SnapshotDescriptor::Live { fd, .. } => return fd.read_directory(),
+            SnapshotDescriptor::Frozen { snapshot, path } => (snapshot.clone(), path.clone()),
+        };
+        let (mut tx, rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            let result = match snapshot.list(&path).await {
+                Ok(entries) => {
+                    tx.write_all(entries).await;
+                    Ok(())
+                }
+                Err(error) => Err(error),
+            };
+            drop(tx);
+            let _ = result_tx.write(result).await;
+        });
+        (rx, result_rx)
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.sync().await,
+            SnapshotDescriptor::Frozen { .. } => Ok(()),
+        }
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => {
+                self.preserve_at(&path).await?;
+                fd.create_directory_at(path).await
+            }
+            SnapshotDescriptor::Frozen { .. } => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.stat().await,
+            SnapshotDescriptor::Frozen { snapshot, path } => stat(snapshot, path).await,
+        }
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.stat_at(path_flags, path).await,
+            SnapshotDescriptor::Frozen {
+                snapshot,
+                path: base,
+            } => stat(snapshot, &join(base, &path)?).await,
+        }
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => {
+                self.preserve_at(&path).await?;
+                fd.set_times_at(
+                    path_flags,
+                    path,
+                    data_access_timestamp,
+                    data_modification_timestamp,
+                )
+                .await
+            }
+            SnapshotDescriptor::Frozen { .. } => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        match (self, new_descriptor) {
+            (SnapshotDescriptor::Live { fd, .. }, SnapshotDescriptor::Live { fd: new_fd, .. }) => {
+                new_descriptor.preserve_at(&new_path).await?;
+                fd.link_at(old_path_flags, old_path, new_fd, new_path).await
+            }
+            (SnapshotDescriptor::Frozen { .. }, SnapshotDescriptor::Frozen { .. }) => {
+                Err(ErrorCode::ReadOnly)
+            }
+            _ => Err(ErrorCode::CrossDevice),
+        }
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let (snapshot, base) = match self {
+            SnapshotDescriptor::Live { fd, .. } => {
+                if open_flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNCATE) {
+                    self.preserve_at(&path).await?;
+                }
+                let tracked = self.tracked_at(&path);
+                let fd = fd.open_at(path_flags, path, open_flags, flags).await?;
+                return Ok(Descriptor::new(SnapshotDescriptor::Live {
+                    fd: Rc::new(fd),
+                    tracked,
+                }));
+            }
+            SnapshotDescriptor::Frozen { snapshot, path } => (snapshot, path),
+        };
+        if open_flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNCATE)
+            || flags.contains(DescriptorFlags::WRITE)
+        {
+            return Err(ErrorCode::ReadOnly);
+        }
+        let path = join(base, &path)?;
+        match stat(snapshot, &path).await?.type_ {
+            // symbolic links are not followed within the snapshot
+            DescriptorType::SymbolicLink => return Err(ErrorCode::Loop),
+            DescriptorType::Directory => {}
+            _ if open_flags.contains(OpenFlags::DIRECTORY) => return Err(ErrorCode::NotDirectory),
+            _ => {}
+        }
+        Ok(Descriptor::new(SnapshotDescriptor::Frozen {
+            snapshot: snapshot.clone(),
+            path,
+        }))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.readlink_at(path).await,
+            SnapshotDescriptor::Frozen {
+                snapshot,
+                path: base,
+            } => {
+                let path = join(base, &path)?;
+                match snapshot.lookup(&path)? {
+                    Found::Live(live) => snapshot.source.readlink_at(relative(&live)).await,
+                    Found::Preserved(Preserved::Symlink(target, _)) => Ok(target),
+                    Found::Preserved(_) => Err(ErrorCode::Invalid),
+                }
+            }
+        }
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => {
+                self.preserve_at(&path).await?;
+                fd.remove_directory_at(path).await
+            }
+            SnapshotDescriptor::Frozen { .. } => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        match (self, new_descriptor) {
+            (SnapshotDescriptor::Live { fd, .. }, SnapshotDescriptor::Live { fd: new_fd, .. }) => {
+                let old = self.tracked_at(&old_path);
+                let new = new_descriptor.tracked_at(&new_path);
+                self.preserve_at(&old_path).await?;
+                new_descriptor.preserve_at(&new_path).await?;
+                fd.rename_at(old_path, new_fd, new_path).await?;
+                // unchanged entries of a renamed directory are found where it is now
+                if let (Some(old), Some(new)) = (old, new) {
+                    old.snapshot.renamed(&old.path, &new.path);
+                }
+                Ok(())
+            }
+            (SnapshotDescriptor::Frozen { .. }, SnapshotDescriptor::Frozen { .. }) => {
+                Err(ErrorCode::ReadOnly)
+            }
+            _ => Err(ErrorCode::CrossDevice),
+        }
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => {
+                self.preserve_at(&new_path).await?;
+                fd.symlink_at(old_path, new_path).await
+            }
+            SnapshotDescriptor::Frozen { .. } => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => {
+                self.preserve_at(&path).await?;
+                fd.unlink_file_at(path).await
+            }
+            SnapshotDescriptor::Frozen { .. } => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        match (self, other) {
+            (SnapshotDescriptor::Live { fd, .. }, SnapshotDescriptor::Live { fd: other, .. }) => {
+                fd.is_same_object(other).await
+            }
+            (
+                SnapshotDescriptor::Frozen { path, .. },
+                SnapshotDescriptor::Frozen { path: other, .. },
+            ) => path == other,
+            _ => false,
+        }
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.metadata_hash().await,
+            SnapshotDescriptor::Frozen { path, .. } => Ok(metadata_hash(path)),
+        }
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        match self {
+            SnapshotDescriptor::Live { fd, .. } => fd.metadata_hash_at(path_flags, path).await,
+            SnapshotDescriptor::Frozen {
+                snapshot,
+                path: base,
+            } => {
+                let path = join(base, &path)?;
+                snapshot.lookup(&path)?;
+                Ok(metadata_hash(&path))
+            }
+        }
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemSnapshot);
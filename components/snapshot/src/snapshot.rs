@@ -0,0 +1,554 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::exports::wasi::filesystem::types::{
+    DescriptorStat, DescriptorType, DirectoryEntry, ErrorCode,
+};
+use crate::wasi::filesystem::types;
+use crate::wasi::logging::logging::{log, Level};
+use crate::wit_future;
+
+/// The state of a path when the snapshot was taken, recorded before it first changed.
+#[derive(Clone)]
+pub(crate) enum Preserved {
+    /// The path did not exist.
+    Absent,
+    /// A directory, listing the types of its entries, which are recorded as they change.
+    Directory(DescriptorStat, BTreeMap<String, DescriptorType>),
+    /// A regular file, copied to the store under a name.
+    File(String, DescriptorStat),
+    Symlink(String, DescriptorStat),
+}
+
+/// Where the snapshot state of a path is found.
+pub(crate) enum Found {
+    /// The path is unchanged since the snapshot was taken, at a path of the source directory.
+    Live(PathBuf),
+    Preserved(Preserved),
+}
+
+/// The host path of a path relative to a directory, which is the directory itself when empty.
+pub(crate) fn relative(path: &Path) -> String {
+    match path.as_os_str().is_empty() {
+        true => String::from("."),
+        false => path.to_string_lossy().into_owned(),
+    }
+}
+
+/// Replace the longest prefix of a path found in a map.
+fn translate<'a>(
+    path: &Path,
+    prefixes: impl Iterator<Item = (&'a PathBuf, &'a PathBuf)>,
+) -> PathBuf {
+    let Some((from, to)) = prefixes
+        .filter(|(from, _)| path.starts_with(from))
+        .max_by_key(|(from, _)| from.components().count())
+    else {
+        return path.to_path_buf();
+    };
+    let mut translated = to.clone();
+    translated.extend(path.strip_prefix(from).expect("path starts with prefix"));
+    translated
+}
+
+/// The snapshot states preserved so far, and where the paths of the snapshot are now.
+#[derive(Default)]
+pub(crate) struct Records {
+    /// The preserved states, by path in the snapshot.
+    preserved: BTreeMap<PathBuf, Preserved>,
+    /// Paths of the snapshot renamed since, by the path of the source directory they are at now,
+    /// so unchanged entries of renamed directories are still found.
+    moved: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Records {
+    /// The path in the snapshot of a path of the source directory.
+    fn snapshot_path(&self, live: &Path) -> PathBuf {
+        translate(live, self.moved.iter())
+    }
+
+    /// The path of the source directory a path in the snapshot is now at.
+    fn live_path(&self, path: &Path) -> PathBuf {
+        translate(path, self.moved.iter().map(|(live, path)| (path, live)))
+    }
+
+    /// Find the snapshot state of a path, without following symbolic links.
+    fn lookup(&self, path: &Path) -> Result<Found, ErrorCode> {
+        let mut prefix = PathBuf::new();
+        let mut components = path.components().peekable();
+        loop {
+            let next = components.peek();
+            match (self.preserved.get(&prefix), next) {
+                (Some(Preserved::Absent), _) => return Err(ErrorCode::NoEntry),
+                (Some(preserved), None) => return Ok(Found::Preserved(preserved.clone())),
+                (Some(Preserved::Directory(_, entries)), Some(name)) => {
+                    // entries created since are not in the snapshot
+                    if !entries.contains_key(&*name.as_os_str().to_string_lossy()) {
+                        return Err(ErrorCode::NoEntry);
+                    }
+                }
+                (Some(_), Some(_)) => return Err(ErrorCode::NotDirectory),
+                (None, None) => return Ok(Found::Live(self.live_path(path))),
+                (None, Some(_)) => {}
+            }
+            match components.next() {
+                Some(component) => prefix.push(component),
+                None => unreachable!("the last component returns"),
+            }
+        }
+    }
+
+    /// Record the state of a path in the snapshot, unless it was recorded first.
+    fn record(&mut self, path: PathBuf, preserved: Preserved) {
+        self.preserved.entry(path).or_insert(preserved);
+    }
+
+    /// Follow a path of the source directory being renamed, once both paths are preserved.
+    fn renamed(&mut self, old: &Path, new: &Path) {
+        let path = self.snapshot_path(old);
+        // a path recreated after its snapshot state moved away doesn't hold it
+        let holds_path = self.live_path(&path) == old;
+        // what was at the new path is replaced, and what is within the old path moves along
+        self.moved.retain(|live, _| !live.starts_with(new));
+        let within = self
+            .moved
+            .keys()
+            .filter(|live| live.starts_with(old))
+            .cloned()
+            .collect::<Vec<_>>();
+        for live in within {
+            let path = self.moved.remove(&live).expect("path is moved");
+            self.moved.insert(
+                new.join(live.strip_prefix(old).expect("path is within")),
+                path,
+            );
+        }
+        if holds_path {
+            self.moved.insert(new.to_path_buf(), path);
+        }
+    }
+
+    /// Apply the records of the entries of a directory in the snapshot to its listing.
+    fn list(&self, path: &Path, entries: &mut BTreeMap<String, DescriptorType>) {
+        for (child, preserved) in self.preserved.iter() {
+            if child.parent() != Some(path) {
+                continue;
+            }
+            let Some(name) = child.file_name() else {
+                continue;
+            };
+            let name = name.to_string_lossy().into_owned();
+            match preserved {
+                Preserved::Absent => entries.remove(&name),
+                Preserved::Directory(stat, _)
+                | Preserved::File(_, stat)
+                | Preserved::Symlink(_, stat) => entries.insert(name, stat.type_.clone()),
+            };
+        }
+    }
+}
+
+/// The reads of a path in the snapshot from the source directory in progress, and the changes
+/// to it waiting for them.
+#[derive(Default)]
+struct Reads {
+    count: usize,
+    waiting: Vec<wit_bindgen::FutureWriter<Result<(), ErrorCode>>>,
+}
+
+/// A read of a path in the snapshot from the source directory, which changes to the path wait
+/// for until it is dropped.
+pub(crate) struct Reading<'a> {
+    snapshot: &'a Snapshot,
+    path: PathBuf,
+}
+
+impl Drop for Reading<'_> {
+    fn drop(&mut self) {
+        let mut reads = self.snapshot.reads.borrow_mut();
+        let Some(path_reads) = reads.get_mut(&self.path) else {
+            return;
+        };
+        path_reads.count -= 1;
+        if path_reads.count == 0 {
+            // dropping the waiting writers wakes the changes
+            reads.remove(&self.path);
+        }
+    }
+}
+
+/// A point-in-time view of the source directory, from the records of paths changed since and the
+/// source directory for everything else.
+pub(crate) struct Snapshot {
+    pub(crate) source: types::Descriptor,
+    store: types::Descriptor,
+    records: RefCell<Records>,
+    reads: RefCell<BTreeMap<PathBuf, Reads>>,
+    next_copy: Cell<u64>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(source: types::Descriptor, store: types::Descriptor) -> Self {
+        Self {
+            source,
+            store,
+            records: RefCell::new(Records::default()),
+            reads: RefCell::new(BTreeMap::new()),
+            next_copy: Cell::new(0),
+        }
+    }
+
+    /// Find the snapshot state of a path, without following symbolic links.
+    pub(crate) fn lookup(&self, path: &Path) -> Result<Found, ErrorCode> {
+        self.records.borrow().lookup(path)
+    }
+
+    /// Start reading a path in the snapshot from the source directory, so it doesn't change
+    /// until the read is dropped.
+    pub(crate) fn start_read(&self, path: &Path) -> Reading<'_> {
+        self.reads
+            .borrow_mut()
+            .entry(path.to_path_buf())
+            .or_default()
+            .count += 1;
+        Reading {
+            snapshot: self,
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Wait for the reads of a path in the snapshot from the source directory in progress.
+    async fn finish_reads(&self, path: &Path) {
+        let rx = {
+            let mut reads = self.reads.borrow_mut();
+            let Some(path_reads) = reads.get_mut(path) else {
+                return;
+            };
+            let (tx, rx) = wit_future::new(|| Ok(()));
+            path_reads.waiting.push(tx);
+            rx
+        };
+        let _ = rx.await;
+    }
+
+    /// Preserve the snapshot state held by a path of the source directory before it first
+    /// changes, and wait for the reads of it from the source directory started before.
+    pub(crate) async fn preserve(&self, live: &Path) -> Result<(), ErrorCode> {
+        let path = self.records.borrow().snapshot_path(live);
+        self.record(live, &path).await?;
+        self.finish_reads(&path).await;
+        Ok(())
+    }
+
+    /// Record the snapshot state held by a path of the source directory at a path in the
+    /// snapshot, copying files aside. Directories only record their entries, which are preserved
+    /// as they change themselves.
+    async fn record(&self, live: &Path, path: &Path) -> Result<(), ErrorCode> {
+        // only a path still holding the state of a path of the snapshot changes it
+        match self.lookup(path) {
+            Ok(Found::Live(found)) if found == live => {}
+            _ => return Ok(()),
+        }
+        let preserved = match self
+            .source
+            .stat_at(types::PathFlags::empty(), relative(live))
+            .await
+        {
+            Ok(stat) => match stat.type_ {
+                DescriptorType::Directory => {
+                    let entries = self.list_source(live).await?;
+                    Preserved::Directory(
+                        stat,
+                        entries
+                            .into_iter()
+                            .map(|entry| (entry.name, entry.type_))
+                            .collect(),
+                    )
+                }
+                DescriptorType::RegularFile => Preserved::File(self.copy(live).await?, stat),
+                DescriptorType::SymbolicLink => {
+                    let target = self.source.readlink_at(relative(live)).await?;
+                    Preserved::Symlink(target, stat)
+                }
+                _ => {
+                    log(
+                        Level::Warn,
+                        "filesystem",
+                        &format!("not preserving '{}': unsupported type", live.display()),
+                    );
+                    Preserved::Absent
+                }
+            },
+            Err(ErrorCode::NoEntry) => Preserved::Absent,
+            Err(error) => return Err(error),
+        };
+        // a concurrent change may have recorded the path first, from an earlier state
+        self.records
+            .borrow_mut()
+            .record(path.to_path_buf(), preserved);
+        Ok(())
+    }
+
+    /// Follow a path of the source directory being renamed, once both paths are preserved.
+    pub(crate) fn renamed(&self, old: &Path, new: &Path) {
+        self.records.borrow_mut().renamed(old, new);
+    }
+
+    async fn list_source(&self, path: &Path) -> Result<Vec<DirectoryEntry>, ErrorCode> {
+        let dir = self
+            .source
+            .open_at(
+                types::PathFlags::empty(),
+                relative(path),
+                types::OpenFlags::DIRECTORY,
+                types::DescriptorFlags::READ,
+            )
+            .await?;
+        let (entries, result) = dir.read_directory();
+        let entries = entries.collect().await;
+        result.await?;
+        Ok(entries)
+    }
+
+    /// Copy a file of the source directory to the store, returning its name there.
+    async fn copy(&self, path: &Path) -> Result<String, ErrorCode> {
+        let name = self.next_copy.replace(self.next_copy.get() + 1).to_string();
+        let src = self
+            .source
+            .open_at(
+                types::PathFlags::empty(),
+                relative(path),
+                types::OpenFlags::empty(),
+                types::DescriptorFlags::READ,
+            )
+            .await?;
+        let dst = self
+            .store
+            .open_at(
+                types::PathFlags::empty(),
+                name.clone(),
+                types::OpenFlags::CREATE | types::OpenFlags::TRUNCATE,
+                types::DescriptorFlags::WRITE,
+            )
+            .await?;
+        let (data, read_result) = src.read_via_stream(0);
+        dst.write_via_stream(data, 0).await?;
+        read_result.await?;
+        Ok(name)
+    }
+
+    /// Open the copy of a preserved file.
+    pub(crate) async fn open_copy(&self, name: String) -> Result<types::Descriptor, ErrorCode> {
+        self.store
+            .open_at(
+                types::PathFlags::empty(),
+                name,
+                types::OpenFlags::empty(),
+                types::DescriptorFlags::READ,
+            )
+            .await
+    }
+
+    /// List a directory of the snapshot.
+    pub(crate) async fn list(&self, path: &Path) -> Result<Vec<DirectoryEntry>, ErrorCode> {
+        let mut entries = BTreeMap::new();
+        match self.lookup(path)? {
+            Found::Live(live) => {
+                let live = self.list_source(&live).await?;
+                entries.extend(live.into_iter().map(|entry| (entry.name, entry.type_)));
+            }
+            Found::Preserved(Preserved::Directory(_, listed)) => entries.extend(listed),
+            Found::Preserved(_) => return Err(ErrorCode::NotDirectory),
+        }
+        // records of entries override the listing
+        self.records.borrow().list(path, &mut entries);
+        Ok(entries
+            .into_iter()
+            .map(|(name, type_)| DirectoryEntry { type_, name })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(type_: DescriptorType) -> DescriptorStat {
+        DescriptorStat {
+            type_,
+            link_count: 1,
+            size: 0,
+            data_access_timestamp: None,
+            data_modification_timestamp: None,
+            status_change_timestamp: None,
+        }
+    }
+
+    /// A directory of regular files.
+    fn directory(names: &[&str]) -> Preserved {
+        let entries = names
+            .iter()
+            .map(|name| (name.to_string(), DescriptorType::RegularFile))
+            .collect();
+        Preserved::Directory(stat(DescriptorType::Directory), entries)
+    }
+
+    /// The path of the source directory a path in the snapshot is read from, if unchanged.
+    fn live(records: &Records, path: &str) -> Option<PathBuf> {
+        match records.lookup(Path::new(path)) {
+            Ok(Found::Live(live)) => Some(live),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn translate_replaces_the_longest_prefix() {
+        let prefixes = [
+            (PathBuf::from("a"), PathBuf::from("x")),
+            (PathBuf::from("a/b"), PathBuf::from("y")),
+        ];
+        let translate =
+            |path: &str| translate(Path::new(path), prefixes.iter().map(|(f, t)| (f, t)));
+        assert_eq!(translate("a/b/c"), Path::new("y/c"));
+        assert_eq!(translate("a/bc"), Path::new("x/bc"));
+        assert_eq!(translate("a"), Path::new("x"));
+        assert_eq!(translate("b/a"), Path::new("b/a"));
+    }
+
+    #[test]
+    fn unchanged_paths_are_live() {
+        let records = Records::default();
+        assert_eq!(live(&records, "a/b"), Some(PathBuf::from("a/b")));
+        assert_eq!(live(&records, ""), Some(PathBuf::new()));
+    }
+
+    #[test]
+    fn preserved_paths_are_found() {
+        let mut records = Records::default();
+        records.record(PathBuf::from("dir"), directory(&["file"]));
+        records.record(
+            PathBuf::from("dir/file"),
+            Preserved::File(String::from("0"), stat(DescriptorType::RegularFile)),
+        );
+        records.record(PathBuf::from("new"), Preserved::Absent);
+        assert!(matches!(
+            records.lookup(Path::new("dir/file")),
+            Ok(Found::Preserved(Preserved::File(name, _))) if name == "0"
+        ));
+        // entries created since the snapshot was taken are not in it
+        assert!(matches!(
+            records.lookup(Path::new("dir/other")),
+            Err(ErrorCode::NoEntry)
+        ));
+        assert!(matches!(
+            records.lookup(Path::new("new")),
+            Err(ErrorCode::NoEntry)
+        ));
+        assert!(matches!(
+            records.lookup(Path::new("new/file")),
+            Err(ErrorCode::NoEntry)
+        ));
+        assert!(matches!(
+            records.lookup(Path::new("dir/file/x")),
+            Err(ErrorCode::NotDirectory)
+        ));
+    }
+
+    #[test]
+    fn paths_are_recorded_once() {
+        let mut records = Records::default();
+        records.record(PathBuf::from("file"), Preserved::Absent);
+        records.record(PathBuf::from("file"), directory(&[]));
+        assert!(matches!(
+            records.lookup(Path::new("file")),
+            Err(ErrorCode::NoEntry)
+        ));
+    }
+
+    #[test]
+    fn renamed_directories_are_followed() {
+        let mut records = Records::default();
+        records.record(PathBuf::from("a"), directory(&["file"]));
+        records.record(PathBuf::from("b"), Preserved::Absent);
+        records.renamed(Path::new("a"), Path::new("b"));
+        assert_eq!(live(&records, "a/file"), Some(PathBuf::from("b/file")));
+        assert_eq!(
+            records.snapshot_path(Path::new("b/file")),
+            Path::new("a/file")
+        );
+        assert!(matches!(
+            records.lookup(Path::new("b")),
+            Err(ErrorCode::NoEntry)
+        ));
+        // moving the directory again moves its entries along
+        records.renamed(Path::new("b"), Path::new("c/d"));
+        assert_eq!(live(&records, "a/file"), Some(PathBuf::from("c/d/file")));
+    }
+
+    #[test]
+    fn renaming_back_restores_paths() {
+        let mut records = Records::default();
+        records.record(PathBuf::from("a"), directory(&["file"]));
+        records.record(PathBuf::from("b"), Preserved::Absent);
+        records.renamed(Path::new("a"), Path::new("b"));
+        records.renamed(Path::new("b"), Path::new("a"));
+        assert_eq!(live(&records, "a/file"), Some(PathBuf::from("a/file")));
+        assert_eq!(
+            records.snapshot_path(Path::new("a/file")),
+            Path::new("a/file")
+        );
+        assert_eq!(
+            records.snapshot_path(Path::new("b/file")),
+            Path::new("b/file")
+        );
+    }
+
+    #[test]
+    fn recreated_paths_do_not_hold_the_snapshot() {
+        let mut records = Records::default();
+        records.record(PathBuf::from("a"), directory(&["file"]));
+        records.record(PathBuf::from("b"), Preserved::Absent);
+        records.renamed(Path::new("a"), Path::new("b"));
+        // 'a' is created again, then renamed away
+        records.record(PathBuf::from("c"), Preserved::Absent);
+        records.renamed(Path::new("a"), Path::new("c"));
+        assert_eq!(live(&records, "a/file"), Some(PathBuf::from("b/file")));
+        assert_eq!(
+            records.snapshot_path(Path::new("c/file")),
+            Path::new("c/file")
+        );
+        assert!(matches!(
+            records.lookup(Path::new("c")),
+            Err(ErrorCode::NoEntry)
+        ));
+    }
+
+    #[test]
+    fn replacing_a_moved_path_forgets_it() {
+        let mut records = Records::default();
+        records.record(PathBuf::from("a"), directory(&["file"]));
+        records.record(PathBuf::from("b"), Preserved::Absent);
+        records.renamed(Path::new("a"), Path::new("b"));
+        // 'x' is unchanged in the snapshot, and replaces what 'a' was moved to
+        records.record(PathBuf::from("x"), directory(&["file"]));
+        records.renamed(Path::new("x"), Path::new("b"));
+        assert_eq!(live(&records, "x/file"), Some(PathBuf::from("b/file")));
+        assert_eq!(live(&records, "a/file"), Some(PathBuf::from("a/file")));
+    }
+
+    #[test]
+    fn records_override_listings() {
+        let mut records = Records::default();
+        records.record(PathBuf::from("dir/new"), Preserved::Absent);
+        records.record(PathBuf::from("dir/old"), directory(&[]));
+        records.record(PathBuf::from("dir/sub/file"), Preserved::Absent);
+        let mut entries = BTreeMap::from([
+            (String::from("new"), DescriptorType::RegularFile),
+            (String::from("sub"), DescriptorType::Directory),
+        ]);
+        records.list(Path::new("dir"), &mut entries);
+        assert_eq!(entries.keys().collect::<Vec<_>>(), ["old", "sub"]);
+        assert!(matches!(entries["old"], DescriptorType::Directory));
+    }
+}
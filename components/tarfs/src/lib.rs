@@ -0,0 +1,303 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::rc::Rc;
+
+use vfs::exports::wasi::filesystem::preopens::Guest as Preopens;
+use vfs::exports::wasi::filesystem::types::{Descriptor, ErrorCode, Filesize, Guest as Types};
+use vfs::wasi::filesystem::preopens;
+use vfs::wasi::filesystem::types;
+use vfs::{wit_future, wit_stream, Tree, VfsDescriptor};
+
+mod tar;
+
+const ARCHIVE_KEY: &str = "archive";
+const ARCHIVE_PREOPEN_KEY: &str = "archive-preopen";
+const PREOPEN_KEY: &str = "preopen";
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+/// Bytes a gzip compressed archive may decompress to, as it is held in memory.
+const MAX_GUNZIP_SIZE: usize = 256 * 1024 * 1024;
+
+thread_local! {
+    static TREE: Rc<Tree<Contents>> = wit_bindgen::block_on(index_from_config());
+}
+
+fn config(key: &str) -> Option<String> {
+    vfs::wasi::config::store::get(key).expect("Config must resolve")
+}
+
+/// Index the archive named in config, relative to the preopened directory named in config or
+/// else the first.
+async fn index_from_config() -> Rc<Tree<Contents>> {
+    let path = config(ARCHIVE_KEY).unwrap_or_else(|| panic!("Config must contain '{ARCHIVE_KEY}'"));
+
+    let dirs = preopens::get_directories();
+    let (dir, _) = match config(ARCHIVE_PREOPEN_KEY) {
+        Some(preopen) => dirs
+            .iter()
+            .find(|(_, path)| *path == preopen)
+            .unwrap_or_else(|| panic!("preopen '{preopen}' must exist")),
+        None => dirs.first().expect("Must have a preopened directory"),
+    };
+    let fd = dir
+        .open_at(
+            types::PathFlags::SYMLINK_FOLLOW,
+            path.clone(),
+            types::OpenFlags::empty(),
+            types::DescriptorFlags::READ,
+        )
+        .await
+        .unwrap_or_else(|_| panic!("archive '{path}' must exist"));
+
+    let archive = Archive::open(fd)
+        .await
+        .unwrap_or_else(|_| panic!("archive '{path}' must be readable"));
+    let tree = tar::index(&Rc::new(archive))
+        .await
+        .unwrap_or_else(|_| panic!("archive '{path}' must be a tar file"));
+    Rc::new(tree)
+}
+
+/// Read up to `len` bytes from an offset, fewer only at the end of the file.
+async fn read_at(
+    fd: &types::Descriptor,
+    offset: Filesize,
+    len: usize,
+) -> Result<Vec<u8>, ErrorCode> {
+    let (mut rx, result) = fd.read_via_stream(offset);
+    let mut data = Vec::with_capacity(len.min(CHUNK_SIZE as usize));
+    while data.len() < len {
+        let (status, buf) = rx.read(Vec::with_capacity(len - data.len())).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        data.extend(buf);
+    }
+    drop(rx);
+    if data.len() < len {
+        result.await?;
+    }
+    Ok(data)
+}
+
+/// Copy at most `limit` bytes from one stream to another, returning whether the source ended
+/// first.
+async fn pipe(
+    rx: &mut wit_bindgen::StreamReader<u8>,
+    tx: &mut wit_bindgen::StreamWriter<u8>,
+    mut limit: u64,
+) -> bool {
+    while limit > 0 {
+        let capacity = limit.min(CHUNK_SIZE) as usize;
+        let (status, buf) = rx.read(Vec::with_capacity(capacity)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => return true,
+            }
+        }
+        limit -= buf.len() as u64;
+        let remaining = tx.write_all(buf).await;
+        if !remaining.is_empty() {
+            break;
+        }
+    }
+    false
+}
+
+/// The archive the tree is indexed from.
+enum Archive {
+    /// An uncompressed archive, read from the imported preopen as files are read.
+    Tar(types::Descriptor),
+    /// A gzip compressed archive, decompressed into memory as it can only be read in order.
+    Gzip(Vec<u8>),
+}
+
+impl Archive {
+    async fn open(fd: types::Descriptor) -> Result<Self, ErrorCode> {
+        if read_at(&fd, 0, 2).await? != [0x1f, 0x8b] {
+            return Ok(Archive::Tar(fd));
+        }
+        let size = fd.stat().await?.size;
+        let size = usize::try_from(size).map_err(|_| ErrorCode::Io)?;
+        let compressed = read_at(&fd, 0, size).await?;
+        Ok(Archive::Gzip(gunzip(&compressed, MAX_GUNZIP_SIZE)?))
+    }
+
+    async fn read_at(&self, offset: Filesize, len: usize) -> Result<Vec<u8>, ErrorCode> {
+        match self {
+            Archive::Tar(fd) => read_at(fd, offset, len).await,
+            Archive::Gzip(data) => {
+                let start =
+                    usize::try_from(offset).map_or(data.len(), |offset| offset.min(data.len()));
+                let end = start.saturating_add(len).min(data.len());
+                Ok(data[start..end].to_vec())
+            }
+        }
+    }
+}
+
+/// Decompress a gzip file, as described by RFC 1952, failing if it decompresses to more than
+/// `limit` bytes.
+fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, ErrorCode> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    // only deflate is defined
+    if data.len() < 10 || data[2] != 8 {
+        return Err(ErrorCode::Io);
+    }
+    let flags = data[3];
+    let mut start = 10;
+    if flags & FEXTRA != 0 {
+        let len = data.get(start..start + 2).ok_or(ErrorCode::Io)?;
+        start += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let rest = data.get(start..).ok_or(ErrorCode::Io)?;
+            start += rest.iter().position(|b| *b == 0).ok_or(ErrorCode::Io)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        start += 2;
+    }
+    let deflated = data.get(start..).ok_or(ErrorCode::Io)?;
+    miniz_oxide::inflate::decompress_to_vec_with_limit(deflated, limit).map_err(|_| ErrorCode::Io)
+}
+
+/// The data of a file within the archive.
+pub(crate) struct Contents {
+    archive: Rc<Archive>,
+    offset: Filesize,
+    size: Filesize,
+}
+
+impl vfs::Contents for Contents {
+    fn size(&self) -> Filesize {
+        self.size
+    }
+
+    fn read(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let offset = offset.min(self.size);
+        let limit = self.size - offset;
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        match &*self.archive {
+            Archive::Tar(fd) => {
+                let (mut data, result) = fd.read_via_stream(self.offset + offset);
+                wit_bindgen::spawn(async move {
+                    let ended = pipe(&mut data, &mut data_tx, limit).await;
+                    drop(data_tx);
+                    drop(data);
+                    let result = match ended {
+                        // the archive is shorter than its index
+                        true => result.await.and(Err(ErrorCode::Io)),
+                        false => Ok(()),
+                    };
+                    let _ = result_tx.write(result).await;
+                });
+            }
+            Archive::Gzip(archive) => {
+                let start = self
+                    .offset
+                    .checked_add(offset)
+                    .and_then(|start| usize::try_from(start).ok());
+                let end = start.and_then(|start| start.checked_add(usize::try_from(limit).ok()?));
+                let data = start
+                    .zip(end)
+                    .and_then(|(start, end)| archive.get(start..end))
+                    .map(<[u8]>::to_vec)
+                    // the archive is shorter than its index
+                    .ok_or(ErrorCode::Io);
+                wit_bindgen::spawn(async move {
+                    let result = match data {
+                        Ok(data) => {
+                            data_tx.write_all(data).await;
+                            Ok(())
+                        }
+                        Err(error) => Err(error),
+                    };
+                    drop(data_tx);
+                    let _ = result_tx.write(result).await;
+                });
+            }
+        }
+        (data_rx, result_rx)
+    }
+}
+
+struct FilesystemTar {}
+
+impl Preopens for FilesystemTar {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let path = config(PREOPEN_KEY).unwrap_or(String::from("/"));
+        let fd = Descriptor::new(VfsDescriptor::root(TREE.with(Rc::clone)));
+        vec![(fd, path)]
+    }
+}
+
+impl Types for FilesystemTar {
+    type Descriptor = VfsDescriptor<Contents>;
+}
+
+vfs::export!(FilesystemTar);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip(data: &[u8], name: Option<&str>) -> Vec<u8> {
+        let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        if let Some(name) = name {
+            gzip[3] |= 0x08;
+            gzip.extend(name.as_bytes());
+            gzip.push(0);
+        }
+        gzip.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+        gzip
+    }
+
+    #[test]
+    fn gunzip_round_trips() {
+        assert_eq!(
+            gunzip(&gzip(b"archive", None), MAX_GUNZIP_SIZE).unwrap(),
+            b"archive"
+        );
+        assert_eq!(
+            gunzip(&gzip(b"archive", Some("a.tar")), MAX_GUNZIP_SIZE).unwrap(),
+            b"archive"
+        );
+    }
+
+    #[test]
+    fn gunzip_rejects_invalid_files() {
+        let mut other_method = gzip(b"archive", None);
+        other_method[2] = 7;
+        assert!(gunzip(&other_method, MAX_GUNZIP_SIZE).is_err());
+        let mut unterminated_name = gzip(b"", None);
+        unterminated_name[3] |= 0x08;
+        unterminated_name.truncate(10);
+        unterminated_name.push(b'a');
+        assert!(gunzip(&unterminated_name, MAX_GUNZIP_SIZE).is_err());
+        assert!(gunzip(&[0x1f, 0x8b], MAX_GUNZIP_SIZE).is_err());
+    }
+
+    #[test]
+    fn gunzip_is_limited() {
+        let archive = gzip(&[0; 1000], None);
+        assert_eq!(gunzip(&archive, 1000).unwrap().len(), 1000);
+        assert!(gunzip(&archive, 999).is_err());
+    }
+}
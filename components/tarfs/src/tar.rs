@@ -0,0 +1,222 @@
+//! Tar archives are a sequence of 512 byte headers, each followed by the data of its entry padded
+//! to a multiple of 512 bytes, and ended by blocks of zeros. Besides the original and ustar
+//! headers, GNU long names and pax extended headers are understood.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::{Archive, Contents};
+use vfs::exports::wasi::filesystem::types::{ErrorCode, Filesize};
+use vfs::wasi::clocks::system_clock::Instant;
+use vfs::wasi::logging::logging::{log, Level};
+use vfs::{Node, Tree};
+
+const BLOCK_SIZE: Filesize = 512;
+/// Bytes of the largest pax extended header or GNU long name, which are read whole.
+const MAX_METADATA_SIZE: Filesize = 1024 * 1024;
+
+/// Bytes of a header field up to the first NUL.
+fn string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// A number stored as octal digits, or as big-endian base-256 by GNU tar for values too large
+/// for octal.
+fn number(field: &[u8]) -> Result<u64, ErrorCode> {
+    if field[0] & 0x80 != 0 {
+        let bytes = &field[field.len().saturating_sub(8)..];
+        return Ok(bytes.iter().fold(0, |n, b| n << 8 | *b as u64));
+    }
+    let digits = string(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| ErrorCode::Io)
+}
+
+/// The records of a pax extended header, each stored as '<length> <key>=<value>\n'.
+fn pax_records(data: &[u8]) -> BTreeMap<String, String> {
+    let mut records = BTreeMap::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|b| *b == b' ') {
+        let len = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok());
+        let Some(len) = len.filter(|len| *len > space && *len <= rest.len()) else {
+            break;
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+/// A pax time, as decimal seconds with an optional fraction.
+fn pax_time(value: &str) -> Option<Instant> {
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let fraction = format!("{:0<9}", &fraction[..fraction.len().min(9)]);
+    Some(Instant {
+        seconds: seconds.parse().ok()?,
+        nanoseconds: fraction.parse().ok()?,
+    })
+}
+
+/// Index the entries of an archive into a tree. Entries that cannot be represented, such as
+/// devices and paths leaving the archive, are skipped.
+pub(crate) async fn index(archive: &Rc<Archive>) -> Result<Tree<Contents>, ErrorCode> {
+    let mut tree = Tree::new();
+    let mut offset = 0;
+    let mut pax: BTreeMap<String, String> = BTreeMap::new();
+    let mut long_name = None;
+    let mut long_link = None;
+    loop {
+        let header = archive.read_at(offset, BLOCK_SIZE as usize).await?;
+        if header.len() < BLOCK_SIZE as usize || header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let size = match pax.get("size") {
+            Some(size) => size.parse().map_err(|_| ErrorCode::Io)?,
+            None => number(&header[124..136])?,
+        };
+        let data = offset + BLOCK_SIZE;
+        offset = size
+            .div_ceil(BLOCK_SIZE)
+            .checked_mul(BLOCK_SIZE)
+            .and_then(|padded| data.checked_add(padded))
+            .ok_or(ErrorCode::Io)?;
+
+        let type_flag = header[156];
+        if matches!(type_flag, b'x' | b'L' | b'K') && size > MAX_METADATA_SIZE {
+            return Err(ErrorCode::Io);
+        }
+        match type_flag {
+            b'x' => {
+                pax = pax_records(&archive.read_at(data, size as usize).await?);
+                continue;
+            }
+            b'L' => {
+                long_name = Some(string(&archive.read_at(data, size as usize).await?));
+                continue;
+            }
+            b'K' => {
+                long_link = Some(string(&archive.read_at(data, size as usize).await?));
+                continue;
+            }
+            // global pax headers only carry defaults for fields that are not indexed
+            b'g' => continue,
+            _ => {}
+        }
+
+        let path = match (pax.remove("path"), long_name.take()) {
+            (Some(path), _) | (None, Some(path)) => path,
+            (None, None) => {
+                let name = string(&header[0..100]);
+                let prefix = string(&header[345..500]);
+                if header[257..262] == *b"ustar" && !prefix.is_empty() {
+                    format!("{prefix}/{name}")
+                } else {
+                    name
+                }
+            }
+        };
+        let link = match (pax.remove("linkpath"), long_link.take()) {
+            (Some(link), _) | (None, Some(link)) => link,
+            (None, None) => string(&header[157..257]),
+        };
+        let modified = match pax.remove("mtime") {
+            Some(mtime) => pax_time(&mtime),
+            None => Some(Instant {
+                seconds: number(&header[136..148])? as i64,
+                nanoseconds: 0,
+            }),
+        };
+        pax.clear();
+
+        let result = match type_flag {
+            b'0' | b'\0' | b'7' if path.ends_with('/') => {
+                tree.insert(&path, Node::Directory(BTreeMap::new()), modified)
+            }
+            b'0' | b'\0' | b'7' => {
+                let contents = Contents {
+                    archive: archive.clone(),
+                    offset: data,
+                    size,
+                };
+                tree.insert(&path, Node::File(contents), modified)
+            }
+            b'1' => tree.link(&path, &link),
+            b'2' => tree.insert(&path, Node::Symlink(link), modified),
+            b'5' => tree.insert(&path, Node::Directory(BTreeMap::new()), modified),
+            _ => Err(ErrorCode::Unsupported),
+        };
+        if let Err(error) = result {
+            log(
+                Level::Warn,
+                "filesystem",
+                &format!("skipping tar entry '{path}': {error:?}"),
+            );
+        }
+    }
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octal_numbers() {
+        assert_eq!(number(b"0000644\0").unwrap(), 0o644);
+        assert_eq!(number(b" 17 \0").unwrap(), 0o17);
+        assert_eq!(number(b"\0\0\0\0").unwrap(), 0);
+        assert!(number(b"0009\0").is_err());
+    }
+
+    #[test]
+    fn base_256_numbers() {
+        let mut field = [0u8; 12];
+        field[0] = 0x80;
+        field[7..].copy_from_slice(&[1, 0, 0, 0, 0]);
+        assert_eq!(number(&field).unwrap(), 1 << 32);
+    }
+
+    #[test]
+    fn pax_records_by_length() {
+        let records = pax_records(b"21 path=dir/file.txt\n16 linkpath=a=b\n");
+        assert_eq!(records.get("path").unwrap(), "dir/file.txt");
+        assert_eq!(records.get("linkpath").unwrap(), "a=b");
+    }
+
+    #[test]
+    fn pax_records_stop_at_bad_lengths() {
+        let records = pax_records(b"13 size=1234\n99 mtime=1\n");
+        assert_eq!(records.len(), 1);
+        assert!(pax_records(b"x path=a\n").is_empty());
+        assert!(pax_records(b"2 path=a\n").is_empty());
+    }
+
+    #[test]
+    fn pax_times() {
+        let time = pax_time("1700000000.5").unwrap();
+        assert_eq!((time.seconds, time.nanoseconds), (1700000000, 500_000_000));
+        let time = pax_time("12.1234567891").unwrap();
+        assert_eq!((time.seconds, time.nanoseconds), (12, 123_456_789));
+        let time = pax_time("-3").unwrap();
+        assert_eq!((time.seconds, time.nanoseconds), (-3, 0));
+    }
+
+    #[test]
+    fn rejects_bad_pax_times() {
+        assert!(pax_time("1.12345678\u{e9}").is_none());
+        assert!(pax_time("1.x").is_none());
+        assert!(pax_time("x").is_none());
+    }
+}
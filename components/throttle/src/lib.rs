@@ -0,0 +1,354 @@
+#![no_main]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasi::clocks::monotonic_clock;
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+const DESCRIPTOR_RATE_KEY: &str = "descriptor-rate";
+const GLOBAL_RATE_KEY: &str = "global-rate";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const NANOS_PER_SECOND: f64 = 1_000_000_000.0;
+
+thread_local! {
+    static GLOBAL: RefCell<Option<Bucket>> = RefCell::new(Bucket::from_config(GLOBAL_RATE_KEY));
+}
+
+/// A token bucket refilled continuously at `rate` bytes per second, holding at most one second
+/// worth of bytes.
+///
+/// Taking more bytes than are available puts the bucket into debt, which must be waited out
+/// before the bytes are transferred.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: monotonic_clock::Mark,
+}
+
+impl Bucket {
+    fn from_config(key: &str) -> Option<Self> {
+        let rate = wasi::config::store::get(key)
+            .expect("Config must resolve")
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|rate| *rate > 0.0)?;
+        Some(Self {
+            rate,
+            tokens: rate,
+            updated: monotonic_clock::now(),
+        })
+    }
+
+    /// Take bytes from the bucket, returning how long to wait before they may be transferred.
+    fn take(&mut self, bytes: usize) -> monotonic_clock::Duration {
+        let now = monotonic_clock::now();
+        let elapsed = now.saturating_sub(self.updated) as f64 / NANOS_PER_SECOND;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            0
+        } else {
+            (-self.tokens / self.rate * NANOS_PER_SECOND) as monotonic_clock::Duration
+        }
+    }
+
+    /// The most bytes to transfer at once, so pacing stays smooth at low rates.
+    fn chunk_size(&self) -> usize {
+        (self.rate as usize).clamp(1, CHUNK_SIZE)
+    }
+}
+
+/// Copy bytes from one stream to another, pacing them to the descriptor's and the global rate.
+async fn throttle(
+    rx: &mut wit_bindgen::StreamReader<u8>,
+    tx: &mut wit_bindgen::StreamWriter<u8>,
+    bucket: &RefCell<Option<Bucket>>,
+) {
+    loop {
+        let chunk_size = GLOBAL.with_borrow(|global| {
+            [global.as_ref(), bucket.borrow().as_ref()]
+                .into_iter()
+                .flatten()
+                .map(Bucket::chunk_size)
+                .min()
+                .unwrap_or(CHUNK_SIZE)
+        });
+        let (status, buf) = rx.read(Vec::with_capacity(chunk_size)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+
+        let wait = GLOBAL
+            .with_borrow_mut(|global| global.as_mut().map_or(0, |global| global.take(buf.len())))
+            .max(
+                bucket
+                    .borrow_mut()
+                    .as_mut()
+                    .map_or(0, |bucket| bucket.take(buf.len())),
+            );
+        if wait > 0 {
+            monotonic_clock::wait_for(wait).await;
+        }
+
+        let remaining = tx.write_all(buf).await;
+        if !remaining.is_empty() {
+            break;
+        }
+    }
+}
+
+struct FilesystemThrottle {}
+
+impl Preopens for FilesystemThrottle {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        preopens::get_directories()
+            .into_iter()
+            .map(|(fd, path)| {
+                let fd = Descriptor::new(ThrottleDescriptor::new(fd));
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemThrottle {
+    type Descriptor = ThrottleDescriptor;
+}
+
+struct ThrottleDescriptor {
+    fd: types::Descriptor,
+    bucket: Rc<RefCell<Option<Bucket>>>,
+}
+
+impl ThrottleDescriptor {
+    fn new(fd: types::Descriptor) -> Self {
+        Self {
+            fd,
+            bucket: Rc::new(RefCell::new(Bucket::from_config(DESCRIPTOR_RATE_KEY))),
+        }
+    }
+
+    fn throttle_write(
+        &self,
+        mut data: wit_bindgen::StreamReader<u8>,
+        write: impl FnOnce(
+            wit_bindgen::StreamReader<u8>,
+        ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let bucket = self.bucket.clone();
+        wit_bindgen::spawn(async move {
+            throttle(&mut data, &mut data_tx, &bucket).await;
+        });
+        write(data_rx)
+    }
+}
+
+impl GuestDescriptor for ThrottleDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (mut data, result) = self.fd.read_via_stream(offset);
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let bucket = self.bucket.clone();
+        wit_bindgen::spawn(async move {
+            throttle(&mut data, &mut data_tx, &bucket).await;
+            drop(data_tx);
+            let _ = result_tx.write(result.await).await;
+        });
+        (data_rx, result_rx)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.throttle_write(data, |data| self.fd.write_via_stream(data, offset))
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.throttle_write(data, |data| self.fd.append_via_stream(data))
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        self.fd.advise(offset, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        self.fd.set_size(size).await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_directory()
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.create_directory_at(path).await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat().await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat_at(path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        self.fd
+            .open_at(path_flags, path, open_flags, flags)
+            .await
+            .map(|fd| Descriptor::new(ThrottleDescriptor::new(fd)))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.remove_directory_at(path).await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        self.fd.symlink_at(old_path, new_path).await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.fd.unlink_file_at(path).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemThrottle);
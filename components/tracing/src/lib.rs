@@ -1,7 +1,8 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
+use std::borrow::Cow;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use chrono::DateTime;
 use heck::ToKebabCase;
@@ -12,20 +13,40 @@ use exports::wasi::filesystem::types::{
     DirectoryEntry, ErrorCode, Filesize, Guest as Types, MetadataHashValue, NewTimestamp,
     OpenFlags, PathFlags,
 };
+use wasi::clocks::monotonic_clock;
 use wasi::filesystem::preopens;
 use wasi::filesystem::types;
 use wasi::logging::logging::{log, Level};
 
+mod redact;
+mod sample;
+mod stats;
+
 #[macro_export]
 macro_rules! trace {
     ($dst:expr, $($arg:tt)*) => {
-        log(Level::Trace, "filesystem", &format!($dst, $($arg)*));
+        if sample::allow($dst) {
+            log(Level::Trace, "filesystem", &format!($dst, $($arg)*));
+        }
     };
     ($dst:expr) => {
-        log(Level::Trace, "filesystem", &format!($dst));
+        if sample::allow($dst) {
+            log(Level::Trace, "filesystem", &format!($dst));
+        }
     };
 }
 
+/// A path as it is logged, replacing anything that is not valid UTF-8 rather than failing.
+fn lossy(path: &Path) -> Cow<'_, str> {
+    path.to_string_lossy()
+}
+
+/// Nanoseconds according to the monotonic clock, so latencies and intervals are unaffected by
+/// changes to the system clock.
+fn now() -> u64 {
+    monotonic_clock::now()
+}
+
 struct FilesystemTracing {}
 
 impl Preopens for FilesystemTracing {
@@ -55,8 +76,19 @@ struct TracingDescriptor {
 
 impl TracingDescriptor {
     fn new(fd: types::Descriptor, path: PathBuf) -> Self {
+        stats::opened();
         Self { fd, path }
     }
+
+    fn path_at(&self, path: &str) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TracingDescriptor {
+    fn drop(&mut self) {
+        stats::closed();
+    }
 }
 
 impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
@@ -86,7 +118,11 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     ) {
         trace!("CALL wasi:filesystem/types#descriptor.read-via-stream FD={self} OFFSET={offset}");
 
-        self.fd.read_via_stream(offset)
+        stats::observe_read(
+            "read-via-stream",
+            self.path.clone(),
+            self.fd.read_via_stream(offset),
+        )
     }
 
     #[doc = "/ Return a stream for writing to a file, if available."]
@@ -109,7 +145,9 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
         trace!("CALL wasi:filesystem/types#descriptor.write-via-stream FD={self} OFFSET={offset}");
 
-        self.fd.write_via_stream(data, offset)
+        stats::observe_write("write-via-stream", self.path.clone(), data, |data| {
+            self.fd.write_via_stream(data, offset)
+        })
     }
 
     #[doc = "/ Return a stream for appending to a file, if available."]
@@ -127,7 +165,9 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
         trace!("CALL wasi:filesystem/types#descriptor.append-via-stream FD={self}",);
 
-        self.fd.append_via_stream(data)
+        stats::observe_write("append-via-stream", self.path.clone(), data, |data| {
+            self.fd.append_via_stream(data)
+        })
     }
 
     #[doc = "/ Provide file advisory information on a descriptor."]
@@ -142,7 +182,7 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     ) -> Result<(), ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.advise FD={self} OFFSET={offset} LENGTH={length} ADVICE={advice}");
 
-        self.fd.advise(offset, length, advice).await
+        stats::observe("advise", &self.path, self.fd.advise(offset, length, advice)).await
     }
 
     #[doc = "/ Synchronize the data of a file to disk."]
@@ -155,7 +195,7 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     async fn sync_data(&self) -> Result<(), ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.sync-data FD={self}");
 
-        self.fd.sync_data().await
+        stats::observe("sync-data", &self.path, self.fd.sync_data()).await
     }
 
     #[doc = "/ Get flags associated with a descriptor."]
@@ -168,7 +208,7 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.get-flags FD={self}");
 
-        self.fd.get_flags().await
+        stats::observe("get-flags", &self.path, self.fd.get_flags()).await
     }
 
     #[doc = "/ Get the dynamic type of a descriptor."]
@@ -185,7 +225,7 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.get-type FD={self}");
 
-        self.fd.get_type().await
+        stats::observe("get-type", &self.path, self.fd.get_type()).await
     }
 
     #[doc = "/ Adjust the size of an open file. If this increases the file\'s size, the"]
@@ -196,7 +236,7 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.set-size FD={self} SIZE={size}");
 
-        self.fd.set_size(size).await
+        stats::observe("set-size", &self.path, self.fd.set_size(size)).await
     }
 
     #[doc = "/ Adjust the timestamps of an open file or directory."]
@@ -212,9 +252,13 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     ) -> Result<(), ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.set-times FD={self} ACCESS-TIMESTAMP={data_access_timestamp} MODIFICATION-TIMESTAMP={data_modification_timestamp}");
 
-        self.fd
-            .set_times(data_access_timestamp, data_modification_timestamp)
-            .await
+        stats::observe(
+            "set-times",
+            &self.path,
+            self.fd
+                .set_times(data_access_timestamp, data_modification_timestamp),
+        )
+        .await
     }
 
     #[doc = "/ Read directory entries from a directory."]
@@ -238,7 +282,11 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     ) {
         trace!("CALL wasi:filesystem/types#descriptor.read-directory FD={self}");
 
-        self.fd.read_directory()
+        stats::observe_result(
+            "read-directory",
+            self.path.clone(),
+            self.fd.read_directory(),
+        )
     }
 
     #[doc = "/ Synchronize the data and metadata of a file to disk."]
@@ -251,7 +299,7 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     async fn sync(&self) -> Result<(), ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.sync FD={self}");
 
-        self.fd.sync().await
+        stats::observe("sync", &self.path, self.fd.sync()).await
     }
 
     #[doc = "/ Create a directory."]
@@ -259,9 +307,18 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     #[doc = "/ Note: This is similar to `mkdirat` in POSIX."]
     #[allow(async_fn_in_trait)]
     async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.create-directory-at FD={self} PATH={path}");
-
-        self.fd.create_directory_at(path).await
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.create-directory-at FD={self} PATH={}",
+            redact::path(&path),
+        );
+
+        let path_at = self.path_at(&path);
+        stats::observe(
+            "create-directory-at",
+            &path_at,
+            self.fd.create_directory_at(path),
+        )
+        .await
     }
 
     #[doc = "/ Return the attributes of an open file or directory."]
@@ -277,7 +334,7 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.stat FD={self}");
 
-        self.fd.stat().await
+        stats::observe("stat", &self.path, self.fd.stat()).await
     }
 
     #[doc = "/ Return the attributes of a file or directory."]
@@ -293,9 +350,13 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
         path_flags: PathFlags,
         path: String,
     ) -> Result<DescriptorStat, ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.stat-at FD={self} PATH-FLAGS={path_flags} PATH={path}");
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.stat-at FD={self} PATH-FLAGS={path_flags} PATH={}",
+            redact::path(&path),
+        );
 
-        self.fd.stat_at(path_flags, path).await
+        let path_at = self.path_at(&path);
+        stats::observe("stat-at", &path_at, self.fd.stat_at(path_flags, path)).await
     }
 
     #[doc = "/ Adjust the timestamps of a file or directory."]
@@ -312,16 +373,23 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
         data_access_timestamp: NewTimestamp,
         data_modification_timestamp: NewTimestamp,
     ) -> Result<(), ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.set-times-at FD={self} PATH-FLAGS={path_flags} PATH={path} ACCESS-TIMESTAMP={data_access_timestamp} MODIFICATION-TIMESTAMP={data_modification_timestamp}");
-
-        self.fd
-            .set_times_at(
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.set-times-at FD={self} PATH-FLAGS={path_flags} PATH={} ACCESS-TIMESTAMP={data_access_timestamp} MODIFICATION-TIMESTAMP={data_modification_timestamp}",
+            redact::path(&path),
+        );
+
+        let path_at = self.path_at(&path);
+        stats::observe(
+            "set-times-at",
+            &path_at,
+            self.fd.set_times_at(
                 path_flags,
                 path,
                 data_access_timestamp,
                 data_modification_timestamp,
-            )
-            .await
+            ),
+        )
+        .await
     }
 
     #[doc = "/ Create a hard link."]
@@ -340,11 +408,20 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
         new_path: String,
     ) -> Result<(), ErrorCode> {
         let new_descriptor: &Self = new_descriptor.get();
-        trace!("CALL wasi:filesystem/types#descriptor.link-at FD={self} OLD-PATH-FLAGS={old_path_flags} OLD-PATH={old_path} NEW-DESCRIPTOR={new_descriptor} NEW-PATH={new_path}");
-
-        self.fd
-            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
-            .await
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.link-at FD={self} OLD-PATH-FLAGS={old_path_flags} OLD-PATH={} NEW-DESCRIPTOR={new_descriptor} NEW-PATH={}",
+            redact::path(&old_path),
+            redact::path(&new_path),
+        );
+
+        let path_at = self.path_at(&old_path);
+        stats::observe(
+            "link-at",
+            &path_at,
+            self.fd
+                .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path),
+        )
+        .await
     }
 
     #[doc = "/ Open a file or directory."]
@@ -367,15 +444,19 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
         open_flags: OpenFlags,
         flags: DescriptorFlags,
     ) -> Result<Descriptor, ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.open-at FD={self} PATH-FLAGS={path_flags} PATH={path} OPEN-FLAGS={open_flags} FLAGS={flags}");
-
-        self.fd
-            .open_at(path_flags, path.clone(), open_flags, flags)
-            .await
-            .map(|fd| {
-                let path = self.path.clone().join(path);
-                Descriptor::new(TracingDescriptor::new(fd, path))
-            })
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.open-at FD={self} PATH-FLAGS={path_flags} PATH={} OPEN-FLAGS={open_flags} FLAGS={flags}",
+            redact::path(&path),
+        );
+
+        let path_at = self.path_at(&path);
+        stats::observe(
+            "open-at",
+            &path_at,
+            self.fd.open_at(path_flags, path, open_flags, flags),
+        )
+        .await
+        .map(|fd| Descriptor::new(TracingDescriptor::new(fd, path_at)))
     }
 
     #[doc = "/ Read the contents of a symbolic link."]
@@ -386,9 +467,20 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     #[doc = "/ Note: This is similar to `readlinkat` in POSIX."]
     #[allow(async_fn_in_trait)]
     async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.readlink-at FD={self} PATH={path}");
-
-        self.fd.readlink_at(path).await
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.readlink-at FD={self} PATH={}",
+            redact::path(&path),
+        );
+
+        let path_at = self.path_at(&path);
+        let target = stats::observe("readlink-at", &path_at, self.fd.readlink_at(path)).await;
+        if let Ok(target) = &target {
+            trace!(
+                "RETURN wasi:filesystem/types#descriptor.readlink-at FD={self} TARGET={}",
+                redact::path(target),
+            );
+        }
+        target
     }
 
     #[doc = "/ Remove a directory."]
@@ -398,9 +490,18 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     #[doc = "/ Note: This is similar to `unlinkat(fd, path, AT_REMOVEDIR)` in POSIX."]
     #[allow(async_fn_in_trait)]
     async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.remove-directory-at FD={self} PATH={path}");
-
-        self.fd.remove_directory_at(path).await
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.remove-directory-at FD={self} PATH={}",
+            redact::path(&path),
+        );
+
+        let path_at = self.path_at(&path);
+        stats::observe(
+            "remove-directory-at",
+            &path_at,
+            self.fd.remove_directory_at(path),
+        )
+        .await
     }
 
     #[doc = "/ Rename a filesystem object."]
@@ -414,11 +515,19 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
         new_path: String,
     ) -> Result<(), ErrorCode> {
         let new_descriptor: &Self = new_descriptor.get();
-        trace!("CALL wasi:filesystem/types#descriptor.rename-at FD={self} OLD-PATH={old_path} NEW-DESCRIPTOR={new_descriptor} NEW-PATH={new_path}");
-
-        self.fd
-            .rename_at(old_path, &new_descriptor.fd, new_path)
-            .await
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.rename-at FD={self} OLD-PATH={} NEW-DESCRIPTOR={new_descriptor} NEW-PATH={}",
+            redact::path(&old_path),
+            redact::path(&new_path),
+        );
+
+        let path_at = self.path_at(&old_path);
+        stats::observe(
+            "rename-at",
+            &path_at,
+            self.fd.rename_at(old_path, &new_descriptor.fd, new_path),
+        )
+        .await
     }
 
     #[doc = "/ Create a symbolic link (also known as a \"symlink\")."]
@@ -429,9 +538,19 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     #[doc = "/ Note: This is similar to `symlinkat` in POSIX."]
     #[allow(async_fn_in_trait)]
     async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.symlink-at FD={self} OLD-PATH={old_path} NEW-PATH={new_path}");
-
-        self.fd.symlink_at(old_path, new_path).await
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.symlink-at FD={self} OLD-PATH={} NEW-PATH={}",
+            redact::path(&old_path),
+            redact::path(&new_path),
+        );
+
+        let path_at = self.path_at(&new_path);
+        stats::observe(
+            "symlink-at",
+            &path_at,
+            self.fd.symlink_at(old_path, new_path),
+        )
+        .await
     }
 
     #[doc = "/ Unlink a filesystem object that is not a directory."]
@@ -445,9 +564,13 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     #[doc = "/ POSIX-specified `error-code::not-permitted`."]
     #[allow(async_fn_in_trait)]
     async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.unlink-file-at FD={self} PATH={path}");
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.unlink-file-at FD={self} PATH={}",
+            redact::path(&path),
+        );
 
-        self.fd.unlink_file_at(path).await
+        let path_at = self.path_at(&path);
+        stats::observe("unlink-file-at", &path_at, self.fd.unlink_file_at(path)).await
     }
 
     #[doc = "/ Test whether two descriptors refer to the same filesystem object."]
@@ -461,6 +584,8 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
         let other: &Self = other.get();
         trace!("CALL wasi:filesystem/types#descriptor.is-same-object FD={self} OTHER={other}");
 
+        stats::called("is-same-object", &self.path);
+
         self.fd.is_same_object(&other.fd).await
     }
 
@@ -487,7 +612,7 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
     async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
         trace!("CALL wasi:filesystem/types#descriptor.metadata-hash FD={self}");
 
-        self.fd.metadata_hash().await
+        stats::observe("metadata-hash", &self.path, self.fd.metadata_hash()).await
     }
 
     #[doc = "/ Return a hash of the metadata associated with a filesystem object referred"]
@@ -500,26 +625,37 @@ impl exports::wasi::filesystem::types::GuestDescriptor for TracingDescriptor {
         path_flags: PathFlags,
         path: String,
     ) -> Result<MetadataHashValue, ErrorCode> {
-        trace!("CALL wasi:filesystem/types#descriptor.metadata-hash-at FD={self} PATH-FLAGS={path_flags} PATH={path}");
-
-        self.fd.metadata_hash_at(path_flags, path).await
+        trace!(
+            "CALL wasi:filesystem/types#descriptor.metadata-hash-at FD={self} PATH-FLAGS={path_flags} PATH={}",
+            redact::path(&path),
+        );
+
+        let path_at = self.path_at(&path);
+        stats::observe(
+            "metadata-hash-at",
+            &path_at,
+            self.fd.metadata_hash_at(path_flags, path),
+        )
+        .await
     }
 }
 
 impl Display for TracingDescriptor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(
-            &self
-                .path
-                .to_str()
-                .expect("path contains invalid unicode characters"),
-        )
+        f.write_str(&redact::path(&lossy(&self.path)))
     }
 }
 
 impl Display for types::Advice {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.to_string().to_kebab_case())
+        match self {
+            types::Advice::Normal => f.write_str("normal"),
+            types::Advice::Sequential => f.write_str("sequential"),
+            types::Advice::Random => f.write_str("random"),
+            types::Advice::WillNeed => f.write_str("will-need"),
+            types::Advice::DontNeed => f.write_str("dont-need"),
+            types::Advice::NoReuse => f.write_str("no-reuse"),
+        }
     }
 }
 
@@ -567,11 +703,11 @@ impl Display for types::NewTimestamp {
 
 impl Display for types::Instant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let date = DateTime::from_timestamp(self.seconds as i64, self.nanoseconds)
-            .unwrap()
-            .format("%Y-%m-%d %H:%M:%S.%3fZ");
-
-        f.write_fmt(format_args!("{date}"))
+        match DateTime::from_timestamp(self.seconds, self.nanoseconds) {
+            Some(date) => f.write_fmt(format_args!("{}", date.format("%Y-%m-%d %H:%M:%S.%3fZ"))),
+            // not representable as a date, fall back to the raw value rather than trapping
+            None => f.write_fmt(format_args!("{}s+{}ns", self.seconds, self.nanoseconds)),
+        }
     }
 }
 
@@ -583,3 +719,195 @@ wit_bindgen::generate!({
 });
 
 export!(FilesystemTracing);
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::ffi::OsStr;
+    #[cfg(unix)]
+    use std::os::unix::ffi::OsStrExt;
+    #[cfg(unix)]
+    use std::path::Path;
+
+    use proptest::prelude::*;
+
+    #[cfg(unix)]
+    use super::lossy;
+    use super::types::{Advice, DescriptorFlags, Instant, NewTimestamp, OpenFlags, PathFlags};
+
+    fn advice() -> impl Strategy<Value = Advice> {
+        prop_oneof![
+            Just(Advice::Normal),
+            Just(Advice::Sequential),
+            Just(Advice::Random),
+            Just(Advice::WillNeed),
+            Just(Advice::DontNeed),
+            Just(Advice::NoReuse),
+        ]
+    }
+
+    fn instant() -> impl Strategy<Value = Instant> {
+        (any::<i64>(), any::<u32>()).prop_map(|(seconds, nanoseconds)| Instant {
+            seconds,
+            nanoseconds,
+        })
+    }
+
+    /// Instants too far from the epoch to be represented as a date.
+    fn out_of_range_instant() -> impl Strategy<Value = Instant> {
+        let seconds = prop_oneof![
+            10_000_000_000_000i64..=i64::MAX,
+            i64::MIN..=-10_000_000_000_000i64,
+        ];
+        (seconds, any::<u32>()).prop_map(|(seconds, nanoseconds)| Instant {
+            seconds,
+            nanoseconds,
+        })
+    }
+
+    fn new_timestamp() -> impl Strategy<Value = NewTimestamp> {
+        prop_oneof![
+            Just(NewTimestamp::NoChange),
+            Just(NewTimestamp::Now),
+            instant().prop_map(NewTimestamp::Timestamp),
+        ]
+    }
+
+    fn assert_flag_names(display: &str, known: &[&str], count: usize) {
+        let names = display
+            .strip_prefix('(')
+            .and_then(|names| names.strip_suffix(')'))
+            .expect("flags are wrapped in parens");
+        let names: Vec<&str> = names.split('|').filter(|name| !name.is_empty()).collect();
+        assert_eq!(names.len(), count, "{display}");
+        for name in names {
+            assert!(known.contains(&name), "unknown flag '{name}' in {display}");
+        }
+    }
+
+    #[test]
+    fn advice_names() {
+        assert_eq!(Advice::Normal.to_string(), "normal");
+        assert_eq!(Advice::Sequential.to_string(), "sequential");
+        assert_eq!(Advice::Random.to_string(), "random");
+        assert_eq!(Advice::WillNeed.to_string(), "will-need");
+        assert_eq!(Advice::DontNeed.to_string(), "dont-need");
+        assert_eq!(Advice::NoReuse.to_string(), "no-reuse");
+    }
+
+    #[test]
+    fn instant_in_range() {
+        let instant = Instant {
+            seconds: 1_700_000_000,
+            nanoseconds: 123_456_789,
+        };
+        assert_eq!(instant.to_string(), "2023-11-14 22:13:20.123Z");
+    }
+
+    #[test]
+    fn instant_out_of_range() {
+        let instant = Instant {
+            seconds: i64::MAX,
+            nanoseconds: u32::MAX,
+        };
+        assert_eq!(instant.to_string(), format!("{}s+{}ns", i64::MAX, u32::MAX));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_path() {
+        let path = Path::new(OsStr::from_bytes(b"dir/\xffname"));
+        assert_eq!(lossy(path), "dir/\u{fffd}name");
+    }
+
+    #[test]
+    fn new_timestamp_names() {
+        assert_eq!(NewTimestamp::NoChange.to_string(), "no-change");
+        assert_eq!(NewTimestamp::Now.to_string(), "now");
+    }
+
+    #[test]
+    fn empty_flags() {
+        assert_eq!(DescriptorFlags::empty().to_string(), "()");
+        assert_eq!(OpenFlags::empty().to_string(), "()");
+        assert_eq!(PathFlags::empty().to_string(), "()");
+    }
+
+    proptest! {
+        #[test]
+        fn advice_is_kebab_case(advice in advice()) {
+            let display = advice.to_string();
+            prop_assert!(!display.is_empty());
+            prop_assert!(display.chars().all(|c| c.is_ascii_lowercase() || c == '-'));
+        }
+
+        #[test]
+        fn descriptor_flags_never_panic(bits in any::<u8>()) {
+            let flags = DescriptorFlags::from_bits_retain(bits);
+            let known = [
+                "read",
+                "write",
+                "file-integrity-sync",
+                "data-integrity-sync",
+                "requested-write-sync",
+                "mutate-directory",
+            ];
+            let count = flags.intersection(DescriptorFlags::all()).bits().count_ones();
+            assert_flag_names(&flags.to_string(), &known, count as usize);
+        }
+
+        #[test]
+        fn open_flags_never_panic(bits in any::<u8>()) {
+            let flags = OpenFlags::from_bits_retain(bits);
+            let known = ["create", "directory", "exclusive", "truncate"];
+            let count = flags.intersection(OpenFlags::all()).bits().count_ones();
+            assert_flag_names(&flags.to_string(), &known, count as usize);
+        }
+
+        #[test]
+        fn path_flags_never_panic(bits in any::<u8>()) {
+            let flags = PathFlags::from_bits_retain(bits);
+            let known = ["symlink-follow"];
+            let count = flags.intersection(PathFlags::all()).bits().count_ones();
+            assert_flag_names(&flags.to_string(), &known, count as usize);
+        }
+
+        #[test]
+        fn instant_never_panics(instant in instant()) {
+            let display = instant.to_string();
+            prop_assert!(!display.is_empty());
+            if !display.ends_with('Z') {
+                prop_assert_eq!(
+                    display,
+                    format!("{}s+{}ns", instant.seconds, instant.nanoseconds)
+                );
+            }
+        }
+
+        #[test]
+        fn out_of_range_instant_falls_back(instant in out_of_range_instant()) {
+            prop_assert_eq!(
+                instant.to_string(),
+                format!("{}s+{}ns", instant.seconds, instant.nanoseconds)
+            );
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn paths_render_lossily(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let path = Path::new(OsStr::from_bytes(&bytes));
+            prop_assert_eq!(lossy(path), String::from_utf8_lossy(&bytes));
+        }
+
+        #[test]
+        fn new_timestamp_never_panics(timestamp in new_timestamp()) {
+            let display = timestamp.to_string();
+            match timestamp {
+                NewTimestamp::Timestamp(instant) => {
+                    prop_assert_eq!(display, format!("timestamp<{instant}>"));
+                }
+                _ => prop_assert!(!display.is_empty()),
+            }
+        }
+    }
+}
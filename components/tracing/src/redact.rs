@@ -0,0 +1,148 @@
+use std::borrow::Cow;
+
+use common::glob;
+use hmac::{Hmac, Mac};
+use regex_lite::{Captures, Regex};
+use sha2::Sha256;
+
+use crate::wasi::config::store;
+use crate::wasi::logging::logging::{log, Level};
+
+const REDACT_KEY_PREFIX: &str = "redact.";
+const REDACT_SECRET_KEY: &str = "redact-secret";
+
+const MASK: &str = "***";
+
+thread_local! {
+    static RULES: Vec<Rule> = Rule::from_config();
+}
+
+enum Action {
+    Mask,
+    /// Replaces values with their HMAC-SHA256 keyed by the 'redact-secret' key, so hashes can be
+    /// correlated but not reversed by guessing values without the secret.
+    Hash(Hmac<Sha256>),
+}
+
+impl Action {
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Action::Mask => String::from(MASK),
+            Action::Hash(mac) => {
+                let mut mac = mac.clone();
+                mac.update(value.as_bytes());
+                let tag = mac.finalize().into_bytes();
+                let hash = tag[..8]
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>();
+                format!("#{hash}")
+            }
+        }
+    }
+}
+
+enum Matcher {
+    /// Replaces every match within the whole path.
+    Regex(Regex),
+    /// Replaces each path component that matches entirely.
+    Glob(Regex),
+}
+
+/// A redaction rule, configured by a wasi:config key prefixed with 'redact.' and a value of the
+/// form '<regex|glob>:<mask|hash>:<pattern>'.
+struct Rule {
+    matcher: Matcher,
+    action: Action,
+}
+
+impl Rule {
+    fn from_config() -> Vec<Self> {
+        let mut entries = store::get_all().unwrap_or_default();
+        entries.sort();
+        let secret = entries
+            .iter()
+            .find(|(key, _value)| key == REDACT_SECRET_KEY)
+            .map(|(_key, secret)| {
+                <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes())
+                    .expect("HMAC accepts any key")
+            });
+        entries
+            .iter()
+            .filter(|(key, _value)| key.starts_with(REDACT_KEY_PREFIX))
+            .filter_map(|(key, value)| match Rule::parse(value, secret.as_ref()) {
+                Ok(rule) => Some(rule),
+                Err(message) => {
+                    log(
+                        Level::Warn,
+                        "filesystem",
+                        &format!("ignoring redaction rule '{key}': {message}"),
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn parse(value: &str, secret: Option<&Hmac<Sha256>>) -> Result<Self, String> {
+        let mut parts = value.splitn(3, ':');
+        let (Some(kind), Some(action), Some(pattern)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(String::from(
+                "expected '<regex|glob>:<mask|hash>:<pattern>'",
+            ));
+        };
+
+        let action = match action {
+            "mask" => Action::Mask,
+            "hash" => match secret {
+                Some(secret) => Action::Hash(secret.clone()),
+                None => return Err(format!("'hash' requires '{REDACT_SECRET_KEY}' to be set")),
+            },
+            _ => return Err(format!("unknown action '{action}'")),
+        };
+        let matcher = match kind {
+            "regex" => Matcher::Regex(Regex::new(pattern).map_err(|e| e.to_string())?),
+            "glob" => {
+                Matcher::Glob(Regex::new(&glob::to_regex(pattern)).map_err(|e| e.to_string())?)
+            }
+            _ => return Err(format!("unknown kind '{kind}'")),
+        };
+
+        Ok(Self { matcher, action })
+    }
+
+    fn apply(&self, path: &str) -> String {
+        match &self.matcher {
+            Matcher::Regex(regex) => regex
+                .replace_all(path, |captures: &Captures| self.action.apply(&captures[0]))
+                .into_owned(),
+            Matcher::Glob(glob) => path
+                .split('/')
+                .map(|component| {
+                    if !component.is_empty() && glob.is_match(component) {
+                        Cow::Owned(self.action.apply(component))
+                    } else {
+                        Cow::Borrowed(component)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/"),
+        }
+    }
+}
+
+/// Apply the configured redaction rules to a path, symlink target or similar value before it is
+/// logged.
+pub(crate) fn path(path: &str) -> Cow<'_, str> {
+    RULES.with(|rules| {
+        if rules.is_empty() {
+            return Cow::Borrowed(path);
+        }
+        let mut redacted = String::from(path);
+        for rule in rules {
+            redacted = rule.apply(&redacted);
+        }
+        Cow::Owned(redacted)
+    })
+}
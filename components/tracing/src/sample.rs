@@ -0,0 +1,239 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use common::random::Xorshift;
+
+use crate::now;
+use crate::wasi::clocks::monotonic_clock;
+use crate::wasi::config::store;
+use crate::wasi::logging::logging::{log, Level};
+
+const SAMPLE_RATE_KEY: &str = "sample-rate";
+const RATE_LIMIT_KEY: &str = "rate-limit";
+const RATE_LIMIT_BURST_KEY: &str = "rate-limit-burst";
+const SUPPRESSED_INTERVAL_KEY: &str = "suppressed-interval";
+
+const NANOS_PER_SECOND: f64 = 1_000_000_000.0;
+
+thread_local! {
+    static SAMPLER: RefCell<Option<Sampler>> = RefCell::new(Sampler::from_config());
+}
+
+/// A token bucket refilled continuously at `rate` tokens per second, holding at most `burst`.
+struct Bucket {
+    tokens: f64,
+    updated: u64,
+}
+
+/// Decides which trace events are logged, enabled by setting 'sample-rate' to a probability
+/// between 0 and 1 and/or 'rate-limit' to a maximum number of events per second for each
+/// operation.
+///
+/// Suppressed events are counted per operation and reported 'suppressed-interval' seconds,
+/// defaulting to 10, after the first of them, so nothing is silently lost.
+struct Sampler {
+    sample_rate: f64,
+    rate_limit: Option<(f64, f64)>,
+    buckets: BTreeMap<&'static str, Bucket>,
+    suppressed: BTreeMap<&'static str, u64>,
+    interval: u64,
+    /// Whether a report of the suppressed events is pending.
+    reporting: bool,
+    random: Xorshift,
+}
+
+impl Sampler {
+    fn from_config() -> Option<Self> {
+        let sample_rate = config(SAMPLE_RATE_KEY)
+            .and_then(|value| value.parse::<f64>().ok())
+            .map(|rate| rate.clamp(0.0, 1.0));
+        let rate_limit = config(RATE_LIMIT_KEY)
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|rate| *rate > 0.0);
+        if sample_rate.is_none() && rate_limit.is_none() {
+            return None;
+        }
+
+        let burst = config(RATE_LIMIT_BURST_KEY)
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|burst| *burst >= 1.0);
+        let interval = config(SUPPRESSED_INTERVAL_KEY)
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|interval| *interval > 0)
+            .unwrap_or(10);
+
+        Some(Self::new(
+            sample_rate.unwrap_or(1.0),
+            rate_limit.map(|rate| (rate, burst.unwrap_or(rate.max(1.0)))),
+            interval.saturating_mul(1_000_000_000),
+            now(),
+        ))
+    }
+
+    fn new(sample_rate: f64, rate_limit: Option<(f64, f64)>, interval: u64, seed: u64) -> Self {
+        Self {
+            sample_rate,
+            rate_limit,
+            buckets: BTreeMap::new(),
+            suppressed: BTreeMap::new(),
+            interval,
+            reporting: false,
+            random: Xorshift::new(seed),
+        }
+    }
+
+    /// Whether an event is logged, counting it as suppressed otherwise.
+    fn allow(&mut self, operation: &'static str, now: u64) -> bool {
+        let allowed = self.sample() && self.take(operation, now);
+        if !allowed {
+            *self.suppressed.entry(operation).or_default() += 1;
+        }
+        allowed
+    }
+
+    fn sample(&mut self) -> bool {
+        self.sample_rate >= 1.0 || self.random.next_f64() < self.sample_rate
+    }
+
+    fn take(&mut self, operation: &'static str, now: u64) -> bool {
+        let Some((rate, burst)) = self.rate_limit else {
+            return true;
+        };
+        let bucket = self.buckets.entry(operation).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_sub(bucket.updated) as f64 / NANOS_PER_SECOND;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Schedule a report of the suppressed events, unless one is already pending, returning
+    /// when it is due.
+    fn schedule_report(&mut self) -> Option<u64> {
+        if self.reporting || self.suppressed.is_empty() {
+            return None;
+        }
+        self.reporting = true;
+        Some(self.interval)
+    }
+
+    /// Take the suppressed event counts to report.
+    fn report(&mut self) -> BTreeMap<&'static str, u64> {
+        self.reporting = false;
+        std::mem::take(&mut self.suppressed)
+    }
+}
+
+fn config(key: &str) -> Option<String> {
+    store::get(key).ok().flatten()
+}
+
+/// The operation a trace message is for, the second word of its format string, e.g.
+/// 'wasi:filesystem/types#descriptor.stat' for 'CALL wasi:filesystem/types#descriptor.stat FD={self}'.
+fn operation(message: &'static str) -> &'static str {
+    message.split(' ').nth(1).unwrap_or(message)
+}
+
+/// Whether a trace event should be logged, given the format string of its message.
+pub(crate) fn allow(message: &'static str) -> bool {
+    let (allowed, report) = SAMPLER.with_borrow_mut(|sampler| match sampler {
+        Some(sampler) => {
+            let allowed = sampler.allow(operation(message), now());
+            (allowed, sampler.schedule_report())
+        }
+        None => (true, None),
+    });
+    // reported from a task of its own, so counts are logged even if no further event arrives
+    if let Some(delay) = report {
+        wit_bindgen::spawn(async move {
+            monotonic_clock::wait_for(delay).await;
+            report_suppressed();
+        });
+    }
+    allowed
+}
+
+fn report_suppressed() {
+    let suppressed = SAMPLER.with_borrow_mut(|sampler| match sampler {
+        Some(sampler) => sampler.report(),
+        None => BTreeMap::new(),
+    });
+    for (operation, events) in suppressed {
+        log(
+            Level::Trace,
+            "filesystem",
+            &format!("SUPPRESSED {operation} EVENTS={events}"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{operation, Sampler};
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn operation_is_second_word() {
+        assert_eq!(
+            operation("CALL wasi:filesystem/types#descriptor.stat FD={self}"),
+            "wasi:filesystem/types#descriptor.stat"
+        );
+        assert_eq!(operation("single"), "single");
+    }
+
+    #[test]
+    fn rate_limit_allows_burst_then_refills() {
+        let mut sampler = Sampler::new(1.0, Some((1.0, 2.0)), 10 * SECOND, 1);
+        assert!(sampler.allow("stat", 0));
+        assert!(sampler.allow("stat", 0));
+        assert!(!sampler.allow("stat", 0));
+        // operations have buckets of their own
+        assert!(sampler.allow("open-at", 0));
+        assert!(sampler.allow("stat", SECOND));
+        assert!(!sampler.allow("stat", SECOND));
+    }
+
+    #[test]
+    fn sample_rate_zero_suppresses_everything() {
+        let mut sampler = Sampler::new(0.0, None, 10 * SECOND, 1);
+        for _ in 0..10 {
+            assert!(!sampler.allow("stat", 0));
+        }
+        assert_eq!(sampler.report().get("stat"), Some(&10));
+    }
+
+    #[test]
+    fn sample_rate_is_approximate() {
+        let mut sampler = Sampler::new(0.25, None, 10 * SECOND, 1);
+        let allowed = (0..10_000).filter(|_| sampler.allow("stat", 0)).count();
+        assert!((2_000..3_000).contains(&allowed), "{allowed}");
+    }
+
+    #[test]
+    fn report_is_scheduled_once_per_interval() {
+        let mut sampler = Sampler::new(1.0, Some((1.0, 1.0)), 10 * SECOND, 1);
+        assert!(sampler.allow("stat", 0));
+        // nothing suppressed, nothing to report
+        assert_eq!(sampler.schedule_report(), None);
+
+        assert!(!sampler.allow("stat", 0));
+        assert_eq!(sampler.schedule_report(), Some(10 * SECOND));
+        assert!(!sampler.allow("stat", 0));
+        assert_eq!(sampler.schedule_report(), None);
+
+        let suppressed = sampler.report();
+        assert_eq!(suppressed.get("stat"), Some(&2));
+        assert!(sampler.report().is_empty());
+
+        assert!(!sampler.allow("stat", 0));
+        assert_eq!(sampler.schedule_report(), Some(10 * SECOND));
+    }
+}
@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+
+use heck::ToKebabCase;
+
+use crate::redact;
+use crate::wasi::config::store;
+use crate::wasi::filesystem::types::ErrorCode;
+use crate::wasi::logging::logging::{log, Level};
+use crate::{now, wit_future, wit_stream};
+
+const STATS_KEY: &str = "stats";
+const STATS_INTERVAL_KEY: &str = "stats-interval";
+const STATS_DEPTH_KEY: &str = "stats-depth";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    static STATS: RefCell<Option<Stats>> = RefCell::new(Stats::from_config());
+}
+
+#[derive(Default)]
+struct Counters {
+    calls: u64,
+    errors: BTreeMap<String, u64>,
+    bytes: u64,
+    latency: u64,
+}
+
+impl Counters {
+    fn record(&mut self, error: Option<&ErrorCode>, bytes: u64, latency: u64) {
+        self.calls += 1;
+        if let Some(error) = error {
+            *self.errors.entry(error_name(error)).or_default() += 1;
+        }
+        self.bytes += bytes;
+        self.latency += latency;
+    }
+
+    fn summary(&self) -> String {
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(name, count)| format!("{name}={count}"))
+            .collect();
+        format!(
+            "CALLS={} ERRORS=({}) BYTES={} LATENCY={:.3}ms",
+            self.calls,
+            errors.join("|"),
+            self.bytes,
+            self.latency as f64 / 1_000_000.0,
+        )
+    }
+}
+
+/// Counters aggregated per operation and per path prefix, enabled by setting the 'stats' config
+/// key to 'true'.
+///
+/// A summary is logged every 'stats-interval' seconds, if set, and once the last open descriptor
+/// is dropped. Paths are grouped by their first 'stats-depth' components, defaulting to 1.
+struct Stats {
+    operations: BTreeMap<&'static str, Counters>,
+    prefixes: BTreeMap<String, Counters>,
+    depth: usize,
+    interval: Option<u64>,
+    last_summary: u64,
+    descriptors: usize,
+}
+
+impl Stats {
+    fn from_config() -> Option<Self> {
+        let enabled = config(STATS_KEY)
+            .map(|value| value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+
+        Some(Self {
+            operations: BTreeMap::new(),
+            prefixes: BTreeMap::new(),
+            depth: config(STATS_DEPTH_KEY)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1),
+            interval: config(STATS_INTERVAL_KEY)
+                .and_then(|value| value.parse().ok())
+                .filter(|interval| *interval > 0)
+                .map(|interval: u64| interval.saturating_mul(1_000_000_000)),
+            last_summary: now(),
+            descriptors: 0,
+        })
+    }
+
+    fn record(
+        &mut self,
+        operation: &'static str,
+        path: &Path,
+        error: Option<&ErrorCode>,
+        bytes: u64,
+        latency: u64,
+    ) {
+        self.operations
+            .entry(operation)
+            .or_default()
+            .record(error, bytes, latency);
+        self.prefixes
+            .entry(prefix(path, self.depth))
+            .or_default()
+            .record(error, bytes, latency);
+
+        if let Some(interval) = self.interval {
+            if now().saturating_sub(self.last_summary) >= interval {
+                self.summarize();
+            }
+        }
+    }
+
+    fn summarize(&mut self) {
+        for (operation, counters) in &self.operations {
+            log(
+                Level::Info,
+                "filesystem",
+                &format!("STATS OPERATION={operation} {}", counters.summary()),
+            );
+        }
+        for (prefix, counters) in &self.prefixes {
+            log(
+                Level::Info,
+                "filesystem",
+                &format!("STATS PATH={} {}", redact::path(prefix), counters.summary()),
+            );
+        }
+        self.last_summary = now();
+    }
+}
+
+fn config(key: &str) -> Option<String> {
+    store::get(key).ok().flatten()
+}
+
+fn error_name(error: &ErrorCode) -> String {
+    let name = format!("{error:?}");
+    let name = name.trim_start_matches("ErrorCode::");
+    name.split('(').next().unwrap_or_default().to_kebab_case()
+}
+
+fn prefix(path: &Path, depth: usize) -> String {
+    let mut prefix = PathBuf::new();
+    let mut normal = 0;
+    for component in path.components() {
+        if let Component::Normal(_) = component {
+            if normal == depth {
+                break;
+            }
+            normal += 1;
+        }
+        prefix.push(component);
+    }
+    prefix.to_string_lossy().into_owned()
+}
+
+fn enabled() -> bool {
+    STATS.with_borrow(|stats| stats.is_some())
+}
+
+fn record(
+    operation: &'static str,
+    path: &Path,
+    error: Option<&ErrorCode>,
+    bytes: u64,
+    started: u64,
+) {
+    STATS.with_borrow_mut(|stats| {
+        if let Some(stats) = stats {
+            let latency = now().saturating_sub(started);
+            stats.record(operation, path, error, bytes, latency);
+        }
+    });
+}
+
+/// Track a newly created descriptor.
+pub(crate) fn opened() {
+    STATS.with_borrow_mut(|stats| {
+        if let Some(stats) = stats {
+            stats.descriptors += 1;
+        }
+    });
+}
+
+/// Track a dropped descriptor, logging a summary once none remain open.
+pub(crate) fn closed() {
+    STATS.with_borrow_mut(|stats| {
+        if let Some(stats) = stats {
+            stats.descriptors = stats.descriptors.saturating_sub(1);
+            if stats.descriptors == 0 {
+                stats.summarize();
+            }
+        }
+    });
+}
+
+/// Count a call that cannot fail.
+pub(crate) fn called(operation: &'static str, path: &Path) {
+    if enabled() {
+        record(operation, path, None, 0, now());
+    }
+}
+
+/// Count a call, its latency and its error, if any.
+pub(crate) async fn observe<T>(
+    operation: &'static str,
+    path: &Path,
+    call: impl Future<Output = Result<T, ErrorCode>>,
+) -> Result<T, ErrorCode> {
+    if !enabled() {
+        return call.await;
+    }
+
+    let started = now();
+    let result = call.await;
+    record(operation, path, result.as_ref().err(), 0, started);
+    result
+}
+
+/// Count the bytes read from a stream, and the outcome of the read once it completes.
+pub(crate) fn observe_read(
+    operation: &'static str,
+    path: PathBuf,
+    (mut data, result): (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ),
+) -> (
+    wit_bindgen::StreamReader<u8>,
+    wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) {
+    if !enabled() {
+        return (data, result);
+    }
+
+    let started = now();
+    let (mut data_tx, data_rx) = wit_stream::new();
+    let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+    wit_bindgen::spawn(async move {
+        let bytes = pipe(&mut data, &mut data_tx).await;
+        drop(data_tx);
+        let result = result.await;
+        record(operation, &path, result.as_ref().err(), bytes, started);
+        let _ = result_tx.write(result).await;
+    });
+    (data_rx, result_rx)
+}
+
+/// Count the outcome of a stream once it completes, leaving the stream itself untouched.
+pub(crate) fn observe_result<T>(
+    operation: &'static str,
+    path: PathBuf,
+    (data, result): (T, wit_bindgen::FutureReader<Result<(), ErrorCode>>),
+) -> (T, wit_bindgen::FutureReader<Result<(), ErrorCode>>) {
+    if !enabled() {
+        return (data, result);
+    }
+
+    let started = now();
+    let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+    wit_bindgen::spawn(async move {
+        let result = result.await;
+        record(operation, &path, result.as_ref().err(), 0, started);
+        let _ = result_tx.write(result).await;
+    });
+    (data, result_rx)
+}
+
+/// Count the bytes written to a stream, and the outcome of the write once it completes.
+pub(crate) fn observe_write(
+    operation: &'static str,
+    path: PathBuf,
+    mut data: wit_bindgen::StreamReader<u8>,
+    write: impl FnOnce(
+        wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    if !enabled() {
+        return write(data);
+    }
+
+    let started = now();
+    let (mut data_tx, data_rx) = wit_stream::new();
+    let result = write(data_rx);
+    let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+    wit_bindgen::spawn(async move {
+        let bytes = pipe(&mut data, &mut data_tx).await;
+        drop(data_tx);
+        let result = result.await;
+        record(operation, &path, result.as_ref().err(), bytes, started);
+        let _ = result_tx.write(result).await;
+    });
+    result_rx
+}
+
+/// Copy a stream until either end is dropped, returning the number of bytes copied.
+async fn pipe(
+    rx: &mut wit_bindgen::StreamReader<u8>,
+    tx: &mut wit_bindgen::StreamWriter<u8>,
+) -> u64 {
+    let mut bytes = 0;
+    loop {
+        let (status, buf) = rx.read(Vec::with_capacity(CHUNK_SIZE)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        let len = buf.len() as u64;
+        let remaining = tx.write_all(buf).await;
+        bytes += len - remaining.len() as u64;
+        if !remaining.is_empty() {
+            break;
+        }
+    }
+    bytes
+}
@@ -0,0 +1,413 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use trash::{Trash, TRASH};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+mod trash;
+
+const MAX_AGE_KEY: &str = "max-age";
+const MAX_SIZE_KEY: &str = "max-size";
+
+const CHUNK_SIZE: usize = 64;
+
+thread_local! {
+    static MAX_AGE: Option<u64> = config(MAX_AGE_KEY).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Config '{MAX_AGE_KEY}' must be a number of seconds"))
+    });
+    static MAX_SIZE: Option<u64> = config(MAX_SIZE_KEY).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Config '{MAX_SIZE_KEY}' must be a number of bytes"))
+    });
+}
+
+fn config(key: &str) -> Option<String> {
+    wasi::config::store::get(key).expect("Config must resolve")
+}
+
+/// Join a path to a path relative to the preopen, resolving '.' and '..' without following
+/// symbolic links. The result may not leave the preopen.
+fn join(base: &Path, path: &str) -> Result<PathBuf, ErrorCode> {
+    if path.starts_with('/') {
+        return Err(ErrorCode::NotPermitted);
+    }
+    let mut joined = base.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => joined.push(name),
+            Component::ParentDir => {
+                if !joined.pop() {
+                    return Err(ErrorCode::NotPermitted);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(joined)
+}
+
+/// Whether the target of a symbolic link at a path relative to the preopen names a path within
+/// the trash.
+fn targets_trash(link: &Path, target: &str) -> bool {
+    let parent = link.parent().unwrap_or(Path::new(""));
+    join(parent, target).is_ok_and(|target| target.starts_with(TRASH))
+}
+
+/// Hide the trash from the listing of the root of its preopen.
+fn hide_trash(
+    mut entries: wit_bindgen::StreamReader<DirectoryEntry>,
+    result: wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) -> (
+    wit_bindgen::StreamReader<DirectoryEntry>,
+    wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+) {
+    let (mut tx, rx) = wit_stream::new();
+    let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+    wit_bindgen::spawn(async move {
+        loop {
+            let (status, buf) = entries.read(Vec::with_capacity(CHUNK_SIZE)).await;
+            if buf.is_empty() {
+                match status {
+                    wit_bindgen::StreamResult::Complete(_) => continue,
+                    _ => break,
+                }
+            }
+            let buf = buf
+                .into_iter()
+                .filter(|entry| entry.name != TRASH)
+                .collect::<Vec<_>>();
+            if !tx.write_all(buf).await.is_empty() {
+                break;
+            }
+        }
+        drop(entries);
+        drop(tx);
+        let _ = result_tx.write(result.await).await;
+    });
+    (rx, result_rx)
+}
+
+struct FilesystemTrash {}
+
+impl Preopens for FilesystemTrash {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let max_age = MAX_AGE.with(|max| *max);
+        let max_size = MAX_SIZE.with(|max| *max);
+        preopens::get_directories()
+            .into_iter()
+            .map(|(root, path)| {
+                let fd = wit_bindgen::block_on(async {
+                    let flags = root.get_flags().await?;
+                    root.open_at(
+                        types::PathFlags::empty(),
+                        String::from("."),
+                        types::OpenFlags::DIRECTORY,
+                        flags,
+                    )
+                    .await
+                })
+                .expect("Preopen must open");
+                let fd = Descriptor::new(TrashDescriptor {
+                    fd,
+                    trash: Rc::new(Trash::new(root, max_age, max_size)),
+                    path: PathBuf::new(),
+                });
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemTrash {
+    type Descriptor = TrashDescriptor;
+}
+
+struct TrashDescriptor {
+    fd: types::Descriptor,
+    trash: Rc<Trash>,
+    /// The path relative to the preopen.
+    path: PathBuf,
+}
+
+impl TrashDescriptor {
+    /// Resolve a path relative to this descriptor to its path relative to the preopen, which may
+    /// not be within the trash.
+    fn checked(&self, path: &str) -> Result<PathBuf, ErrorCode> {
+        let joined = join(&self.path, path)?;
+        match joined.starts_with(TRASH) {
+            true => Err(ErrorCode::NotPermitted),
+            false => Ok(joined),
+        }
+    }
+}
+
+impl GuestDescriptor for TrashDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        self.fd.read_via_stream(offset)
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.fd.write_via_stream(data, offset)
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        self.fd.append_via_stream(data)
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        self.fd.advise(offset, length, advice).await
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        self.fd.sync_data().await
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        self.fd.get_flags().await
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        self.fd.get_type().await
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        self.fd.set_size(size).await
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.fd
+            .set_times(data_access_timestamp, data_modification_timestamp)
+            .await
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (entries, result) = self.fd.read_directory();
+        match self.path.as_os_str().is_empty() {
+            true => hide_trash(entries, result),
+            false => (entries, result),
+        }
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        self.fd.sync().await
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        self.checked(&path)?;
+        self.fd.create_directory_at(path).await
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        self.fd.stat().await
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        self.checked(&path)?;
+        self.fd.stat_at(path_flags, path).await
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        self.checked(&path)?;
+        self.fd
+            .set_times_at(
+                path_flags,
+                path,
+                data_access_timestamp,
+                data_modification_timestamp,
+            )
+            .await
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.checked(&old_path)?;
+        new_descriptor.checked(&new_path)?;
+        self.fd
+            .link_at(old_path_flags, old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let path_at = self.checked(&path)?;
+        self.fd
+            .open_at(path_flags, path, open_flags, flags)
+            .await
+            .map(|fd| {
+                Descriptor::new(TrashDescriptor {
+                    fd,
+                    trash: self.trash.clone(),
+                    path: path_at,
+                })
+            })
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        self.checked(&path)?;
+        self.fd.readlink_at(path).await
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        let original = self.checked(&path)?;
+        // only empty directories may be removed
+        let dir = self
+            .fd
+            .open_at(
+                types::PathFlags::empty(),
+                path.clone(),
+                types::OpenFlags::DIRECTORY,
+                types::DescriptorFlags::READ,
+            )
+            .await?;
+        let (entries, result) = dir.read_directory();
+        let entries = entries.collect().await;
+        result.await?;
+        if !entries.is_empty() {
+            return Err(ErrorCode::NotEmpty);
+        }
+        drop(dir);
+        self.trash.put(&self.fd, &path, &original).await
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        self.checked(&old_path)?;
+        new_descriptor.checked(&new_path)?;
+        self.fd
+            .rename_at(old_path, &new_descriptor.fd, new_path)
+            .await
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        let link = self.checked(&new_path)?;
+        // a link into the trash would reach it by another name
+        if targets_trash(&link, &old_path) {
+            return Err(ErrorCode::NotPermitted);
+        }
+        self.fd.symlink_at(old_path, new_path).await
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        let original = self.checked(&path)?;
+        let stat = self.fd.stat_at(PathFlags::empty(), path.clone()).await?;
+        if matches!(stat.type_, DescriptorType::Directory) {
+            return Err(ErrorCode::IsDirectory);
+        }
+        self.trash.put(&self.fd, &path, &original).await
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        self.fd.is_same_object(&other.fd).await
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        self.fd.metadata_hash().await
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        self.checked(&path)?;
+        self.fd.metadata_hash_at(path_flags, path).await
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemTrash);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_into_the_trash_are_found() {
+        assert!(targets_trash(Path::new("link"), TRASH));
+        assert!(targets_trash(Path::new("link"), ".trash/files/x"));
+        assert!(targets_trash(Path::new("a/b/link"), "../../.trash"));
+        assert!(targets_trash(Path::new("a/link"), "./.././.trash/info"));
+    }
+
+    #[test]
+    fn other_links_are_not() {
+        assert!(!targets_trash(Path::new("link"), "file"));
+        assert!(!targets_trash(Path::new("a/link"), ".trash"));
+        assert!(!targets_trash(Path::new("link"), ".trash2"));
+        // targets leaving the preopen are left to the host
+        assert!(!targets_trash(Path::new("link"), "../.trash"));
+        assert!(!targets_trash(Path::new("link"), "/.trash"));
+    }
+}
@@ -0,0 +1,367 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::DateTime;
+
+use crate::exports::wasi::filesystem::types::{DescriptorType, ErrorCode};
+use crate::wasi::clocks::system_clock;
+use crate::wasi::filesystem::types;
+use crate::wasi::logging::logging::{log, Level};
+use crate::wit_stream;
+
+/// The directory of a preopen holding its trash, laid out like the freedesktop.org trash with
+/// deleted entries in 'files' and where they were deleted from in 'info'.
+pub(crate) const TRASH: &str = ".trash";
+const FILES: &str = ".trash/files";
+const INFO: &str = ".trash/info";
+
+const INFO_SUFFIX: &str = ".trashinfo";
+
+/// The trash of a preopen.
+pub(crate) struct Trash {
+    pub(crate) root: types::Descriptor,
+    /// The age in seconds after which deleted entries are purged.
+    max_age: Option<u64>,
+    /// The size in bytes of deleted files beyond which the oldest entries are purged.
+    max_size: Option<u64>,
+    /// The entries of the trash, loaded when it is first purged and kept up to date from then
+    /// on, so deleting an entry does not measure the whole trash.
+    entries: RefCell<Option<Entries>>,
+}
+
+/// The entries of a trash by when they were deleted, with the bytes used by their files.
+#[derive(Default)]
+struct Entries {
+    deleted: BTreeMap<(i64, String), u64>,
+    size: u64,
+}
+
+impl Entries {
+    fn insert(&mut self, deleted: i64, id: String, size: u64) {
+        if let Some(previous) = self.deleted.insert((deleted, id), size) {
+            self.size = self.size.saturating_sub(previous);
+        }
+        self.size += size;
+    }
+
+    /// Take the oldest entry if it is older than the maximum age, or the trash exceeds the
+    /// maximum size.
+    fn pop_purged(
+        &mut self,
+        now: i64,
+        max_age: Option<u64>,
+        max_size: Option<u64>,
+    ) -> Option<String> {
+        let (&(deleted, _), _) = self.deleted.first_key_value()?;
+        let expired =
+            max_age.is_some_and(|max_age| now.saturating_sub(deleted).max(0) as u64 > max_age);
+        let oversized = max_size.is_some_and(|max_size| self.size > max_size);
+        if !expired && !oversized {
+            return None;
+        }
+        let ((_, id), size) = self.deleted.pop_first()?;
+        self.size = self.size.saturating_sub(size);
+        Some(id)
+    }
+}
+
+impl Trash {
+    pub(crate) fn new(
+        root: types::Descriptor,
+        max_age: Option<u64>,
+        max_size: Option<u64>,
+    ) -> Self {
+        Self {
+            root,
+            max_age,
+            max_size,
+            entries: RefCell::new(None),
+        }
+    }
+
+    /// Move an entry into the trash, recording its original path relative to the preopen and
+    /// when it was deleted, then purge the trash.
+    pub(crate) async fn put(
+        &self,
+        fd: &types::Descriptor,
+        path: &str,
+        original: &Path,
+    ) -> Result<(), ErrorCode> {
+        for dir in [TRASH, FILES, INFO] {
+            match self.root.create_directory_at(dir.to_string()).await {
+                Ok(()) | Err(ErrorCode::Exist) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        let name = original.file_name().map_or(String::from("entry"), |name| {
+            name.to_string_lossy().into_owned()
+        });
+        let (id, info) = self.reserve(&name).await?;
+        let contents = format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            escape(&original.to_string_lossy()),
+            deletion_date()
+        );
+        let (mut tx, rx) = wit_stream::new();
+        let result = info.write_via_stream(rx, 0);
+        let remaining = tx.write_all(contents.into_bytes()).await;
+        drop(tx);
+        let moved = match (result.await, remaining.is_empty()) {
+            (Ok(()), true) => {
+                fd.rename_at(path.to_string(), &self.root, format!("{FILES}/{id}"))
+                    .await
+            }
+            (Err(error), _) => Err(error),
+            (Ok(()), false) => Err(ErrorCode::Io),
+        };
+        if let Err(error) = moved {
+            let _ = self.root.unlink_file_at(info_path(&id)).await;
+            return Err(error);
+        }
+        if let Err(error) = self.purge(&id).await {
+            // the entries are loaded again once they may be out of date
+            self.entries.take();
+            log(
+                Level::Warn,
+                "filesystem",
+                &format!("failed to purge trash: {error:?}"),
+            );
+        }
+        Ok(())
+    }
+
+    /// Reserve a name in the trash by creating its info file, suffixing the name with a number
+    /// when it is taken.
+    async fn reserve(&self, name: &str) -> Result<(String, types::Descriptor), ErrorCode> {
+        let mut n = 1;
+        loop {
+            let id = match n {
+                1 => name.to_string(),
+                n => format!("{name}.{n}"),
+            };
+            match self
+                .root
+                .open_at(
+                    types::PathFlags::empty(),
+                    info_path(&id),
+                    types::OpenFlags::CREATE | types::OpenFlags::EXCLUSIVE,
+                    types::DescriptorFlags::WRITE,
+                )
+                .await
+            {
+                Ok(info) => return Ok((id, info)),
+                Err(ErrorCode::Exist) => n += 1,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Record a deleted entry, then purge the oldest entries while they are older than the
+    /// maximum age or the trash exceeds the maximum size.
+    async fn purge(&self, id: &str) -> Result<(), ErrorCode> {
+        if self.max_age.is_none() && self.max_size.is_none() {
+            return Ok(());
+        }
+        if self.entries.borrow().is_some() {
+            let size = self.measure(format!("{FILES}/{id}")).await?;
+            let deleted = system_clock::now().seconds;
+            if let Some(entries) = self.entries.borrow_mut().as_mut() {
+                entries.insert(deleted, id.to_string(), size);
+            }
+        } else {
+            let entries = self.load().await?;
+            self.entries.borrow_mut().get_or_insert(entries);
+        }
+        let now = system_clock::now().seconds;
+        loop {
+            let purged = self
+                .entries
+                .borrow_mut()
+                .as_mut()
+                .and_then(|entries| entries.pop_purged(now, self.max_age, self.max_size));
+            match purged {
+                Some(id) => self.remove(&id).await?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Load the entries of the trash, deleted when their info files were written.
+    async fn load(&self) -> Result<Entries, ErrorCode> {
+        let mut entries = Entries::default();
+        for name in self.list(INFO).await? {
+            let Some(id) = name.strip_suffix(INFO_SUFFIX) else {
+                continue;
+            };
+            let stat = self
+                .root
+                .stat_at(types::PathFlags::empty(), info_path(id))
+                .await?;
+            let deleted = stat
+                .data_modification_timestamp
+                .map_or(0, |deleted| deleted.seconds);
+            let size = self.measure(format!("{FILES}/{id}")).await?;
+            entries.insert(deleted, id.to_string(), size);
+        }
+        Ok(entries)
+    }
+
+    /// Permanently delete an entry of the trash.
+    async fn remove(&self, id: &str) -> Result<(), ErrorCode> {
+        let mut dirs = Vec::new();
+        let mut paths = vec![format!("{FILES}/{id}")];
+        while let Some(path) = paths.pop() {
+            match self
+                .root
+                .stat_at(types::PathFlags::empty(), path.clone())
+                .await
+            {
+                Ok(stat) if matches!(stat.type_, DescriptorType::Directory) => {
+                    let names = self.list(&path).await?;
+                    paths.extend(names.into_iter().map(|name| format!("{path}/{name}")));
+                    dirs.push(path);
+                }
+                Ok(_) => self.root.unlink_file_at(path).await?,
+                Err(ErrorCode::NoEntry) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        // directories are removed after the entries within them
+        for dir in dirs.into_iter().rev() {
+            self.root.remove_directory_at(dir).await?;
+        }
+        // entries may have been restored from the host
+        match self.root.unlink_file_at(info_path(id)).await {
+            Ok(()) | Err(ErrorCode::NoEntry) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// The bytes used by the files of an entry of the trash.
+    async fn measure(&self, path: String) -> Result<u64, ErrorCode> {
+        let mut size = 0;
+        let mut paths = vec![path];
+        while let Some(path) = paths.pop() {
+            let stat = match self
+                .root
+                .stat_at(types::PathFlags::empty(), path.clone())
+                .await
+            {
+                Ok(stat) => stat,
+                Err(ErrorCode::NoEntry) => continue,
+                Err(error) => return Err(error),
+            };
+            match stat.type_ {
+                DescriptorType::Directory => {
+                    let names = self.list(&path).await?;
+                    paths.extend(names.into_iter().map(|name| format!("{path}/{name}")));
+                }
+                DescriptorType::RegularFile => size += stat.size,
+                _ => {}
+            }
+        }
+        Ok(size)
+    }
+
+    async fn list(&self, dir: &str) -> Result<Vec<String>, ErrorCode> {
+        let dir = self
+            .root
+            .open_at(
+                types::PathFlags::empty(),
+                dir.to_string(),
+                types::OpenFlags::DIRECTORY,
+                types::DescriptorFlags::READ,
+            )
+            .await?;
+        let (entries, result) = dir.read_directory();
+        let entries = entries.collect().await;
+        result.await?;
+        Ok(entries.into_iter().map(|entry| entry.name).collect())
+    }
+}
+
+/// Escape a path as in a URI, as the freedesktop.org trash records it.
+fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'_'
+            | b'.'
+            | b'!'
+            | b'~'
+            | b'*'
+            | b'\''
+            | b'('
+            | b')'
+            | b'/' => escaped.push(char::from(byte)),
+            _ => escaped.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    escaped
+}
+
+fn info_path(id: &str) -> String {
+    format!("{INFO}/{id}{INFO_SUFFIX}")
+}
+
+/// The current time, formatted as in the freedesktop.org trash.
+fn deletion_date() -> String {
+    let now = system_clock::now();
+    match DateTime::from_timestamp(now.seconds, now.nanoseconds) {
+        Some(date) => date.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        None => now.seconds.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_path() {
+        assert_eq!(escape("docs/notes (1).txt"), "docs/notes%20(1).txt");
+        assert_eq!(escape("100%\nx"), "100%25%0Ax");
+        assert_eq!(escape("caf\u{e9}"), "caf%C3%A9");
+    }
+
+    #[test]
+    fn purge_expired() {
+        let mut entries = Entries::default();
+        entries.insert(100, String::from("b"), 1);
+        entries.insert(10, String::from("a"), 1);
+        entries.insert(200, String::from("c"), 1);
+        assert_eq!(
+            entries.pop_purged(250, Some(100), None),
+            Some(String::from("a"))
+        );
+        assert_eq!(
+            entries.pop_purged(250, Some(100), None),
+            Some(String::from("b"))
+        );
+        assert_eq!(entries.pop_purged(250, Some(100), None), None);
+        assert_eq!(entries.size, 1);
+    }
+
+    #[test]
+    fn purge_oversized() {
+        let mut entries = Entries::default();
+        entries.insert(1, String::from("a"), 40);
+        entries.insert(2, String::from("b"), 30);
+        entries.insert(3, String::from("c"), 50);
+        assert_eq!(
+            entries.pop_purged(3, None, Some(100)),
+            Some(String::from("a"))
+        );
+        assert_eq!(entries.pop_purged(3, None, Some(100)), None);
+        assert_eq!(entries.size, 80);
+        // an entry recorded again replaces its size
+        entries.insert(3, String::from("c"), 10);
+        assert_eq!(entries.size, 40);
+        assert_eq!(entries.pop_purged(3, None, None), None);
+    }
+}
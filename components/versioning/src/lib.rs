@@ -0,0 +1,697 @@
+#![cfg_attr(not(test), no_main)]
+
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use exports::wasi::filesystem::preopens::Guest as Preopens;
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, Guest as Types, GuestDescriptor, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use versions::{relative, Keep, Versions, STORE};
+use wasi::filesystem::preopens;
+use wasi::filesystem::types;
+
+mod versions;
+
+const MAX_VERSIONS_KEY: &str = "max-versions";
+const MAX_AGE_KEY: &str = "max-age";
+
+const CHUNK_SIZE: usize = 64;
+
+thread_local! {
+    static MAX_VERSIONS: Option<usize> = config(MAX_VERSIONS_KEY).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Config '{MAX_VERSIONS_KEY}' must be a number"))
+    });
+    static MAX_AGE: Option<u64> = config(MAX_AGE_KEY).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Config '{MAX_AGE_KEY}' must be a number of seconds"))
+    });
+}
+
+fn config(key: &str) -> Option<String> {
+    wasi::config::store::get(key).expect("Config must resolve")
+}
+
+/// Join a path to a path relative to the preopen, resolving '.' and '..' without following
+/// symbolic links. The result may not leave the preopen.
+fn join(base: &Path, path: &str) -> Result<PathBuf, ErrorCode> {
+    if path.starts_with('/') {
+        return Err(ErrorCode::NotPermitted);
+    }
+    let mut joined = base.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => joined.push(name),
+            Component::ParentDir => {
+                if !joined.pop() {
+                    return Err(ErrorCode::NotPermitted);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(joined)
+}
+
+fn failed(error: ErrorCode) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    let (tx, rx) = wit_future::new(|| Err(ErrorCode::Io));
+    wit_bindgen::spawn(async move {
+        let _ = tx.write(Err(error)).await;
+    });
+    rx
+}
+
+/// What a path relative to the preopen refers to.
+enum Route {
+    /// A file or directory of the preopen.
+    Live,
+    /// The '.versions' directory within a directory, or the versions of a file within it.
+    History(PathBuf, Option<String>),
+    /// A version of a file.
+    Version(PathBuf, String),
+}
+
+fn route(path: &Path) -> Result<Route, ErrorCode> {
+    let names = path
+        .iter()
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let Some(i) = names.iter().position(|name| name == STORE) else {
+        return Ok(Route::Live);
+    };
+    let dir = names[..i].iter().collect::<PathBuf>();
+    match &names[i + 1..] {
+        [] => Ok(Route::History(dir, None)),
+        [name] => Ok(Route::History(dir, Some(name.clone()))),
+        [name, version] => Ok(Route::Version(dir.join(name), version.clone())),
+        _ => Err(ErrorCode::NotDirectory),
+    }
+}
+
+struct FilesystemVersioning {}
+
+impl Preopens for FilesystemVersioning {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let max_versions = MAX_VERSIONS.with(|max| *max);
+        let max_age = MAX_AGE.with(|max| *max);
+        preopens::get_directories()
+            .into_iter()
+            .map(|(root, path)| {
+                let fd = wit_bindgen::block_on(async {
+                    let flags = root.get_flags().await?;
+                    root.open_at(
+                        types::PathFlags::empty(),
+                        String::from("."),
+                        types::OpenFlags::DIRECTORY,
+                        flags,
+                    )
+                    .await
+                })
+                .expect("Preopen must open");
+                let fd = Descriptor::new(VersioningDescriptor {
+                    versions: Rc::new(Versions::new(root, max_versions, max_age)),
+                    path: PathBuf::new(),
+                    kind: Kind::Live {
+                        fd: Rc::new(fd),
+                        versioned: Rc::new(Cell::new(false)),
+                    },
+                });
+                (fd, path)
+            })
+            .collect()
+    }
+}
+
+impl Types for FilesystemVersioning {
+    type Descriptor = VersioningDescriptor;
+}
+
+enum Kind {
+    /// A file or directory of the preopen, which keeps a version before it is first overwritten
+    /// or truncated through this descriptor.
+    Live {
+        fd: Rc<types::Descriptor>,
+        versioned: Rc<Cell<bool>>,
+    },
+    /// A version of a file, read-only.
+    Version(types::Descriptor),
+    /// A synthesized directory of versions.
+    History,
+}
+
+struct VersioningDescriptor {
+    versions: Rc<Versions>,
+    /// The path relative to the preopen.
+    path: PathBuf,
+    kind: Kind,
+}
+
+impl VersioningDescriptor {
+    fn fd(&self) -> Option<&types::Descriptor> {
+        match &self.kind {
+            Kind::Live { fd, .. } => Some(fd),
+            Kind::Version(fd) => Some(fd),
+            Kind::History => None,
+        }
+    }
+
+    /// Resolve a path relative to this descriptor, to its path relative to the preopen.
+    fn resolve(&self, path: &str) -> Result<(PathBuf, Route), ErrorCode> {
+        let joined = join(&self.path, path)?;
+        let route = route(&joined)?;
+        Ok((joined, route))
+    }
+
+    /// The descriptor and path to reach a path that is not a synthesized directory. Live paths
+    /// within this directory are reached through it, anything else through the preopen.
+    fn target<'a>(
+        &'a self,
+        path: &str,
+        joined: &Path,
+        route: &Route,
+    ) -> Result<(&'a types::Descriptor, String), ErrorCode> {
+        let root = &self.versions.root;
+        match route {
+            Route::Live => match &self.kind {
+                Kind::Live { fd, .. }
+                    if !Path::new(path)
+                        .components()
+                        .any(|component| component == Component::ParentDir) =>
+                {
+                    Ok((fd, path.to_string()))
+                }
+                _ => Ok((root, relative(joined))),
+            },
+            Route::History(dir, Some(name)) => Ok((root, Versions::store_path(&dir.join(name)))),
+            Route::Version(file, version) => {
+                Ok((root, format!("{}/{version}", Versions::store_path(file))))
+            }
+            Route::History(_, None) => Err(ErrorCode::IsDirectory),
+        }
+    }
+
+    /// Keep a version of a file before a descriptor first overwrites or truncates it, which is
+    /// when writing or truncating to a size within the file changes existing contents.
+    async fn keep_once(
+        versions: &Versions,
+        path: &Path,
+        fd: &types::Descriptor,
+        versioned: &Cell<bool>,
+        size: Filesize,
+    ) -> Result<(), ErrorCode> {
+        if !versioned.get() && size < fd.stat().await?.size {
+            versions.keep(path, Keep::Copy).await?;
+            versioned.set(true);
+        }
+        Ok(())
+    }
+
+    fn history_stat() -> DescriptorStat {
+        DescriptorStat {
+            type_: DescriptorType::Directory,
+            link_count: 1,
+            size: 0,
+            data_access_timestamp: None,
+            data_modification_timestamp: None,
+            status_change_timestamp: None,
+        }
+    }
+
+    fn history_metadata_hash(path: &Path) -> MetadataHashValue {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        MetadataHashValue {
+            lower: hasher.finish(),
+            upper: 0,
+        }
+    }
+
+    /// Hide the versions stored in the preopen from the listing of its root.
+    fn hide_store(
+        mut entries: wit_bindgen::StreamReader<DirectoryEntry>,
+        result: wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (mut tx, rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            loop {
+                let (status, buf) = entries.read(Vec::with_capacity(CHUNK_SIZE)).await;
+                if buf.is_empty() {
+                    match status {
+                        wit_bindgen::StreamResult::Complete(_) => continue,
+                        _ => break,
+                    }
+                }
+                let buf = buf
+                    .into_iter()
+                    .filter(|entry| entry.name != STORE)
+                    .collect::<Vec<_>>();
+                if !tx.write_all(buf).await.is_empty() {
+                    break;
+                }
+            }
+            drop(entries);
+            drop(tx);
+            let _ = result_tx.write(result.await).await;
+        });
+        (rx, result_rx)
+    }
+}
+
+impl GuestDescriptor for VersioningDescriptor {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        match self.fd() {
+            Some(fd) => fd.read_via_stream(offset),
+            None => (wit_stream::new().1, failed(ErrorCode::IsDirectory)),
+        }
+    }
+
+    fn write_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+        offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        let (fd, versioned) = match &self.kind {
+            Kind::Live { fd, versioned } => (fd.clone(), versioned.clone()),
+            Kind::Version(fd) => return fd.write_via_stream(data, offset),
+            Kind::History => return failed(ErrorCode::IsDirectory),
+        };
+        let versions = self.versions.clone();
+        let path = self.path.clone();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            let result = match Self::keep_once(&versions, &path, &fd, &versioned, offset).await {
+                Ok(()) => fd.write_via_stream(data, offset).await,
+                Err(error) => Err(error),
+            };
+            let _ = result_tx.write(result).await;
+        });
+        result_rx
+    }
+
+    fn append_via_stream(
+        &self,
+        data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        match self.fd() {
+            Some(fd) => fd.append_via_stream(data),
+            None => failed(ErrorCode::IsDirectory),
+        }
+    }
+
+    async fn advise(
+        &self,
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        match self.fd() {
+            Some(fd) => fd.advise(offset, length, advice).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        match self.fd() {
+            Some(fd) => fd.sync_data().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        match self.fd() {
+            Some(fd) => fd.get_flags().await,
+            None => Ok(DescriptorFlags::READ),
+        }
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        match self.fd() {
+            Some(fd) => fd.get_type().await,
+            None => Ok(DescriptorType::Directory),
+        }
+    }
+
+    async fn set_size(&self, size: Filesize) -> Result<(), ErrorCode> {
+        match &self.kind {
+            Kind::Live { fd, versioned } => {
+                Self::keep_once(&self.versions, &self.path, fd, versioned, size).await?;
+                fd.set_size(size).await
+            }
+            Kind::Version(fd) => fd.set_size(size).await,
+            Kind::History => Err(ErrorCode::IsDirectory),
+        }
+    }
+
+    async fn set_times(
+        &self,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        match self.fd() {
+            Some(fd) => {
+                fd.set_times(data_access_timestamp, data_modification_timestamp)
+                    .await
+            }
+            None => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        if let Some(fd) = self.fd() {
+            let (entries, result) = fd.read_directory();
+            return match self.path.as_os_str().is_empty() {
+                true => Self::hide_store(entries, result),
+                false => (entries, result),
+            };
+        }
+        let versions = self.versions.clone();
+        let route = route(&self.path);
+        let (mut tx, rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            let entries = match route {
+                Ok(Route::History(dir, None)) => versions.files(&dir).await,
+                Ok(Route::History(dir, Some(name))) => versions.versions(&dir.join(name)).await,
+                Ok(_) => Err(ErrorCode::NotDirectory),
+                Err(error) => Err(error),
+            };
+            let result = match entries {
+                Ok(entries) => {
+                    tx.write_all(entries).await;
+                    Ok(())
+                }
+                Err(error) => Err(error),
+            };
+            drop(tx);
+            let _ = result_tx.write(result).await;
+        });
+        (rx, result_rx)
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        match self.fd() {
+            Some(fd) => fd.sync().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn create_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        let (joined, route) = self.resolve(&path)?;
+        match route {
+            Route::Live => {
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                fd.create_directory_at(path).await
+            }
+            _ => Err(ErrorCode::Exist),
+        }
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        match self.fd() {
+            Some(fd) => fd.stat().await,
+            None => self.stat_at(PathFlags::empty(), String::new()).await,
+        }
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        let (joined, route) = self.resolve(&path)?;
+        match route {
+            Route::History(_, None) => Ok(Self::history_stat()),
+            _ => {
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                fd.stat_at(path_flags, path).await
+            }
+        }
+    }
+
+    async fn set_times_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        let (joined, route) = self.resolve(&path)?;
+        match route {
+            Route::Live => {
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                fd.set_times_at(
+                    path_flags,
+                    path,
+                    data_access_timestamp,
+                    data_modification_timestamp,
+                )
+                .await
+            }
+            _ => Err(ErrorCode::ReadOnly),
+        }
+    }
+
+    async fn link_at(
+        &self,
+        old_path_flags: PathFlags,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        if !Rc::ptr_eq(&self.versions, &new_descriptor.versions) {
+            return Err(ErrorCode::CrossDevice);
+        }
+        let (old_joined, old_route) = self.resolve(&old_path)?;
+        let (new_joined, new_route) = new_descriptor.resolve(&new_path)?;
+        match (&old_route, &new_route) {
+            // a version may be linked back into the preopen
+            (Route::Live | Route::Version(..), Route::Live) => {
+                let (fd, old_path) = self.target(&old_path, &old_joined, &old_route)?;
+                let (new_fd, new_path) =
+                    new_descriptor.target(&new_path, &new_joined, &new_route)?;
+                fd.link_at(old_path_flags, old_path, new_fd, new_path).await
+            }
+            (_, Route::Live) => Err(ErrorCode::NotPermitted),
+            _ => Err(ErrorCode::Exist),
+        }
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        let (joined, route) = self.resolve(&path)?;
+        let kind = match &route {
+            Route::Live => {
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                let truncate = open_flags.contains(OpenFlags::TRUNCATE);
+                // the version is discarded if the open fails, so it doesn't replace a real one
+                let version = match truncate {
+                    true => self.versions.store(&joined, Keep::Copy).await?,
+                    false => None,
+                };
+                let opened = fd.open_at(path_flags, path, open_flags, flags).await;
+                let fd = match (opened, version) {
+                    (Ok(fd), Some(_)) => {
+                        self.versions.prune(&joined).await?;
+                        fd
+                    }
+                    (Err(error), Some(version)) => {
+                        self.versions.discard(&joined, version).await?;
+                        return Err(error);
+                    }
+                    (opened, None) => opened?,
+                };
+                Kind::Live {
+                    fd: Rc::new(fd),
+                    versioned: Rc::new(Cell::new(truncate)),
+                }
+            }
+            Route::Version(..) => {
+                if open_flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNCATE)
+                    || flags.contains(DescriptorFlags::WRITE)
+                {
+                    return Err(ErrorCode::ReadOnly);
+                }
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                Kind::Version(fd.open_at(path_flags, path, open_flags, flags).await?)
+            }
+            Route::History(..) => {
+                if open_flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) {
+                    return Err(ErrorCode::Exist);
+                }
+                if flags.contains(DescriptorFlags::WRITE)
+                    || open_flags.contains(OpenFlags::TRUNCATE)
+                {
+                    return Err(ErrorCode::IsDirectory);
+                }
+                // only files with versions have a directory of them
+                if let Ok((fd, path)) = self.target(&path, &joined, &route) {
+                    fd.stat_at(PathFlags::empty(), path).await?;
+                }
+                Kind::History
+            }
+        };
+        Ok(Descriptor::new(VersioningDescriptor {
+            versions: self.versions.clone(),
+            path: joined,
+            kind,
+        }))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        let (joined, route) = self.resolve(&path)?;
+        match route {
+            Route::Live => {
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                fd.readlink_at(path).await
+            }
+            _ => Err(ErrorCode::Invalid),
+        }
+    }
+
+    async fn remove_directory_at(&self, path: String) -> Result<(), ErrorCode> {
+        let (joined, route) = self.resolve(&path)?;
+        match route {
+            // the versions of a file may be removed once each version is
+            Route::Live | Route::History(_, Some(_)) => {
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                fd.remove_directory_at(path).await
+            }
+            Route::History(_, None) => Err(ErrorCode::NotPermitted),
+            Route::Version(..) => Err(ErrorCode::NotDirectory),
+        }
+    }
+
+    async fn rename_at(
+        &self,
+        old_path: String,
+        new_descriptor: DescriptorBorrow<'_>,
+        new_path: String,
+    ) -> Result<(), ErrorCode> {
+        let new_descriptor: &Self = new_descriptor.get();
+        if !Rc::ptr_eq(&self.versions, &new_descriptor.versions) {
+            return Err(ErrorCode::CrossDevice);
+        }
+        let (old_joined, old_route) = self.resolve(&old_path)?;
+        let (new_joined, new_route) = new_descriptor.resolve(&new_path)?;
+        match (&old_route, &new_route) {
+            // a version may be restored by renaming it back into the preopen
+            (Route::Live | Route::Version(..), Route::Live) => {
+                // the destination is linked rather than moved, so a failed rename changes nothing
+                let version = match old_joined != new_joined {
+                    true => self.versions.store(&new_joined, Keep::Link).await?,
+                    false => None,
+                };
+                let (fd, old_path) = self.target(&old_path, &old_joined, &old_route)?;
+                let (new_fd, new_path) =
+                    new_descriptor.target(&new_path, &new_joined, &new_route)?;
+                let renamed = fd.rename_at(old_path, new_fd, new_path).await;
+                match (renamed, version) {
+                    (Ok(()), Some(_)) => self.versions.prune(&new_joined).await,
+                    (Err(error), Some(version)) => {
+                        self.versions.discard(&new_joined, version).await?;
+                        Err(error)
+                    }
+                    (renamed, None) => renamed,
+                }
+            }
+            _ => Err(ErrorCode::NotPermitted),
+        }
+    }
+
+    async fn symlink_at(&self, old_path: String, new_path: String) -> Result<(), ErrorCode> {
+        let (joined, route) = self.resolve(&new_path)?;
+        match route {
+            Route::Live => {
+                let (fd, new_path) = self.target(&new_path, &joined, &route)?;
+                fd.symlink_at(old_path, new_path).await
+            }
+            _ => Err(ErrorCode::Exist),
+        }
+    }
+
+    async fn unlink_file_at(&self, path: String) -> Result<(), ErrorCode> {
+        let (joined, route) = self.resolve(&path)?;
+        match route {
+            // keeping the file as a version moves it away
+            Route::Live if self.versions.keep(&joined, Keep::Move).await? => Ok(()),
+            // a version may be discarded
+            Route::Live | Route::Version(..) => {
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                fd.unlink_file_at(path).await
+            }
+            Route::History(..) => Err(ErrorCode::IsDirectory),
+        }
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        match (self.fd(), other.fd()) {
+            (Some(fd), Some(other_fd)) => fd.is_same_object(other_fd).await,
+            (None, None) => Rc::ptr_eq(&self.versions, &other.versions) && self.path == other.path,
+            _ => false,
+        }
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        match self.fd() {
+            Some(fd) => fd.metadata_hash().await,
+            None => {
+                self.metadata_hash_at(PathFlags::empty(), String::new())
+                    .await
+            }
+        }
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        let (joined, route) = self.resolve(&path)?;
+        match route {
+            Route::History(_, None) => Ok(Self::history_metadata_hash(&joined)),
+            _ => {
+                let (fd, path) = self.target(&path, &joined, &route)?;
+                fd.metadata_hash_at(path_flags, path).await
+            }
+        }
+    }
+}
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all
+});
+
+export!(FilesystemVersioning);
@@ -0,0 +1,328 @@
+use std::path::{Path, PathBuf};
+
+use chrono::DateTime;
+
+use crate::exports::wasi::filesystem::types::{DescriptorType, DirectoryEntry, ErrorCode};
+use crate::wasi::clocks::system_clock;
+use crate::wasi::filesystem::types;
+
+/// The directory of a preopen holding the stored versions, with a directory for each file named
+/// by its encoded path.
+pub(crate) const STORE: &str = ".versions";
+
+/// The host path of a path relative to a directory, which is the directory itself when empty.
+pub(crate) fn relative(path: &Path) -> String {
+    match path.as_os_str().is_empty() {
+        true => String::from("."),
+        false => path.to_string_lossy().into_owned(),
+    }
+}
+
+/// Encode the path of a file as a single name, escaping '%' and '/'.
+fn encode(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('%', "%25")
+        .replace('/', "%2F")
+}
+
+fn decode(name: &str) -> PathBuf {
+    PathBuf::from(name.replace("%2F", "/").replace("%25", "%"))
+}
+
+/// How the previous contents of a file are kept.
+#[derive(Clone, Copy)]
+pub(crate) enum Keep {
+    /// Move the file, as it is about to be removed.
+    Move,
+    /// Link the file, as it is about to be replaced.
+    Link,
+    /// Copy the file, as it is about to be changed in place.
+    Copy,
+}
+
+/// The stored versions of the files of a preopen.
+pub(crate) struct Versions {
+    pub(crate) root: types::Descriptor,
+    max_versions: Option<usize>,
+    /// The age in seconds after which versions are removed.
+    max_age: Option<u64>,
+}
+
+impl Versions {
+    pub(crate) fn new(
+        root: types::Descriptor,
+        max_versions: Option<usize>,
+        max_age: Option<u64>,
+    ) -> Self {
+        Self {
+            root,
+            max_versions,
+            max_age,
+        }
+    }
+
+    /// The path within the preopen of the stored versions of a file.
+    pub(crate) fn store_path(path: &Path) -> String {
+        format!("{STORE}/{}", encode(path))
+    }
+
+    /// Keep the previous contents of a file as a new version, then remove versions beyond the
+    /// retention limits. Anything other than a regular file has no versions.
+    pub(crate) async fn keep(&self, path: &Path, keep: Keep) -> Result<bool, ErrorCode> {
+        if self.store(path, keep).await?.is_none() {
+            return Ok(false);
+        }
+        self.prune(path).await?;
+        Ok(true)
+    }
+
+    /// Keep the previous contents of a file as a new version without applying the retention
+    /// limits, returning the path of the version within the preopen.
+    pub(crate) async fn store(&self, path: &Path, keep: Keep) -> Result<Option<String>, ErrorCode> {
+        match self
+            .root
+            .stat_at(types::PathFlags::empty(), relative(path))
+            .await
+        {
+            Ok(stat) if matches!(stat.type_, DescriptorType::RegularFile) => {}
+            Ok(_) | Err(ErrorCode::NoEntry) => return Ok(None),
+            Err(error) => return Err(error),
+        }
+        let store = Self::store_path(path);
+        for dir in [String::from(STORE), store.clone()] {
+            match self.root.create_directory_at(dir).await {
+                Ok(()) | Err(ErrorCode::Exist) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        let time = version_time();
+        for count in 0.. {
+            let version = format!("{store}/{}", version_name(&time, count));
+            // each way of keeping fails rather than replace a version kept at the same time
+            let kept = match keep {
+                Keep::Move | Keep::Link => {
+                    self.root
+                        .link_at(
+                            types::PathFlags::empty(),
+                            relative(path),
+                            &self.root,
+                            version.clone(),
+                        )
+                        .await
+                }
+                Keep::Copy => self.copy(path, version.clone()).await,
+            };
+            match kept {
+                Ok(()) => {}
+                Err(ErrorCode::Exist) => continue,
+                Err(error) => return Err(error),
+            }
+            if let Keep::Move = keep {
+                if let Err(error) = self.root.unlink_file_at(relative(path)).await {
+                    self.discard(path, version).await?;
+                    return Err(error);
+                }
+            }
+            return Ok(Some(version));
+        }
+        unreachable!("versions must be named")
+    }
+
+    /// Remove a version kept for a change that failed.
+    pub(crate) async fn discard(&self, path: &Path, version: String) -> Result<(), ErrorCode> {
+        self.root.unlink_file_at(version).await?;
+        match self.root.remove_directory_at(Self::store_path(path)).await {
+            Ok(()) | Err(ErrorCode::NotEmpty) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn copy(&self, path: &Path, version: String) -> Result<(), ErrorCode> {
+        let src = self
+            .root
+            .open_at(
+                types::PathFlags::empty(),
+                relative(path),
+                types::OpenFlags::empty(),
+                types::DescriptorFlags::READ,
+            )
+            .await?;
+        let dst = self
+            .root
+            .open_at(
+                types::PathFlags::empty(),
+                version,
+                types::OpenFlags::CREATE | types::OpenFlags::EXCLUSIVE,
+                types::DescriptorFlags::WRITE,
+            )
+            .await?;
+        let (data, read_result) = src.read_via_stream(0);
+        dst.write_via_stream(data, 0).await?;
+        read_result.await
+    }
+
+    /// Remove the oldest versions of a file beyond the maximum count, and versions older than the
+    /// maximum age.
+    pub(crate) async fn prune(&self, path: &Path) -> Result<(), ErrorCode> {
+        let store = Self::store_path(path);
+        let mut versions = self.list(&store).await?;
+        versions.sort_by(|a, b| order(a).cmp(&order(b)));
+        let excess = self
+            .max_versions
+            .map_or(0, |max| versions.len().saturating_sub(max));
+        let now = system_clock::now().seconds;
+        let mut remaining = versions.len();
+        for (i, version) in versions.into_iter().enumerate() {
+            let path = format!("{store}/{version}");
+            let expired = match self.max_age {
+                Some(max_age) if i >= excess => {
+                    let stat = self
+                        .root
+                        .stat_at(types::PathFlags::empty(), path.clone())
+                        .await?;
+                    // versions are kept as they are moved or created in the store
+                    stat.status_change_timestamp
+                        .or(stat.data_modification_timestamp)
+                        .is_some_and(|kept| {
+                            now.saturating_sub(kept.seconds).max(0) as u64 > max_age
+                        })
+                }
+                _ => i < excess,
+            };
+            if expired {
+                self.root.unlink_file_at(path).await?;
+                remaining -= 1;
+            }
+        }
+        if remaining == 0 {
+            self.root.remove_directory_at(store).await?;
+        }
+        Ok(())
+    }
+
+    async fn list(&self, dir: &str) -> Result<Vec<String>, ErrorCode> {
+        let dir = self
+            .root
+            .open_at(
+                types::PathFlags::empty(),
+                dir.to_string(),
+                types::OpenFlags::DIRECTORY,
+                types::DescriptorFlags::READ,
+            )
+            .await?;
+        let (entries, result) = dir.read_directory();
+        let entries = entries.collect().await;
+        result.await?;
+        Ok(entries.into_iter().map(|entry| entry.name).collect())
+    }
+
+    /// List the files of a directory that have versions.
+    pub(crate) async fn files(&self, dir: &Path) -> Result<Vec<DirectoryEntry>, ErrorCode> {
+        let names = match self.list(STORE).await {
+            Ok(names) => names,
+            Err(ErrorCode::NoEntry) => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        let mut files = names
+            .iter()
+            .map(|name| decode(name))
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>();
+        files.sort();
+        Ok(files
+            .into_iter()
+            .map(|name| DirectoryEntry {
+                type_: DescriptorType::Directory,
+                name,
+            })
+            .collect())
+    }
+
+    /// List the versions of a file, oldest first.
+    pub(crate) async fn versions(&self, path: &Path) -> Result<Vec<DirectoryEntry>, ErrorCode> {
+        let mut versions = self.list(&Self::store_path(path)).await?;
+        versions.sort_by(|a, b| order(a).cmp(&order(b)));
+        Ok(versions
+            .into_iter()
+            .map(|name| DirectoryEntry {
+                type_: DescriptorType::RegularFile,
+                name,
+            })
+            .collect())
+    }
+}
+
+/// The time a version is kept, so names sort in the order versions are kept.
+fn version_time() -> String {
+    let now = system_clock::now();
+    match DateTime::from_timestamp(now.seconds, now.nanoseconds) {
+        Some(date) => date.format("%Y%m%dT%H%M%S%.9fZ").to_string(),
+        None => format!("{}.{:09}", now.seconds, now.nanoseconds),
+    }
+}
+
+/// Name a version by the time it is kept, counting versions kept at the same time.
+fn version_name(time: &str, count: u64) -> String {
+    match count {
+        0 => time.to_string(),
+        _ => format!("{time}-{count}"),
+    }
+}
+
+/// The order of a version by the time it was kept, then by its count.
+fn order(name: &str) -> (&str, u64) {
+    match name.rsplit_once('-') {
+        Some((time, count)) => match count.parse() {
+            Ok(count) => (time, count),
+            Err(_) => (name, 0),
+        },
+        None => (name, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    #[test]
+    fn encode_escapes() {
+        assert_eq!(encode(Path::new("docs/notes.txt")), "docs%2Fnotes.txt");
+        assert_eq!(encode(Path::new("100%/a%2Fb")), "100%25%2Fa%252Fb");
+        assert_eq!(decode("100%25%2Fa%252Fb"), Path::new("100%/a%2Fb"));
+    }
+
+    #[test]
+    fn versions_order() {
+        let mut names = [
+            "20261018T120000.000000001Z",
+            "20261018T120000.000000000Z-10",
+            "20261018T120000.000000000Z-2",
+            "20261018T120000.000000000Z",
+        ];
+        names.sort_by(|a, b| order(a).cmp(&order(b)));
+        assert_eq!(
+            names,
+            [
+                "20261018T120000.000000000Z",
+                "20261018T120000.000000000Z-2",
+                "20261018T120000.000000000Z-10",
+                "20261018T120000.000000001Z",
+            ]
+        );
+        assert_eq!(version_name("20261018T120000.000000000Z", 0), names[0]);
+        assert_eq!(version_name("20261018T120000.000000000Z", 2), names[1]);
+    }
+
+    proptest! {
+        #[test]
+        fn encode_round_trip(path in "[a-z%2F]{1,5}(/[a-z%2F]{1,5}){0,3}") {
+            let path = PathBuf::from(path);
+            let name = encode(&path);
+            prop_assert!(!name.contains('/'));
+            prop_assert_eq!(decode(&name), path);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+#![no_main]
+
+use std::rc::Rc;
+
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+
+use vfs::exports::wasi::filesystem::preopens::Guest as Preopens;
+use vfs::exports::wasi::filesystem::types::{Descriptor, ErrorCode, Filesize, Guest as Types};
+use vfs::wasi::filesystem::preopens;
+use vfs::wasi::filesystem::types;
+use vfs::{wit_future, wit_stream, Tree, VfsDescriptor};
+
+mod zip;
+
+const ARCHIVE_KEY: &str = "archive";
+const ARCHIVE_PREOPEN_KEY: &str = "archive-preopen";
+const PREOPEN_KEY: &str = "preopen";
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+thread_local! {
+    static TREE: Rc<Tree<Contents>> = wit_bindgen::block_on(index_from_config());
+}
+
+fn config(key: &str) -> Option<String> {
+    vfs::wasi::config::store::get(key).expect("Config must resolve")
+}
+
+/// Index the archive named in config, relative to the preopened directory named in config or
+/// else the first.
+async fn index_from_config() -> Rc<Tree<Contents>> {
+    let path = config(ARCHIVE_KEY).unwrap_or_else(|| panic!("Config must contain '{ARCHIVE_KEY}'"));
+
+    let dirs = preopens::get_directories();
+    let (dir, _) = match config(ARCHIVE_PREOPEN_KEY) {
+        Some(preopen) => dirs
+            .iter()
+            .find(|(_, path)| *path == preopen)
+            .unwrap_or_else(|| panic!("preopen '{preopen}' must exist")),
+        None => dirs.first().expect("Must have a preopened directory"),
+    };
+    let fd = dir
+        .open_at(
+            types::PathFlags::SYMLINK_FOLLOW,
+            path.clone(),
+            types::OpenFlags::empty(),
+            types::DescriptorFlags::READ,
+        )
+        .await
+        .unwrap_or_else(|_| panic!("archive '{path}' must exist"));
+
+    let tree = zip::index(&Rc::new(fd))
+        .await
+        .unwrap_or_else(|_| panic!("archive '{path}' must be a zip file"));
+    Rc::new(tree)
+}
+
+/// Read up to `len` bytes from an offset, fewer only at the end of the file.
+async fn read_at(
+    fd: &types::Descriptor,
+    offset: Filesize,
+    len: usize,
+) -> Result<Vec<u8>, ErrorCode> {
+    let (mut rx, result) = fd.read_via_stream(offset);
+    let mut data = Vec::with_capacity(len.min(CHUNK_SIZE as usize));
+    while data.len() < len {
+        let (status, buf) = rx.read(Vec::with_capacity(len - data.len())).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => break,
+            }
+        }
+        data.extend(buf);
+    }
+    drop(rx);
+    if data.len() < len {
+        result.await?;
+    }
+    Ok(data)
+}
+
+/// Copy at most `limit` bytes from one stream to another, returning whether the source ended
+/// first.
+async fn pipe(
+    rx: &mut wit_bindgen::StreamReader<u8>,
+    tx: &mut wit_bindgen::StreamWriter<u8>,
+    mut limit: u64,
+) -> bool {
+    while limit > 0 {
+        let capacity = limit.min(CHUNK_SIZE) as usize;
+        let (status, buf) = rx.read(Vec::with_capacity(capacity)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                _ => return true,
+            }
+        }
+        limit -= buf.len() as u64;
+        let remaining = tx.write_all(buf).await;
+        if !remaining.is_empty() {
+            break;
+        }
+    }
+    false
+}
+
+/// Decompress a raw deflate stream, skipping the first `skip` bytes of decompressed data and
+/// copying at most `limit` bytes after them. The stream is decompressed from its start as
+/// deflate has no points to resume from.
+async fn inflate_stream(
+    rx: &mut wit_bindgen::StreamReader<u8>,
+    tx: &mut wit_bindgen::StreamWriter<u8>,
+    mut skip: u64,
+    mut limit: u64,
+) -> Result<(), ErrorCode> {
+    let mut state = InflateState::new_boxed(DataFormat::Raw);
+    let mut output = vec![0; CHUNK_SIZE as usize];
+    loop {
+        let (status, buf) = rx.read(Vec::with_capacity(CHUNK_SIZE as usize)).await;
+        if buf.is_empty() {
+            match status {
+                wit_bindgen::StreamResult::Complete(_) => continue,
+                // the archive ended before the deflate stream did
+                _ => return Err(ErrorCode::Io),
+            }
+        }
+        let mut input = buf.as_slice();
+        loop {
+            let result = inflate(&mut state, input, &mut output, MZFlush::None);
+            input = &input[result.bytes_consumed..];
+            let mut data = &output[..result.bytes_written];
+            let skipped = skip.min(data.len() as u64);
+            data = &data[skipped as usize..];
+            skip -= skipped;
+            // the stream may decompress to more than the central directory records
+            data = &data[..limit.min(data.len() as u64) as usize];
+            limit -= data.len() as u64;
+            if !data.is_empty() && !tx.write_all(data.to_vec()).await.is_empty() {
+                return Ok(());
+            }
+            if limit == 0 {
+                return Ok(());
+            }
+            match result.status {
+                Ok(MZStatus::StreamEnd) => return Ok(()),
+                Ok(_) | Err(MZError::Buf) => {}
+                Err(_) => return Err(ErrorCode::Io),
+            }
+            if result.bytes_consumed == 0 && result.bytes_written == 0 {
+                // more input is needed
+                break;
+            }
+        }
+    }
+}
+
+/// The data of a file within the archive.
+#[derive(Clone)]
+pub(crate) struct Contents {
+    archive: Rc<types::Descriptor>,
+    /// Offset of the local header preceding the data.
+    header: Filesize,
+    method: u16,
+    compressed: Filesize,
+    size: Filesize,
+}
+
+impl Contents {
+    async fn copy(
+        &self,
+        tx: &mut wit_bindgen::StreamWriter<u8>,
+        offset: Filesize,
+    ) -> Result<(), ErrorCode> {
+        let start = zip::data_offset(&self.archive, self.header).await?;
+        match self.method {
+            zip::STORED => {
+                let (mut data, result) = self.archive.read_via_stream(start + offset);
+                let ended = pipe(&mut data, tx, self.size - offset).await;
+                drop(data);
+                match ended {
+                    // the archive is shorter than its central directory
+                    true => result.await.and(Err(ErrorCode::Io)),
+                    false => Ok(()),
+                }
+            }
+            zip::DEFLATED => {
+                let (mut data, _) = self.archive.read_via_stream(start);
+                inflate_stream(&mut data, tx, offset, self.size - offset).await
+            }
+            _ => Err(ErrorCode::Unsupported),
+        }
+    }
+}
+
+impl vfs::Contents for Contents {
+    fn size(&self) -> Filesize {
+        self.size
+    }
+
+    fn read(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let (mut data_tx, data_rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        let contents = self.clone();
+        wit_bindgen::spawn(async move {
+            let result = contents.copy(&mut data_tx, offset.min(contents.size)).await;
+            drop(data_tx);
+            let _ = result_tx.write(result).await;
+        });
+        (data_rx, result_rx)
+    }
+}
+
+struct FilesystemZip {}
+
+impl Preopens for FilesystemZip {
+    fn get_directories() -> Vec<(Descriptor, String)> {
+        let path = config(PREOPEN_KEY).unwrap_or(String::from("/"));
+        let fd = Descriptor::new(VfsDescriptor::root(TREE.with(Rc::clone)));
+        vec![(fd, path)]
+    }
+}
+
+impl Types for FilesystemZip {
+    type Descriptor = VfsDescriptor<Contents>;
+}
+
+vfs::export!(FilesystemZip);
@@ -0,0 +1,252 @@
+//! Zip archives end with a central directory listing every entry with its sizes, modification
+//! time and the offset of its local header, which is followed by the entry's data. The central
+//! directory is found from the end of central directory record at the very end of the archive.
+//! Zip64 archives are understood.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::{read_at, Contents};
+use vfs::exports::wasi::filesystem::types::{ErrorCode, Filesize};
+use vfs::wasi::clocks::system_clock::Instant;
+use vfs::wasi::filesystem::types::Descriptor;
+use vfs::wasi::logging::logging::{log, Level};
+use vfs::{Node, Tree};
+
+pub(crate) const STORED: u16 = 0;
+pub(crate) const DEFLATED: u16 = 8;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP64_END_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const END_SIZE: usize = 22;
+const ZIP64_END_SIZE: usize = 56;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+/// The end of central directory record may be followed by a comment of up to 64 KiB.
+const MAX_COMMENT_SIZE: usize = 0xffff;
+/// The longest symbolic link target read, as targets are read whole while indexing.
+const MAX_TARGET_SIZE: usize = 4096;
+
+const ZIP64_EXTRA: u16 = 0x0001;
+const TIMESTAMP_EXTRA: u16 = 0x5455;
+
+const ENCRYPTED: u16 = 0x0001;
+/// The 'version made by' of archives created on Unix, whose external attributes hold the mode.
+const UNIX: u8 = 3;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().expect("2 bytes"))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+/// The extra fields of a header, as their id and data.
+fn extra_fields(mut extra: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if extra.len() < 4 {
+            return None;
+        }
+        let id = u16_at(extra, 0);
+        let len = u16_at(extra, 2) as usize;
+        let data = extra.get(4..4 + len)?;
+        extra = &extra[4 + len..];
+        Some((id, data))
+    })
+}
+
+/// Days since the Unix epoch of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// An MS-DOS date and time, which has no time zone and is taken as UTC.
+fn dos_time(date: u16, time: u16) -> Option<Instant> {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf) as i64;
+    let day = (date & 0x1f) as i64;
+    if month == 0 || day == 0 {
+        return None;
+    }
+    let hours = (time >> 11) as i64;
+    let minutes = ((time >> 5) & 0x3f) as i64;
+    let seconds = ((time & 0x1f) * 2) as i64;
+    Some(Instant {
+        seconds: days_from_civil(year, month, day) * 86_400 + hours * 3600 + minutes * 60 + seconds,
+        nanoseconds: 0,
+    })
+}
+
+/// Where the central directory is stored, and how many entries it lists.
+struct CentralDirectory {
+    offset: Filesize,
+    size: Filesize,
+    entries: u64,
+}
+
+async fn central_directory(fd: &Descriptor) -> Result<CentralDirectory, ErrorCode> {
+    let size = fd.stat().await?.size;
+    let tail_size = size.min((END_SIZE + MAX_COMMENT_SIZE) as Filesize);
+    let tail = read_at(fd, size - tail_size, tail_size as usize).await?;
+    // search from the end, as the comment may contain anything
+    let end = (0..=tail.len().checked_sub(END_SIZE).ok_or(ErrorCode::Io)?)
+        .rev()
+        .find(|end| u32_at(&tail, *end) == END_SIGNATURE)
+        .ok_or(ErrorCode::Io)?;
+
+    let directory = if end >= ZIP64_LOCATOR_SIZE
+        && u32_at(&tail, end - ZIP64_LOCATOR_SIZE) == ZIP64_LOCATOR_SIGNATURE
+    {
+        let record_offset = u64_at(&tail, end - ZIP64_LOCATOR_SIZE + 8);
+        let record = read_at(fd, record_offset, ZIP64_END_SIZE).await?;
+        if record.len() < ZIP64_END_SIZE || u32_at(&record, 0) != ZIP64_END_SIGNATURE {
+            return Err(ErrorCode::Io);
+        }
+        CentralDirectory {
+            entries: u64_at(&record, 32),
+            size: u64_at(&record, 40),
+            offset: u64_at(&record, 48),
+        }
+    } else {
+        CentralDirectory {
+            entries: u16_at(&tail, end + 10) as u64,
+            size: u32_at(&tail, end + 12) as Filesize,
+            offset: u32_at(&tail, end + 16) as Filesize,
+        }
+    };
+
+    // the central directory is read whole, so must lie within the archive
+    let within = directory
+        .offset
+        .checked_add(directory.size)
+        .is_some_and(|end| end <= size);
+    if !within {
+        return Err(ErrorCode::Io);
+    }
+    Ok(directory)
+}
+
+/// Where the data of an entry starts, after its local header.
+pub(crate) async fn data_offset(fd: &Descriptor, header: Filesize) -> Result<Filesize, ErrorCode> {
+    let local = read_at(fd, header, LOCAL_HEADER_SIZE).await?;
+    if local.len() < LOCAL_HEADER_SIZE || u32_at(&local, 0) != LOCAL_HEADER_SIGNATURE {
+        return Err(ErrorCode::Io);
+    }
+    let name_len = u16_at(&local, 26) as Filesize;
+    let extra_len = u16_at(&local, 28) as Filesize;
+    Ok(header + LOCAL_HEADER_SIZE as Filesize + name_len + extra_len)
+}
+
+/// Read the target of a symbolic link, stored as the contents of its entry.
+async fn read_target(contents: &Contents) -> Result<Vec<u8>, ErrorCode> {
+    if contents.size > MAX_TARGET_SIZE as Filesize
+        || contents.compressed > MAX_TARGET_SIZE as Filesize
+    {
+        return Err(ErrorCode::NameTooLong);
+    }
+    let start = data_offset(&contents.archive, contents.header).await?;
+    let data = read_at(&contents.archive, start, contents.compressed as usize).await?;
+    match contents.method {
+        STORED => Ok(data),
+        DEFLATED => miniz_oxide::inflate::decompress_to_vec_with_limit(&data, MAX_TARGET_SIZE)
+            .map_err(|_| ErrorCode::NameTooLong),
+        _ => Err(ErrorCode::Unsupported),
+    }
+}
+
+/// Index the entries listed by the central directory of an archive into a tree. Entries that
+/// cannot be represented, such as encrypted entries and paths leaving the archive, are skipped.
+pub(crate) async fn index(archive: &Rc<Descriptor>) -> Result<Tree<Contents>, ErrorCode> {
+    let directory = central_directory(archive).await?;
+    let len = usize::try_from(directory.size).map_err(|_| ErrorCode::Io)?;
+    let data = read_at(archive, directory.offset, len).await?;
+    let mut tree = Tree::new();
+    let mut offset = 0;
+    for _ in 0..directory.entries {
+        let header = data
+            .get(offset..offset + CENTRAL_HEADER_SIZE)
+            .ok_or(ErrorCode::Io)?;
+        if u32_at(header, 0) != CENTRAL_HEADER_SIGNATURE {
+            return Err(ErrorCode::Io);
+        }
+        let name_start = offset + CENTRAL_HEADER_SIZE;
+        let extra_start = name_start + u16_at(header, 28) as usize;
+        let extra_end = extra_start + u16_at(header, 30) as usize;
+        offset = extra_end + u16_at(header, 32) as usize;
+        let name = data.get(name_start..extra_start).ok_or(ErrorCode::Io)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        let extra = data.get(extra_start..extra_end).ok_or(ErrorCode::Io)?;
+
+        let mut size = u32_at(header, 24) as Filesize;
+        let mut compressed = u32_at(header, 20) as Filesize;
+        let mut local_header = u32_at(header, 42) as Filesize;
+        if let Some((_, zip64)) = extra_fields(extra).find(|(id, _)| *id == ZIP64_EXTRA) {
+            // only the values too large for the header are present, in this order
+            let mut values = zip64.chunks_exact(8).map(|value| u64_at(value, 0));
+            for value in [&mut size, &mut compressed, &mut local_header] {
+                if *value == 0xffff_ffff {
+                    *value = values.next().ok_or(ErrorCode::Io)?;
+                }
+            }
+        }
+        let modified = match extra_fields(extra).find(|(id, _)| *id == TIMESTAMP_EXTRA) {
+            Some((_, timestamp)) if timestamp.len() >= 5 && timestamp[0] & 1 != 0 => {
+                Some(Instant {
+                    seconds: i32::from_le_bytes(timestamp[1..5].try_into().expect("4 bytes"))
+                        as i64,
+                    nanoseconds: 0,
+                })
+            }
+            _ => dos_time(u16_at(header, 14), u16_at(header, 12)),
+        };
+        let mode = match header[5] {
+            UNIX => u32_at(header, 38) >> 16,
+            _ => 0,
+        };
+
+        let contents = Contents {
+            archive: archive.clone(),
+            header: local_header,
+            method: u16_at(header, 10),
+            compressed,
+            size,
+        };
+        let node = if u16_at(header, 8) & ENCRYPTED != 0 {
+            Err(ErrorCode::Unsupported)
+        } else if name.ends_with('/') || mode & S_IFMT == S_IFDIR {
+            Ok(Node::Directory(BTreeMap::new()))
+        } else if mode & S_IFMT == S_IFLNK {
+            read_target(&contents)
+                .await
+                .map(|target| Node::Symlink(String::from_utf8_lossy(&target).into_owned()))
+        } else {
+            Ok(Node::File(contents))
+        };
+        if let Err(error) = node.and_then(|node| tree.insert(&name, node, modified)) {
+            log(
+                Level::Warn,
+                "filesystem",
+                &format!("skipping zip entry '{name}': {error:?}"),
+            );
+        }
+    }
+    Ok(tree)
+}
@@ -0,0 +1,57 @@
+/// Translate a glob into an anchored regex, where `*` and `?` match within a single path
+/// component and `**` matches across components.
+pub fn to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex_lite::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use regex_lite::Regex;
+
+    use super::to_regex;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        Regex::new(&to_regex(glob)).unwrap().is_match(path)
+    }
+
+    #[test]
+    fn star_stays_within_component() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(!matches("*.txt", "dir/notes.txt"));
+        assert!(matches("dir/*", "dir/notes.txt"));
+    }
+
+    #[test]
+    fn double_star_crosses_components() {
+        assert!(matches("**.txt", "dir/sub/notes.txt"));
+        assert!(matches("dir/**", "dir/sub/notes.txt"));
+        assert!(!matches("dir/**", "other/notes.txt"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+        assert!(!matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn regex_characters_are_literal() {
+        assert!(matches("a+b(c).txt", "a+b(c).txt"));
+        assert!(!matches("a.txt", "abtxt"));
+    }
+}
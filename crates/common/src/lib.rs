@@ -0,0 +1,4 @@
+//! Helpers shared by components that are independent of the wasi:filesystem bindings.
+
+pub mod glob;
+pub mod random;
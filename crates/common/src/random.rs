@@ -0,0 +1,53 @@
+/// A xorshift64* pseudo-random number generator. It is fast and good enough to pick which calls
+/// are sampled, failed or delayed, but must not be used where values need to be unpredictable.
+pub struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Self {
+        // xorshift must not be seeded with zero
+        Self { state: seed | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A value uniformly distributed between 0 inclusive and 1 exclusive.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Xorshift;
+
+    #[test]
+    fn zero_seed_is_usable() {
+        let mut random = Xorshift::new(0);
+        assert_ne!(random.next_u64(), random.next_u64());
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Xorshift::new(42);
+        let mut b = Xorshift::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn f64_in_unit_interval() {
+        let mut random = Xorshift::new(7);
+        for _ in 0..10_000 {
+            let value = random.next_f64();
+            assert!((0.0..1.0).contains(&value), "{value}");
+        }
+    }
+}
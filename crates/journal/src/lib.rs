@@ -0,0 +1,341 @@
+//! The journal format shared by the `record` and `replay` components.
+//!
+//! A journal is a file of newline delimited JSON entries, one for each call made through
+//! wasi:filesystem, in the order the calls completed. The items transferred over streams are
+//! journaled in chunks as they pass, ahead of the entry for their call.
+//!
+//! The wasi:filesystem bindings are generated here, as the journal serializes their types, so
+//! both components use them from this crate and export with `journal::export!`.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use wasi::filesystem::types::{
+    Advice, DescriptorStat, DescriptorType, DirectoryEntry, ErrorCode, Filesize, MetadataHashValue,
+    NewTimestamp,
+};
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    additional_derives: [serde::Serialize, serde::Deserialize, PartialEq],
+    generate_all,
+    pub_export_macro: true,
+    default_bindings_module: "journal",
+});
+
+pub const JOURNAL_PREOPEN_KEY: &str = "journal-preopen";
+pub const JOURNAL_PATH_KEY: &str = "journal-path";
+pub const DEFAULT_JOURNAL_PATH: &str = "journal.jsonl";
+
+/// Identifies a descriptor within a journal, assigned in the order descriptors are created.
+pub type DescriptorId = u64;
+
+/// Identifies a stream within a journal, assigned in the order streams are started.
+pub type StreamId = u64;
+
+/// A call and its arguments. Flags are recorded as their bits, and descriptors as their id.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Call {
+    GetDirectories,
+    ReadViaStream {
+        offset: Filesize,
+    },
+    WriteViaStream {
+        offset: Filesize,
+    },
+    AppendViaStream,
+    Advise {
+        offset: Filesize,
+        length: Filesize,
+        advice: Advice,
+    },
+    SyncData,
+    GetFlags,
+    GetType,
+    SetSize {
+        size: Filesize,
+    },
+    SetTimes {
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    },
+    ReadDirectory,
+    Sync,
+    CreateDirectoryAt {
+        path: String,
+    },
+    Stat,
+    StatAt {
+        path_flags: u8,
+        path: String,
+    },
+    SetTimesAt {
+        path_flags: u8,
+        path: String,
+        data_access_timestamp: NewTimestamp,
+        data_modification_timestamp: NewTimestamp,
+    },
+    LinkAt {
+        old_path_flags: u8,
+        old_path: String,
+        new_descriptor: DescriptorId,
+        new_path: String,
+    },
+    OpenAt {
+        path_flags: u8,
+        path: String,
+        open_flags: u8,
+        flags: u8,
+    },
+    ReadlinkAt {
+        path: String,
+    },
+    RemoveDirectoryAt {
+        path: String,
+    },
+    RenameAt {
+        old_path: String,
+        new_descriptor: DescriptorId,
+        new_path: String,
+    },
+    SymlinkAt {
+        old_path: String,
+        new_path: String,
+    },
+    UnlinkFileAt {
+        path: String,
+    },
+    IsSameObject {
+        other: DescriptorId,
+    },
+    MetadataHash,
+    MetadataHashAt {
+        path_flags: u8,
+        path: String,
+    },
+}
+
+/// The value returned by a successful call.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Return {
+    Unit,
+    Directories(Vec<(DescriptorId, String)>),
+    Flags(u8),
+    Type(DescriptorType),
+    Stat(DescriptorStat),
+    Descriptor(DescriptorId),
+    Path(String),
+    Bool(bool),
+    Hash(MetadataHashValue),
+}
+
+/// The items transferred over a stream, either read from or written to a file, or read from a
+/// directory. Bytes are base64 encoded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stream {
+    Bytes(String),
+    Entries(Vec<DirectoryEntry>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    /// The descriptor the call was made on, absent for `get-directories`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fd: Option<DescriptorId>,
+    pub call: Call,
+    /// The stream whose chunks hold the items transferred by the call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamId>,
+    pub result: Result<Return, ErrorCode>,
+}
+
+/// A line of the journal.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Line {
+    /// Items transferred over a stream, in the order they were transferred.
+    Chunk {
+        chunk: StreamId,
+        stream: Stream,
+    },
+    Entry(Entry),
+}
+
+impl Line {
+    /// The line as it is appended to a journal, terminated by a newline.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut line = serde_json::to_vec(self).expect("journal entries must serialize");
+        line.push(b'\n');
+        line
+    }
+}
+
+/// Recorded entries not yet replayed, grouped by the descriptor they were made on.
+///
+/// Calls on a descriptor are matched to the first unreplayed entry for an equal call, so
+/// concurrent calls may complete in a different order than they were recorded.
+#[derive(Default)]
+pub struct Journal {
+    entries: BTreeMap<Option<DescriptorId>, VecDeque<Entry>>,
+    chunks: BTreeMap<StreamId, Vec<Stream>>,
+}
+
+impl Journal {
+    /// Parse the lines of a journal, failing with the number of the first invalid line.
+    pub fn parse(content: &[u8]) -> Result<Self, (usize, serde_json::Error)> {
+        let mut journal = Self::default();
+        for (number, line) in content.split(|b| *b == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(line).map_err(|error| (number + 1, error))? {
+                Line::Chunk { chunk, stream } => {
+                    journal.chunks.entry(chunk).or_default().push(stream)
+                }
+                Line::Entry(entry) => journal
+                    .entries
+                    .entry(entry.fd)
+                    .or_default()
+                    .push_back(entry),
+            }
+        }
+        Ok(journal)
+    }
+
+    /// Take the first unreplayed entry for a call on a descriptor.
+    pub fn take(&mut self, fd: Option<DescriptorId>, call: &Call) -> Option<Entry> {
+        let entries = self.entries.get_mut(&fd)?;
+        let index = entries.iter().position(|entry| entry.call == *call)?;
+        entries.remove(index)
+    }
+
+    /// Take the chunks of a recorded stream, in the order they were transferred.
+    pub fn chunks(&mut self, stream: StreamId) -> Vec<Stream> {
+        self.chunks.remove(&stream).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(fd: Option<DescriptorId>, call: Call, result: Result<Return, ErrorCode>) -> Line {
+        Line::Entry(Entry {
+            fd,
+            call,
+            stream: None,
+            result,
+        })
+    }
+
+    fn parse(lines: &[Line]) -> Journal {
+        let content = lines.iter().flat_map(Line::encode).collect::<Vec<_>>();
+        Journal::parse(&content).unwrap()
+    }
+
+    #[test]
+    fn lines_round_trip() {
+        let lines = [
+            entry(
+                None,
+                Call::GetDirectories,
+                Ok(Return::Directories(vec![(0, String::from("/"))])),
+            ),
+            entry(
+                Some(0),
+                Call::OpenAt {
+                    path_flags: 1,
+                    path: String::from("dir/file.txt"),
+                    open_flags: 3,
+                    flags: 2,
+                },
+                Err(ErrorCode::Other(Some(String::from("failed")))),
+            ),
+            entry(
+                Some(0),
+                Call::SetTimes {
+                    data_access_timestamp: NewTimestamp::NoChange,
+                    data_modification_timestamp: NewTimestamp::Now,
+                },
+                Ok(Return::Unit),
+            ),
+            Line::Chunk {
+                chunk: 7,
+                stream: Stream::Bytes(String::from("aGVsbG8=")),
+            },
+        ];
+        for line in lines {
+            let encoded = line.encode();
+            assert_eq!(encoded.last(), Some(&b'\n'));
+            let decoded: Line = serde_json::from_slice(&encoded).unwrap();
+            assert_eq!(decoded.encode(), encoded);
+        }
+    }
+
+    #[test]
+    fn invalid_line_is_numbered() {
+        let mut content = entry(None, Call::GetDirectories, Ok(Return::Unit)).encode();
+        content.extend_from_slice(b"\n{\"op\":\n");
+        let (number, _) = Journal::parse(&content).err().unwrap();
+        assert_eq!(number, 3);
+    }
+
+    #[test]
+    fn calls_match_in_any_order() {
+        let stat = |path: &str| Call::StatAt {
+            path_flags: 0,
+            path: String::from(path),
+        };
+        let mut journal = parse(&[
+            entry(Some(1), stat("a"), Ok(Return::Unit)),
+            entry(Some(1), stat("b"), Err(ErrorCode::NoEntry)),
+            entry(Some(1), stat("a"), Err(ErrorCode::Access)),
+        ]);
+        let b = journal.take(Some(1), &stat("b")).unwrap();
+        assert_eq!(b.result.err(), Some(ErrorCode::NoEntry));
+        // equal calls are replayed in the order they were recorded
+        assert!(journal.take(Some(1), &stat("a")).unwrap().result.is_ok());
+        let a = journal.take(Some(1), &stat("a")).unwrap();
+        assert_eq!(a.result.err(), Some(ErrorCode::Access));
+        assert!(journal.take(Some(1), &stat("a")).is_none());
+    }
+
+    #[test]
+    fn mismatched_calls_are_not_found() {
+        let mut journal = parse(&[entry(Some(1), Call::SetSize { size: 10 }, Ok(Return::Unit))]);
+        assert!(journal.take(Some(1), &Call::SetSize { size: 11 }).is_none());
+        assert!(journal.take(Some(2), &Call::SetSize { size: 10 }).is_none());
+        assert!(journal.take(None, &Call::SetSize { size: 10 }).is_none());
+        assert!(journal.take(Some(1), &Call::Sync).is_none());
+        assert!(journal.take(Some(1), &Call::SetSize { size: 10 }).is_some());
+    }
+
+    #[test]
+    fn chunks_are_taken_in_order() {
+        let mut journal = parse(&[
+            Line::Chunk {
+                chunk: 1,
+                stream: Stream::Bytes(String::from("YQ==")),
+            },
+            Line::Chunk {
+                chunk: 2,
+                stream: Stream::Entries(vec![]),
+            },
+            Line::Chunk {
+                chunk: 1,
+                stream: Stream::Bytes(String::from("Yg==")),
+            },
+        ]);
+        let chunks = journal.chunks(1);
+        assert_eq!(chunks.len(), 2);
+        assert!(matches!(&chunks[1], Stream::Bytes(bytes) if bytes == "Yg=="));
+        assert!(journal.chunks(1).is_empty());
+        assert_eq!(journal.chunks(2).len(), 1);
+    }
+}
@@ -0,0 +1,687 @@
+//! A read-only file system held in memory as a tree of nodes, exported with the same
+//! `GuestDescriptor` semantics as the `readonly` component. The tree only holds the structure,
+//! the component building it provides the data of files by implementing `Contents`.
+//!
+//! The wasi:filesystem bindings are generated here, as the descriptors of the tree implement
+//! their exports, so components use them from this crate and export with `vfs::export!`.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use exports::wasi::filesystem::types::{
+    Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
+    DirectoryEntry, ErrorCode, Filesize, GuestDescriptor, MetadataHashValue, NewTimestamp,
+    OpenFlags, PathFlags,
+};
+use wasi::clocks::system_clock::Instant;
+
+wit_bindgen::generate!({
+    path: "../../wit",
+    world: "filesystem",
+    merge_structurally_equal_types: true,
+    generate_all,
+    pub_export_macro: true,
+    default_bindings_module: "vfs",
+});
+
+/// The data of a file in a tree.
+pub trait Contents: 'static {
+    fn size(&self) -> Filesize;
+
+    /// Read the data from an offset onwards.
+    fn read(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    );
+}
+
+type NodeId = usize;
+
+const ROOT: NodeId = 0;
+/// Symbolic links followed while resolving a single path, as with Linux.
+const MAX_SYMLINKS: usize = 40;
+
+pub enum Node<C> {
+    Directory(BTreeMap<String, NodeId>),
+    File(C),
+    Symlink(String),
+}
+
+impl<C: Contents> Node<C> {
+    fn descriptor_type(&self) -> DescriptorType {
+        match self {
+            Node::Directory(_) => DescriptorType::Directory,
+            Node::File(_) => DescriptorType::RegularFile,
+            Node::Symlink(_) => DescriptorType::SymbolicLink,
+        }
+    }
+
+    fn size(&self) -> Filesize {
+        match self {
+            Node::Directory(_) => 0,
+            Node::File(contents) => contents.size(),
+            Node::Symlink(target) => target.len() as Filesize,
+        }
+    }
+}
+
+impl<C: Contents> Default for Tree<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Entry<C> {
+    node: Node<C>,
+    links: u64,
+    modified: Option<Instant>,
+}
+
+pub struct Tree<C> {
+    entries: Vec<Entry<C>>,
+}
+
+impl<C: Contents> Tree<C> {
+    /// A tree holding only an empty root directory.
+    pub fn new() -> Self {
+        Self {
+            entries: vec![Entry {
+                node: Node::Directory(BTreeMap::new()),
+                links: 1,
+                modified: None,
+            }],
+        }
+    }
+
+    fn push(&mut self, node: Node<C>, modified: Option<Instant>) -> NodeId {
+        self.entries.push(Entry {
+            node,
+            links: 0,
+            modified,
+        });
+        self.entries.len() - 1
+    }
+
+    /// The names of a path relative to the root, which may not leave it.
+    fn names(path: &str) -> Result<Vec<&str>, ErrorCode> {
+        let names = path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .collect::<Vec<_>>();
+        if names.contains(&"..") {
+            return Err(ErrorCode::NotPermitted);
+        }
+        Ok(names)
+    }
+
+    /// The directory holding the last name of a path, creating any missing directories.
+    fn parent(&mut self, names: &[&str]) -> Result<NodeId, ErrorCode> {
+        let mut parent = ROOT;
+        for name in names {
+            let Node::Directory(children) = &self.entries[parent].node else {
+                return Err(ErrorCode::NotDirectory);
+            };
+            parent = match children.get(*name) {
+                Some(child) => *child,
+                None => {
+                    let child = self.push(Node::Directory(BTreeMap::new()), None);
+                    self.add_child(parent, name, child);
+                    child
+                }
+            };
+        }
+        match self.entries[parent].node {
+            Node::Directory(_) => Ok(parent),
+            _ => Err(ErrorCode::NotDirectory),
+        }
+    }
+
+    fn add_child(&mut self, parent: NodeId, name: &str, child: NodeId) {
+        let Node::Directory(children) = &mut self.entries[parent].node else {
+            unreachable!("parent is a directory");
+        };
+        if let Some(replaced) = children.insert(name.to_string(), child) {
+            self.entries[replaced].links -= 1;
+        }
+        self.entries[child].links += 1;
+    }
+
+    /// Add a node at a path relative to the root, creating any missing parent directories. An
+    /// existing node is replaced, except for a directory added over a directory which keeps its
+    /// entries, as archives may list a directory after its contents.
+    pub fn insert(
+        &mut self,
+        path: &str,
+        node: Node<C>,
+        modified: Option<Instant>,
+    ) -> Result<(), ErrorCode> {
+        let names = Self::names(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            // the root itself
+            self.entries[ROOT].modified = modified;
+            return Ok(());
+        };
+        let parent = self.parent(parents)?;
+        let Node::Directory(children) = &self.entries[parent].node else {
+            unreachable!("parent is a directory");
+        };
+        if let Some(existing) = children.get(*name) {
+            let existing = *existing;
+            if let (Node::Directory(_), Node::Directory(_)) = (&self.entries[existing].node, &node)
+            {
+                self.entries[existing].modified = modified;
+                return Ok(());
+            }
+        }
+        let child = self.push(node, modified);
+        self.add_child(parent, name, child);
+        Ok(())
+    }
+
+    /// Add a hard link at a path to the node at another path, both relative to the root.
+    pub fn link(&mut self, path: &str, target: &str) -> Result<(), ErrorCode> {
+        let target = self.resolve(ROOT, target.trim_start_matches('/'), false)?;
+        if let Node::Directory(_) = self.entries[target].node {
+            return Err(ErrorCode::NotPermitted);
+        }
+        let names = Self::names(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            return Err(ErrorCode::Exist);
+        };
+        let parent = self.parent(parents)?;
+        self.add_child(parent, name, target);
+        Ok(())
+    }
+
+    /// Resolve a path relative to a directory, following symbolic links along the way. The path
+    /// may not leave the directory it is relative to.
+    fn resolve(&self, base: NodeId, path: &str, follow: bool) -> Result<NodeId, ErrorCode> {
+        let mut stack = vec![base];
+        let mut links = 0;
+        self.walk(&mut stack, path, follow, &mut links)?;
+        Ok(*stack.last().expect("stack holds the base"))
+    }
+
+    fn walk(
+        &self,
+        stack: &mut Vec<NodeId>,
+        path: &str,
+        follow: bool,
+        links: &mut usize,
+    ) -> Result<(), ErrorCode> {
+        if path.starts_with('/') {
+            return Err(ErrorCode::NotPermitted);
+        }
+        let names = path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .collect::<Vec<_>>();
+        for (i, name) in names.iter().enumerate() {
+            let last = i + 1 == names.len();
+            let current = *stack.last().expect("stack holds the base");
+            let Node::Directory(children) = &self.entries[current].node else {
+                return Err(ErrorCode::NotDirectory);
+            };
+            if *name == ".." {
+                if stack.len() == 1 {
+                    return Err(ErrorCode::NotPermitted);
+                }
+                stack.pop();
+                continue;
+            }
+            let child = *children.get(*name).ok_or(ErrorCode::NoEntry)?;
+            match &self.entries[child].node {
+                Node::Symlink(target) if follow || !last => {
+                    *links += 1;
+                    if *links > MAX_SYMLINKS {
+                        return Err(ErrorCode::Loop);
+                    }
+                    self.walk(stack, target, true, links)?;
+                }
+                _ => stack.push(child),
+            }
+        }
+        Ok(())
+    }
+
+    fn stat(&self, node: NodeId) -> DescriptorStat {
+        let entry = &self.entries[node];
+        DescriptorStat {
+            type_: entry.node.descriptor_type(),
+            link_count: entry.links,
+            size: entry.node.size(),
+            data_access_timestamp: entry.modified,
+            data_modification_timestamp: entry.modified,
+            status_change_timestamp: entry.modified,
+        }
+    }
+}
+
+fn failed(error: ErrorCode) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+    let (tx, rx) = wit_future::new(|| Err(ErrorCode::Io));
+    wit_bindgen::spawn(async move {
+        let _ = tx.write(Err(error)).await;
+    });
+    rx
+}
+
+pub struct VfsDescriptor<C> {
+    tree: Rc<Tree<C>>,
+    node: NodeId,
+    flags: DescriptorFlags,
+}
+
+impl<C: Contents> VfsDescriptor<C> {
+    /// A descriptor for the root directory of a tree.
+    pub fn root(tree: Rc<Tree<C>>) -> Self {
+        Self {
+            tree,
+            node: ROOT,
+            flags: DescriptorFlags::READ,
+        }
+    }
+
+    fn entry(&self) -> &Entry<C> {
+        &self.tree.entries[self.node]
+    }
+
+    fn resolve(&self, path_flags: PathFlags, path: &str) -> Result<NodeId, ErrorCode> {
+        self.tree.resolve(
+            self.node,
+            path,
+            path_flags.contains(PathFlags::SYMLINK_FOLLOW),
+        )
+    }
+}
+
+impl<C: Contents> GuestDescriptor for VfsDescriptor<C> {
+    fn read_via_stream(
+        &self,
+        offset: Filesize,
+    ) -> (
+        wit_bindgen::StreamReader<u8>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        match &self.entry().node {
+            Node::File(contents) => contents.read(offset),
+            Node::Directory(_) => (wit_stream::new().1, failed(ErrorCode::IsDirectory)),
+            Node::Symlink(_) => (wit_stream::new().1, failed(ErrorCode::BadDescriptor)),
+        }
+    }
+
+    fn write_via_stream(
+        &self,
+        _data: wit_bindgen::StreamReader<u8>,
+        _offset: Filesize,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        failed(ErrorCode::ReadOnly)
+    }
+
+    fn append_via_stream(
+        &self,
+        _data: wit_bindgen::StreamReader<u8>,
+    ) -> wit_bindgen::FutureReader<Result<(), ErrorCode>> {
+        failed(ErrorCode::ReadOnly)
+    }
+
+    async fn advise(
+        &self,
+        _offset: Filesize,
+        _length: Filesize,
+        _advice: Advice,
+    ) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    async fn sync_data(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn get_flags(&self) -> Result<DescriptorFlags, ErrorCode> {
+        Ok(self.flags)
+    }
+
+    async fn get_type(&self) -> Result<DescriptorType, ErrorCode> {
+        Ok(self.entry().node.descriptor_type())
+    }
+
+    async fn set_size(&self, _size: Filesize) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn set_times(
+        &self,
+        _data_access_timestamp: NewTimestamp,
+        _data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    fn read_directory(
+        &self,
+    ) -> (
+        wit_bindgen::StreamReader<DirectoryEntry>,
+        wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+    ) {
+        let Node::Directory(children) = &self.entry().node else {
+            return (wit_stream::new().1, failed(ErrorCode::NotDirectory));
+        };
+        let entries = children
+            .iter()
+            .map(|(name, child)| DirectoryEntry {
+                type_: self.tree.entries[*child].node.descriptor_type(),
+                name: name.clone(),
+            })
+            .collect::<Vec<_>>();
+        let (mut tx, rx) = wit_stream::new();
+        let (result_tx, result_rx) = wit_future::new(|| Ok(()));
+        wit_bindgen::spawn(async move {
+            tx.write_all(entries).await;
+            drop(tx);
+            let _ = result_tx.write(Ok(())).await;
+        });
+        (rx, result_rx)
+    }
+
+    async fn sync(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn create_directory_at(&self, _path: String) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn stat(&self) -> Result<DescriptorStat, ErrorCode> {
+        Ok(self.tree.stat(self.node))
+    }
+
+    async fn stat_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<DescriptorStat, ErrorCode> {
+        let node = self.resolve(path_flags, &path)?;
+        Ok(self.tree.stat(node))
+    }
+
+    async fn set_times_at(
+        &self,
+        _path_flags: PathFlags,
+        _path: String,
+        _data_access_timestamp: NewTimestamp,
+        _data_modification_timestamp: NewTimestamp,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn link_at(
+        &self,
+        _old_path_flags: PathFlags,
+        _old_path: String,
+        _new_descriptor: DescriptorBorrow<'_>,
+        _new_path: String,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn open_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        flags: DescriptorFlags,
+    ) -> Result<Descriptor, ErrorCode> {
+        if open_flags.contains(OpenFlags::CREATE)
+            || open_flags.contains(OpenFlags::EXCLUSIVE)
+            || open_flags.contains(OpenFlags::TRUNCATE)
+            || flags.contains(DescriptorFlags::WRITE)
+            || flags.contains(DescriptorFlags::FILE_INTEGRITY_SYNC)
+            || flags.contains(DescriptorFlags::DATA_INTEGRITY_SYNC)
+            || flags.contains(DescriptorFlags::REQUESTED_WRITE_SYNC)
+            || flags.contains(DescriptorFlags::MUTATE_DIRECTORY)
+        {
+            return Err(ErrorCode::ReadOnly);
+        }
+
+        let node = self.resolve(path_flags, &path)?;
+        match self.tree.entries[node].node {
+            Node::Symlink(_) => return Err(ErrorCode::Loop),
+            Node::File(_) if open_flags.contains(OpenFlags::DIRECTORY) => {
+                return Err(ErrorCode::NotDirectory)
+            }
+            _ => {}
+        }
+        Ok(Descriptor::new(VfsDescriptor {
+            tree: self.tree.clone(),
+            node,
+            flags,
+        }))
+    }
+
+    async fn readlink_at(&self, path: String) -> Result<String, ErrorCode> {
+        let node = self.resolve(PathFlags::empty(), &path)?;
+        match &self.tree.entries[node].node {
+            // absolute targets are returned, although they can't be followed
+            Node::Symlink(target) => Ok(target.clone()),
+            _ => Err(ErrorCode::Invalid),
+        }
+    }
+
+    async fn remove_directory_at(&self, _path: String) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn rename_at(
+        &self,
+        _old_path: String,
+        _new_descriptor: DescriptorBorrow<'_>,
+        _new_path: String,
+    ) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn symlink_at(&self, _old_path: String, _new_path: String) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn unlink_file_at(&self, _path: String) -> Result<(), ErrorCode> {
+        Err(ErrorCode::ReadOnly)
+    }
+
+    async fn is_same_object(&self, other: DescriptorBorrow<'_>) -> bool {
+        let other: &Self = other.get();
+        Rc::ptr_eq(&self.tree, &other.tree) && self.node == other.node
+    }
+
+    async fn metadata_hash(&self) -> Result<MetadataHashValue, ErrorCode> {
+        Ok(MetadataHashValue {
+            lower: self.node as u64,
+            upper: 0,
+        })
+    }
+
+    async fn metadata_hash_at(
+        &self,
+        path_flags: PathFlags,
+        path: String,
+    ) -> Result<MetadataHashValue, ErrorCode> {
+        let node = self.resolve(path_flags, &path)?;
+        Ok(MetadataHashValue {
+            lower: node as u64,
+            upper: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Empty;
+
+    impl Contents for Empty {
+        fn size(&self) -> Filesize {
+            0
+        }
+
+        fn read(
+            &self,
+            _offset: Filesize,
+        ) -> (
+            wit_bindgen::StreamReader<u8>,
+            wit_bindgen::FutureReader<Result<(), ErrorCode>>,
+        ) {
+            unreachable!("contents are not read")
+        }
+    }
+
+    fn symlink(target: &str) -> Node<Empty> {
+        Node::Symlink(target.to_string())
+    }
+
+    fn tree() -> Tree<Empty> {
+        let mut tree = Tree::new();
+        tree.insert("a/b/file", Node::File(Empty), None).unwrap();
+        tree.insert("a/up", symlink("../c"), None).unwrap();
+        tree.insert("c/file", Node::File(Empty), None).unwrap();
+        tree.insert("absolute", symlink("/c"), None).unwrap();
+        tree.insert("loop/x", symlink("y"), None).unwrap();
+        tree.insert("loop/y", symlink("x"), None).unwrap();
+        tree
+    }
+
+    fn lookup(tree: &Tree<Empty>, path: &str) -> Result<NodeId, ErrorCode> {
+        tree.resolve(ROOT, path, true)
+    }
+
+    #[test]
+    fn resolves_dots() {
+        let tree = tree();
+        let file = lookup(&tree, "a/b/file").unwrap();
+        assert_eq!(lookup(&tree, "./a//b/./file").unwrap(), file);
+        assert_eq!(lookup(&tree, "a/b/../b/file").unwrap(), file);
+        assert_eq!(lookup(&tree, "a/..").unwrap(), ROOT);
+        assert!(matches!(
+            lookup(&tree, "a/b/file/.."),
+            Err(ErrorCode::NotDirectory)
+        ));
+        assert!(matches!(
+            lookup(&tree, "a/missing"),
+            Err(ErrorCode::NoEntry)
+        ));
+    }
+
+    #[test]
+    fn may_not_escape_the_base() {
+        let tree = tree();
+        assert!(matches!(lookup(&tree, ".."), Err(ErrorCode::NotPermitted)));
+        assert!(matches!(
+            lookup(&tree, "a/../../c"),
+            Err(ErrorCode::NotPermitted)
+        ));
+        assert!(matches!(lookup(&tree, "/c"), Err(ErrorCode::NotPermitted)));
+        // relative to a directory, its parent is out of reach
+        let a = lookup(&tree, "a").unwrap();
+        assert!(matches!(
+            tree.resolve(a, "../c", true),
+            Err(ErrorCode::NotPermitted)
+        ));
+        assert!(matches!(
+            tree.resolve(a, "up", true),
+            Err(ErrorCode::NotPermitted)
+        ));
+    }
+
+    #[test]
+    fn follows_symlinks() {
+        let tree = tree();
+        let c = lookup(&tree, "c").unwrap();
+        assert_eq!(lookup(&tree, "a/up").unwrap(), c);
+        assert_eq!(
+            lookup(&tree, "a/up/file").unwrap(),
+            lookup(&tree, "c/file").unwrap()
+        );
+        // the last name is only followed when asked
+        let link = tree.resolve(ROOT, "a/up", false).unwrap();
+        assert!(matches!(tree.entries[link].node, Node::Symlink(_)));
+        // absolute targets can't be followed
+        assert!(matches!(
+            lookup(&tree, "absolute"),
+            Err(ErrorCode::NotPermitted)
+        ));
+        assert!(tree.resolve(ROOT, "absolute", false).is_ok());
+    }
+
+    #[test]
+    fn symlink_loops_fail() {
+        let tree = tree();
+        assert!(matches!(lookup(&tree, "loop/x"), Err(ErrorCode::Loop)));
+        assert!(tree.resolve(ROOT, "loop/x", false).is_ok());
+    }
+
+    #[test]
+    fn follows_at_most_max_symlinks() {
+        let mut tree = Tree::new();
+        tree.insert("0", Node::File(Empty), None).unwrap();
+        for i in 1..=MAX_SYMLINKS + 1 {
+            tree.insert(&i.to_string(), symlink(&(i - 1).to_string()), None)
+                .unwrap();
+        }
+        let file = lookup(&tree, "0").unwrap();
+        assert_eq!(lookup(&tree, &MAX_SYMLINKS.to_string()).unwrap(), file);
+        assert!(matches!(
+            lookup(&tree, &(MAX_SYMLINKS + 1).to_string()),
+            Err(ErrorCode::Loop)
+        ));
+    }
+
+    #[test]
+    fn insert_creates_parents() {
+        let tree = tree();
+        let b = lookup(&tree, "a/b").unwrap();
+        assert!(matches!(tree.entries[b].node, Node::Directory(_)));
+        assert_eq!(tree.entries[b].links, 1);
+    }
+
+    #[test]
+    fn insert_conflicts() {
+        let mut tree = tree();
+        // a directory listed after its contents keeps them
+        tree.insert("a/", Node::Directory(BTreeMap::new()), None)
+            .unwrap();
+        assert!(lookup(&tree, "a/b/file").is_ok());
+        // a file is replaced
+        let old = lookup(&tree, "c/file").unwrap();
+        tree.insert("c/file", symlink("../a"), None).unwrap();
+        assert_eq!(tree.entries[old].links, 0);
+        assert_eq!(
+            lookup(&tree, "c/file").unwrap(),
+            lookup(&tree, "a").unwrap()
+        );
+        // a file can't hold entries
+        assert!(matches!(
+            tree.insert("a/b/file/x", Node::File(Empty), None),
+            Err(ErrorCode::NotDirectory)
+        ));
+        assert!(matches!(
+            tree.insert("a/../x", Node::File(Empty), None),
+            Err(ErrorCode::NotPermitted)
+        ));
+    }
+
+    #[test]
+    fn links_share_a_node() {
+        let mut tree = tree();
+        tree.link("d/link", "a/b/file").unwrap();
+        let file = lookup(&tree, "a/b/file").unwrap();
+        assert_eq!(lookup(&tree, "d/link").unwrap(), file);
+        assert_eq!(tree.entries[file].links, 2);
+        assert!(matches!(
+            tree.link("d/dir", "a"),
+            Err(ErrorCode::NotPermitted)
+        ));
+    }
+}